@@ -4,6 +4,7 @@ mod filesystem;
 use anyhow::Result;
 use clap::{Arg, ArgAction, Command};
 use fuser::MountOption;
+use std::path::Path;
 
 
 
@@ -37,10 +38,32 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             Arg::new("password")
                 .long("password")
                 .short('p')
-                .required(true)
+                .required_unless_present("identity")
                 .value_name("PASSWORD")
                 .help("Password for the SFTP server"),
         )
+        .arg(
+            Arg::new("otp")
+                .long("otp")
+                .requires("password")
+                .value_name("CODE")
+                .help("TOTP verification code or recovery code, for users with two-factor authentication"),
+        )
+        .arg(
+            Arg::new("identity")
+                .long("identity")
+                .short('i')
+                .conflicts_with("password")
+                .value_name("FILE")
+                .help("Private key file for public key authentication"),
+        )
+        .arg(
+            Arg::new("passphrase")
+                .long("passphrase")
+                .requires("identity")
+                .value_name("PASSPHRASE")
+                .help("Passphrase of the private key"),
+        )
         .arg(
             Arg::new("path")
                 .long("path")
@@ -64,9 +87,20 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         .get_matches();
     let addr = matches.get_one::<String>("addr").unwrap();
     let username = matches.get_one::<String>("username").unwrap();
-    let password = matches.get_one::<String>("password").unwrap();
     let path = "/";
-    let session = sftp_client::make_ssh_session_by_password(username, password, addr)?;
+    let session = match matches.get_one::<String>("identity") {
+        Some(identity) => {
+            let passphrase = matches.get_one::<String>("passphrase").map(String::as_str);
+            sftp_client::make_ssh_session_by_key(username, Path::new(identity), passphrase, addr)?
+        }
+        None => {
+            let password = matches.get_one::<String>("password").unwrap();
+            match matches.get_one::<String>("otp") {
+                Some(code) => sftp_client::make_ssh_session_with_totp(username, password, code, addr)?,
+                None => sftp_client::make_ssh_session_by_password(username, password, addr)?,
+            }
+        }
+    };
     let fs = filesystem::Sshfs::new(session, path.into());
     env_logger::init();
     let mountpoint = matches.get_one::<String>("MOUNT_POINT").unwrap();
@@ -1,6 +1,7 @@
 use anyhow::{Context, Result};
-use ssh2::Session;
+use ssh2::{KeyboardInteractivePrompt, Prompt, Session};
 use std::net::TcpStream;
+use std::path::Path;
 
 pub fn make_ssh_session_by_password(username: &str, password: &str, addr: &str) -> Result<Session> {
     let tcp = TcpStream::connect(addr).context("Failed to connect to the server")?;
@@ -11,6 +12,45 @@ pub fn make_ssh_session_by_password(username: &str, password: &str, addr: &str)
     Ok(sess)
 }
 
+/// 对服务器的每个 keyboard-interactive 提示都回答同一个验证码
+struct VerificationCode<'a>(&'a str);
+
+impl KeyboardInteractivePrompt for VerificationCode<'_> {
+    fn prompt<'a>(&mut self, _username: &str, _instructions: &str, prompts: &[Prompt<'a>]) -> Vec<String> {
+        prompts.iter().map(|_| self.0.to_string()).collect()
+    }
+}
+
+/// 登记了两步验证的用户：先验证密码，再通过 keyboard-interactive 提交 TOTP 验证码或恢复码
+pub fn make_ssh_session_with_totp(username: &str, password: &str, code: &str, addr: &str) -> Result<Session> {
+    let tcp = TcpStream::connect(addr).context("Failed to connect to the server")?;
+    let mut sess = Session::new().context("Failed to create a new session")?;
+    sess.set_tcp_stream(tcp);
+    sess.handshake().context("Failed to perform SSH handshake")?;
+    // 密码正确时服务器同样回复失败，并要求继续进行 keyboard-interactive 认证
+    if sess.userauth_password(username, password).is_err() {
+        sess.userauth_keyboard_interactive(username, &mut VerificationCode(code))
+            .context("Failed to authenticate by password and verification code")?;
+    }
+    Ok(sess)
+}
+
+/// 使用私钥文件登录，公钥需要先在服务器上通过 `auth key add` 登记
+pub fn make_ssh_session_by_key(
+    username: &str,
+    private_key: &Path,
+    passphrase: Option<&str>,
+    addr: &str,
+) -> Result<Session> {
+    let tcp = TcpStream::connect(addr).context("Failed to connect to the server")?;
+    let mut sess = Session::new().context("Failed to create a new session")?;
+    sess.set_tcp_stream(tcp);
+    sess.handshake().context("Failed to perform SSH handshake")?;
+    sess.userauth_pubkey_file(username, None, private_key, passphrase)
+        .context("Failed to authenticate by public key")?;
+    Ok(sess)
+}
+
 #[cfg(test)]
 mod tests {
     use ssh2::Session;
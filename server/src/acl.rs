@@ -0,0 +1,315 @@
+//! 基于路径前缀的访问控制规则
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::fs::normalize_virtual_path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Right {
+    Read,
+    Write,
+    List,
+    Delete,
+    MakeDir,
+}
+
+const ALL_RIGHTS: [Right; 5] = [
+    Right::Read,
+    Right::Write,
+    Right::List,
+    Right::Delete,
+    Right::MakeDir,
+];
+
+impl Right {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Right::Read => "read",
+            Right::Write => "write",
+            Right::List => "list",
+            Right::Delete => "delete",
+            Right::MakeDir => "mkdir",
+        }
+    }
+
+    fn bit(&self) -> u8 {
+        1 << (*self as u8)
+    }
+}
+
+/// 一组权限，在数据库中保存为逗号分隔的名字，例如 `read,list`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Rights(u8);
+
+impl Rights {
+    pub fn contains(&self, right: Right) -> bool {
+        self.0 & right.bit() != 0
+    }
+}
+
+impl FromStr for Rights {
+    type Err = anyhow::Error;
+
+    /// `none` 或空字符串表示不授予任何权限（即拒绝），`all` 表示全部权限
+    fn from_str(s: &str) -> Result<Self> {
+        let mut rights = Rights::default();
+        for name in s.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+            match name {
+                "none" => {}
+                "all" => ALL_RIGHTS.iter().for_each(|right| rights.0 |= right.bit()),
+                name => {
+                    let right = ALL_RIGHTS
+                        .iter()
+                        .find(|right| right.name() == name)
+                        .ok_or_else(|| anyhow::anyhow!("Unknown right: {}", name))?;
+                    rights.0 |= right.bit();
+                }
+            }
+        }
+        Ok(rights)
+    }
+}
+
+impl fmt::Display for Rights {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<&str> = ALL_RIGHTS
+            .iter()
+            .filter(|right| self.contains(**right))
+            .map(Right::name)
+            .collect();
+        if names.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", names.join(","))
+        }
+    }
+}
+
+/// 一条规则作用于某个用户或某个角色，路径是相对于用户根目录的虚拟路径
+#[derive(Debug, Clone)]
+pub struct AccessRule {
+    pub id: i64,
+    pub username: Option<String>,
+    pub role: Option<String>,
+    pub path: PathBuf,
+    pub rights: Rights,
+}
+
+/// 某个用户在一次会话中适用的全部规则
+#[derive(Debug, Default)]
+pub struct Acl {
+    // admin 不受规则限制
+    bypass: bool,
+    rules: Vec<AccessRule>,
+}
+
+impl Acl {
+    /// 读取直接作用于该用户以及作用于其角色的规则
+    pub fn load(conn: &Connection, username: &str) -> Result<Self> {
+        let role: Option<String> = conn
+            .query_row(
+                "SELECT role FROM Users WHERE username = ?",
+                params![username],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let role = role.ok_or_else(|| anyhow::anyhow!("No such user: {}", username))?;
+        if role == "admin" {
+            return Ok(Self {
+                bypass: true,
+                rules: vec![],
+            });
+        }
+        let rules = query_rules(
+            conn,
+            "WHERE username = ?1 OR role = ?2",
+            params![username, role],
+        )?;
+        Ok(Self {
+            bypass: false,
+            rules,
+        })
+    }
+
+    /// 按最长路径前缀匹配规则；前缀相同时用户规则优先于角色规则。
+    /// 没有任何规则匹配时允许访问。
+    pub fn check(&self, virtual_path: &Path, right: Right) -> io::Result<()> {
+        if self.bypass {
+            return Ok(());
+        }
+        let virtual_path = normalize_virtual_path(virtual_path);
+        let rule = self
+            .rules
+            .iter()
+            .filter(|rule| virtual_path.starts_with(&rule.path))
+            .max_by_key(|rule| (rule.path.components().count(), rule.username.is_some()));
+        match rule {
+            Some(rule) if !rule.rights.contains(right) => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("Access rule {} denies {} on {}", rule.id, right.name(), virtual_path.display()),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// 移动或重命名目录时，目录之下更具体的规则不会跟着移动，移走后其中的文件就不再受限。
+    /// 因此目录之下存在任何规则时拒绝移动。
+    pub fn check_subtree(&self, virtual_path: &Path) -> io::Result<()> {
+        if self.bypass {
+            return Ok(());
+        }
+        let virtual_path = normalize_virtual_path(virtual_path);
+        let rule = self
+            .rules
+            .iter()
+            .find(|rule| rule.path != virtual_path && rule.path.starts_with(&virtual_path));
+        match rule {
+            Some(rule) => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "Access rule {} on {} prevents moving {}",
+                    rule.id,
+                    rule.path.display(),
+                    virtual_path.display()
+                ),
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+fn query_rules(
+    conn: &Connection,
+    filter: &str,
+    params: impl rusqlite::Params,
+) -> Result<Vec<AccessRule>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT rule_id, username, role, path, rights FROM AccessRules {} ORDER BY rule_id",
+        filter
+    ))?;
+    let rows = stmt.query_map(params, |row| {
+        Ok((
+            row.get::<_, i64>(0)?,
+            row.get::<_, Option<String>>(1)?,
+            row.get::<_, Option<String>>(2)?,
+            row.get::<_, String>(3)?,
+            row.get::<_, String>(4)?,
+        ))
+    })?;
+    let mut rules = vec![];
+    for row in rows {
+        let (id, username, role, path, rights) = row?;
+        rules.push(AccessRule {
+            id,
+            username,
+            role,
+            path: PathBuf::from(path),
+            rights: rights
+                .parse()
+                .with_context(|| format!("Invalid rights in access rule {}", id))?,
+        });
+    }
+    Ok(rules)
+}
+
+/// 添加一条规则，username 和 role 必须且只能给出一个
+pub fn add_rule(
+    conn: &Connection,
+    username: Option<&str>,
+    role: Option<&str>,
+    path: &Path,
+    rights: Rights,
+) -> Result<i64> {
+    if username.is_some() == role.is_some() {
+        return Err(anyhow::anyhow!("A rule applies to either a user or a role"));
+    }
+    if !path.has_root() {
+        return Err(anyhow::anyhow!("Rule path must be absolute: {}", path.display()));
+    }
+    let path = normalize_virtual_path(path);
+    conn.execute(
+        "INSERT INTO AccessRules (username, role, path, rights) VALUES (?, ?, ?, ?)",
+        params![username, role, path.to_string_lossy(), rights.to_string()],
+    )?;
+    Ok(conn.last_insert_rowid())
+}
+
+pub fn list_rules(conn: &Connection) -> Result<Vec<AccessRule>> {
+    query_rules(conn, "", params![])
+}
+
+pub fn remove_rule(conn: &Connection, id: i64) -> Result<()> {
+    let removed = conn.execute("DELETE FROM AccessRules WHERE rule_id = ?", params![id])?;
+    if removed == 0 {
+        return Err(anyhow::anyhow!("No such access rule: {}", id));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{DatabasePool, MockDatabasePool};
+
+    #[test]
+    fn test_rights() {
+        let rights: Rights = "read, list".parse().unwrap();
+        assert!(rights.contains(Right::Read));
+        assert!(rights.contains(Right::List));
+        assert!(!rights.contains(Right::Delete));
+        assert_eq!(rights.to_string(), "read,list");
+        assert_eq!("none".parse::<Rights>().unwrap(), Rights::default());
+        assert_eq!("all".parse::<Rights>().unwrap().to_string(), "read,write,list,delete,mkdir");
+        assert!("execute".parse::<Rights>().is_err());
+    }
+
+    #[test]
+    fn test_acl() {
+        let pool = MockDatabasePool::get_pool();
+        let conn = pool.get().unwrap();
+        conn.execute(
+            "INSERT INTO Users (username, password, role) VALUES ('student', '', 'user')",
+            params![],
+        )
+        .unwrap();
+        let all = "all".parse().unwrap();
+        let read_only = "read,list".parse().unwrap();
+        add_rule(&conn, None, Some("user"), Path::new("/shared"), read_only).unwrap();
+        add_rule(&conn, Some("student"), None, Path::new("/shared/student"), all).unwrap();
+        let deny = add_rule(&conn, Some("student"), None, Path::new("/secret"), Rights::default())
+            .unwrap();
+        assert!(add_rule(&conn, None, None, Path::new("/"), all).is_err());
+        assert!(add_rule(&conn, Some("student"), None, Path::new("relative"), all).is_err());
+
+        let acl = Acl::load(&conn, "student").unwrap();
+        assert!(acl.check(Path::new("/shared/notes.txt"), Right::Read).is_ok());
+        let err = acl.check(Path::new("/shared/notes.txt"), Right::Delete).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(acl.check(Path::new("/shared/student/a.txt"), Right::Delete).is_ok());
+        assert!(acl.check(Path::new("/shared/../secret/a.txt"), Right::Read).is_err());
+        // 组件级匹配：/secretary 不受 /secret 的规则影响
+        assert!(acl.check(Path::new("/secretary"), Right::Read).is_ok());
+        assert!(acl.check(Path::new("/home"), Right::Write).is_ok());
+        // 目录之下有规则时不能移动该目录，规则所在的目录本身以及其下的目录可以
+        assert!(acl.check_subtree(Path::new("/")).is_err());
+        assert!(acl.check_subtree(Path::new("/shared")).is_err());
+        assert!(acl.check_subtree(Path::new("/shared/student")).is_ok());
+        assert!(acl.check_subtree(Path::new("/shared/student/sub")).is_ok());
+        assert!(acl.check_subtree(Path::new("/home")).is_ok());
+
+        // admin 不受规则限制
+        add_rule(&conn, None, Some("admin"), Path::new("/"), Rights::default()).unwrap();
+        let admin = Acl::load(&conn, "admin").unwrap();
+        assert!(admin.check(Path::new("/secret"), Right::Delete).is_ok());
+
+        remove_rule(&conn, deny).unwrap();
+        assert!(remove_rule(&conn, deny).is_err());
+        assert_eq!(list_rules(&conn).unwrap().len(), 3);
+        assert!(Acl::load(&conn, "nobody").is_err());
+    }
+}
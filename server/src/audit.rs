@@ -1,19 +1,194 @@
-use std::sync::Arc;
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use anyhow::Context as _;
+use chrono::{NaiveDate, NaiveDateTime, Utc};
 use r2d2::Pool;
 use r2d2_sqlite::SqliteConnectionManager;
-use rusqlite::{params, Connection};
+use rusqlite::types::Value;
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension};
+use sha2::{Digest, Sha256};
+use serde::{Deserialize, Serialize};
 use tracing::{Event, Subscriber};
 use tracing_subscriber::{layer::Context, Layer};
 
+use crate::sink::{AuditSink, SinkConfig};
 
-// 自定义 Layer，将日志插入到 AuditLogs 表中
+
+/// 审计事件的 tracing target，例如 `info!(target: AUDIT_TARGET, username = .., action = .., target = .., ..)`，
+/// 只有这些事件会写入审计日志
+pub const AUDIT_TARGET: &str = "audit";
+
+/// 与 SQLite 的 CURRENT_TIMESTAMP 相同的格式（UTC）
+pub const TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// 哈希链中第一条记录的 prev_hash
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// 审计队列满时的处理方式，由 AUDIT_OVERFLOW 设置
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// 等待后台线程写入，会拖慢正在记录日志的会话，但不会丢失记录
+    Block,
+    /// 丢弃记录并计数
+    Drop,
+    /// 追加到 JSON Lines 文件中，队列空闲时由后台线程导入数据库
+    Spill(PathBuf),
+}
+
+/// 后台写入审计日志的参数
+#[derive(Debug, Clone)]
+pub struct AuditConfig {
+    pub queue_size: usize,
+    /// 一个事务中最多写入的记录数
+    pub batch_size: usize,
+    pub overflow: OverflowPolicy,
+    /// 每条记录都写入其中的每个输出目标
+    pub sinks: Vec<SinkConfig>,
+}
+
+impl AuditConfig {
+    /// 从环境变量读取：AUDIT_QUEUE_SIZE、AUDIT_BATCH_SIZE、AUDIT_OVERFLOW（block、drop 或 spill）、
+    /// AUDIT_SPILL_PATH，以及 SinkConfig::from_env 读取的输出目标
+    pub fn from_env() -> anyhow::Result<Self> {
+        let var = |name: &str, default: usize| -> anyhow::Result<usize> {
+            match env::var(name) {
+                Ok(value) => value
+                    .parse()
+                    .with_context(|| format!("Invalid {}: {}", name, value)),
+                Err(_) => Ok(default),
+            }
+        };
+        let defaults = Self::default();
+        let overflow = match env::var("AUDIT_OVERFLOW").as_deref() {
+            Err(_) | Ok("block") => OverflowPolicy::Block,
+            Ok("drop") => OverflowPolicy::Drop,
+            Ok("spill") => OverflowPolicy::Spill(PathBuf::from(
+                env::var("AUDIT_SPILL_PATH").unwrap_or("audit_spill.jsonl".to_string()),
+            )),
+            Ok(name) => return Err(anyhow::anyhow!("Unknown audit overflow policy: {}", name)),
+        };
+        Ok(Self {
+            queue_size: var("AUDIT_QUEUE_SIZE", defaults.queue_size)?.max(1),
+            batch_size: var("AUDIT_BATCH_SIZE", defaults.batch_size)?.max(1),
+            overflow,
+            sinks: SinkConfig::from_env()?,
+        })
+    }
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            queue_size: 10000,
+            batch_size: 256,
+            overflow: OverflowPolicy::Block,
+            sinks: vec![SinkConfig::Sqlite],
+        }
+    }
+}
+
+enum Message {
+    Entry(Box<AuditEntry>),
+    /// 写完之前的全部记录（包括溢出文件中的）后回复
+    Flush(SyncSender<()>),
+}
+
+// 自定义 Layer，将日志写入 AuditConfig 中的输出目标（默认为 AuditLogs 表）。
+// on_event 只把记录放进有界队列，由后台线程成批写入，不在会话的线程上等待 SQLite
+#[derive(Clone)]
 pub struct DatabaseLogger {
-    pool: Arc<Pool<SqliteConnectionManager>>,
+    sender: SyncSender<Message>,
+    overflow: OverflowPolicy,
+    dropped: Arc<AtomicU64>,
+    // 写溢出文件和后台线程导入溢出文件时持有
+    spill_lock: Arc<Mutex<()>>,
 }
 
 impl DatabaseLogger {
+    #[cfg(test)]
     pub fn new(pool: Arc<Pool<SqliteConnectionManager>>) -> Self {
-        DatabaseLogger { pool }
+        Self::with_config(pool, AuditConfig::default()).expect("Failed to open audit sinks")
+    }
+
+    /// 打开 config.sinks 中的输出目标，启动后台写入线程
+    pub fn with_config(
+        pool: Arc<Pool<SqliteConnectionManager>>,
+        config: AuditConfig,
+    ) -> anyhow::Result<Self> {
+        let sinks = config
+            .sinks
+            .iter()
+            .map(|sink| sink.open(&pool))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let (sender, receiver) = mpsc::sync_channel(config.queue_size);
+        let logger = DatabaseLogger {
+            sender,
+            overflow: config.overflow,
+            dropped: Arc::new(AtomicU64::new(0)),
+            spill_lock: Arc::new(Mutex::new(())),
+        };
+        let worker = AuditWorker {
+            sinks,
+            receiver,
+            batch_size: config.batch_size,
+            spill_path: match &logger.overflow {
+                OverflowPolicy::Spill(path) => Some(path.clone()),
+                _ => None,
+            },
+            spill_lock: logger.spill_lock.clone(),
+            dropped: logger.dropped.clone(),
+            reported_dropped: 0,
+        };
+        thread::Builder::new()
+            .name("audit-writer".to_string())
+            .spawn(move || worker.run())
+            .expect("Failed to start audit writer thread");
+        Ok(logger)
+    }
+
+    /// 等待已经记录的事件全部写入输出目标，用于退出前和测试
+    pub fn flush(&self) {
+        let (ack, done) = mpsc::sync_channel(1);
+        if self.sender.send(Message::Flush(ack)).is_ok() {
+            let _ = done.recv();
+        }
+    }
+
+    /// 因为队列已满而丢弃的记录数
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    fn enqueue(&self, entry: AuditEntry) {
+        let entry = match self.sender.try_send(Message::Entry(Box::new(entry))) {
+            Ok(()) => return,
+            Err(TrySendError::Full(Message::Entry(entry))) => *entry,
+            Err(_) => {
+                eprintln!("Audit writer has stopped, dropping audit event");
+                return;
+            }
+        };
+        match &self.overflow {
+            OverflowPolicy::Block => {
+                let _ = self.sender.send(Message::Entry(Box::new(entry)));
+            }
+            OverflowPolicy::Drop => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            OverflowPolicy::Spill(path) => {
+                let _guard = self.spill_lock.lock().unwrap_or_else(|err| err.into_inner());
+                if let Err(e) = append_jsonl(path, &entry) {
+                    eprintln!("Failed to spill audit event to {}: {:?}", path.display(), e);
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
     }
 }
 
@@ -23,20 +198,12 @@ where
     S: Subscriber,
 {
     fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
-        // 获取数据库连接
-        let conn = self.pool.get().expect("Failed to get connection from pool");
-
         // 提取事件的元数据和字段（如 username、action、target 等）
         let mut visitor = LogVisitor::default();
         event.record(&mut visitor);
 
         match visitor.get_val() {
-            Some((username, action, target)) => {
-                // 插入日志数据到 AuditLogs 表
-                if let Err(e) = log_action_to_audit_logs(&conn, &username, &action, &target) {
-                    eprintln!("Failed to log action to database: {:?}", e);
-                }
-            }
+            Some(entry) => self.enqueue(entry),
             None => {
                 eprintln!("Event is missing required fields: username, action, or target");
             }
@@ -44,12 +211,171 @@ where
     }
 }
 
+/// 后台线程：从队列中取出记录，成批写入每个输出目标
+struct AuditWorker {
+    sinks: Vec<Box<dyn AuditSink>>,
+    receiver: Receiver<Message>,
+    batch_size: usize,
+    spill_path: Option<PathBuf>,
+    spill_lock: Arc<Mutex<()>>,
+    dropped: Arc<AtomicU64>,
+    reported_dropped: u64,
+}
+
+impl AuditWorker {
+    /// 所有的 DatabaseLogger 都被丢弃后退出，退出前队列中的记录已经写完。
+    /// 溢出文件只在队列空闲时导入，溢出的记录可能排在之后产生的记录后面，
+    /// 得到较大的 log_id 和哈希链位置；created_at 仍是事件发生的时间，
+    /// 所以 query_audit_logs 按 created_at 排序，哈希链按 log_id 顺序校验
+    fn run(mut self) {
+        while let Ok(message) = self.receiver.recv() {
+            let mut batch = vec![];
+            let mut acks = vec![];
+            let mut next = Some(message);
+            while let Some(message) = next.take() {
+                match message {
+                    Message::Entry(entry) => batch.push(*entry),
+                    Message::Flush(ack) => acks.push(ack),
+                }
+                if batch.len() < self.batch_size {
+                    next = self.receiver.try_recv().ok();
+                }
+            }
+            self.write(&batch);
+            // 队列空闲或有人等待时，导入之前溢出到文件的记录
+            if !acks.is_empty() || batch.len() < self.batch_size {
+                self.import_spill();
+            }
+            self.report_dropped();
+            for ack in acks {
+                let _ = ack.send(());
+            }
+        }
+        self.import_spill();
+    }
+
+    /// 一个输出目标失败不影响其他输出目标
+    fn write(&mut self, batch: &[AuditEntry]) {
+        if batch.is_empty() {
+            return;
+        }
+        for sink in &mut self.sinks {
+            if let Err(e) = sink.write(batch) {
+                eprintln!("Failed to write {} audit events to {}: {:?}", batch.len(), sink.name(), e);
+            }
+        }
+    }
+
+    /// 把溢出文件改名后逐批导入，改名期间新的溢出记录写入新文件
+    fn import_spill(&mut self) {
+        let Some(path) = &self.spill_path else {
+            return;
+        };
+        let importing = path.with_extension("importing");
+        {
+            let _guard = self.spill_lock.lock().unwrap_or_else(|err| err.into_inner());
+            if !importing.exists() && fs::rename(path, &importing).is_err() {
+                return;
+            }
+        }
+        let content = match fs::read_to_string(&importing) {
+            Ok(content) => content,
+            Err(e) => {
+                eprintln!("Failed to read {}: {:?}", importing.display(), e);
+                return;
+            }
+        };
+        let entries: Vec<AuditEntry> = content
+            .lines()
+            .filter_map(|line| match serde_json::from_str(line) {
+                Ok(entry) => Some(entry),
+                Err(e) => {
+                    eprintln!("Skipping invalid spilled audit event: {:?}", e);
+                    None
+                }
+            })
+            .collect();
+        for batch in entries.chunks(self.batch_size) {
+            self.write(batch);
+        }
+        if let Err(e) = fs::remove_file(&importing) {
+            eprintln!("Failed to remove {}: {:?}", importing.display(), e);
+        }
+    }
+
+    fn report_dropped(&mut self) {
+        let dropped = self.dropped.load(Ordering::Relaxed);
+        if dropped > self.reported_dropped {
+            eprintln!(
+                "Audit queue is full, dropped {} audit events ({} in total)",
+                dropped - self.reported_dropped,
+                dropped
+            );
+            self.reported_dropped = dropped;
+        }
+    }
+}
+
+fn append_jsonl(path: &Path, entry: &AuditEntry) -> anyhow::Result<()> {
+    let mut file = fs::OpenOptions::new().create(true).append(true).open(path)?;
+    let mut line = serde_json::to_vec(entry)?;
+    line.push(b'\n');
+    file.write_all(&line)?;
+    Ok(())
+}
+
 // 定义一个 Visitor 来提取事件中的字段
 #[derive(Default)]
 struct LogVisitor {
     username: Option<String>,
     action: Option<String>,
     target: Option<String>,
+    result: Option<String>,
+    session_id: Option<String>,
+    client_addr: Option<String>,
+    // 重命名、链接等操作的第二个路径
+    secondary_target: Option<String>,
+    bytes: Option<u64>,
+    duration_us: Option<u64>,
+}
+
+/// 一条审计记录，可选字段只有部分操作才有
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub username: String,
+    pub action: String,
+    pub target: String,
+    pub result: String,
+    pub session_id: Option<String>,
+    pub client_addr: Option<String>,
+    pub secondary_target: Option<String>,
+    pub bytes: Option<u64>,
+    pub duration_us: Option<u64>,
+    /// 事件发生的时间（UTC），为 None 时使用写入的时间
+    pub created_at: Option<String>,
+}
+
+impl AuditEntry {
+    /// 这条记录在哈希链中的哈希：前一条记录的哈希加上全部字段的 JSON 数组
+    fn hash(&self, prev_hash: &str) -> String {
+        let content = serde_json::json!([
+            self.username,
+            self.action,
+            self.target,
+            self.result,
+            self.session_id,
+            self.client_addr,
+            self.secondary_target,
+            self.bytes,
+            self.duration_us,
+            self.created_at,
+        ]);
+        let mut hasher = Sha256::new();
+        hasher.update(prev_hash.as_bytes());
+        hasher.update(b"\n");
+        hasher.update(content.to_string().as_bytes());
+        format!("{:x}", hasher.finalize())
+    }
 }
 
 impl LogVisitor {
@@ -58,13 +384,21 @@ impl LogVisitor {
         self.username.is_some() && self.action.is_some() && self.target.is_some()
     }
 
-    fn get_val(&self) -> Option<(String, String, String)> {
+    /// 返回要写入的记录，未记录结果的事件视为成功
+    fn get_val(&self) -> Option<AuditEntry> {
         if self.is_valid() {
-            Some((
-                self.username.as_ref().unwrap().to_string(),
-                self.action.as_ref().unwrap().to_string(),
-                self.target.as_ref().unwrap().to_string(),
-            ))
+            Some(AuditEntry {
+                username: self.username.as_ref().unwrap().to_string(),
+                action: self.action.as_ref().unwrap().to_string(),
+                target: self.target.as_ref().unwrap().to_string(),
+                result: self.result.clone().unwrap_or_else(|| "Ok".to_string()),
+                session_id: self.session_id.clone(),
+                client_addr: self.client_addr.clone(),
+                secondary_target: self.secondary_target.clone(),
+                bytes: self.bytes,
+                duration_us: self.duration_us,
+                created_at: Some(Utc::now().format(TIMESTAMP_FORMAT).to_string()),
+            })
         } else {
             None
         }
@@ -77,23 +411,319 @@ impl tracing::field::Visit for LogVisitor {
             "username" => self.username = Some(value.to_string()),
             "action" => self.action = Some(value.to_string()),
             "target" => self.target = Some(value.to_string()),
+            "result" => self.result = Some(value.to_string()),
+            "session_id" => self.session_id = Some(value.to_string()),
+            "client_addr" => self.client_addr = Some(value.to_string()),
+            "secondary_target" => self.secondary_target = Some(value.to_string()),
+            _ => {}
+        }
+    }
+
+    fn record_u64(&mut self, field: &tracing::field::Field, value: u64) {
+        match field.name() {
+            "bytes" => self.bytes = Some(value),
+            "duration_us" => self.duration_us = Some(value),
             _ => {}
         }
     }
 
+    fn record_i64(&mut self, field: &tracing::field::Field, value: i64) {
+        self.record_u64(field, value.max(0) as u64);
+    }
+
     fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
-        if field.name() == "target" {
-            self.target = Some(format!("{:?}", value));
+        match field.name() {
+            "target" => self.target = Some(format!("{:?}", value)),
+            "result" => self.result = Some(format!("{:?}", value)),
+            "client_addr" => self.client_addr = Some(format!("{:?}", value)),
+            "secondary_target" => self.secondary_target = Some(format!("{:?}", value)),
+            _ => {}
+        }
+    }
+}
+
+/// 写入 SQLite 的 AuditLogs 表，每批在一个事务中写入，记录串成哈希链
+pub struct DatabaseSink {
+    pool: Arc<Pool<SqliteConnectionManager>>,
+}
+
+impl DatabaseSink {
+    pub fn new(pool: Arc<Pool<SqliteConnectionManager>>) -> Self {
+        Self { pool }
+    }
+}
+
+impl AuditSink for DatabaseSink {
+    fn name(&self) -> &'static str {
+        "database"
+    }
+
+    fn write(&mut self, batch: &[AuditEntry]) -> anyhow::Result<()> {
+        let mut conn = self.pool.get()?;
+        let tx = conn.transaction()?;
+        let mut prev_hash = last_hash(&tx)?;
+        // 单条记录失败不影响同一批中的其他记录
+        for entry in batch {
+            match log_action_to_audit_logs(&tx, entry, &prev_hash) {
+                Ok(entry_hash) => prev_hash = entry_hash,
+                Err(e) => eprintln!("Failed to log action to database: {:?}", e),
+            }
         }
+        tx.commit()?;
+        Ok(())
     }
 }
 
 /// 将日志写入到 AuditLogs 表的函数
-fn log_action_to_audit_logs(conn: &Connection, username: &str, action: &str, target: &str) -> Result<(), rusqlite::Error> {
+/// prev_hash 为前一条记录的哈希，返回这条记录的哈希
+fn log_action_to_audit_logs(
+    conn: &Connection,
+    entry: &AuditEntry,
+    prev_hash: &str,
+) -> Result<String, rusqlite::Error> {
+    let created_at = entry
+        .created_at
+        .clone()
+        .unwrap_or_else(|| Utc::now().format(TIMESTAMP_FORMAT).to_string());
+    let entry = AuditEntry {
+        created_at: Some(created_at),
+        ..entry.clone()
+    };
+    let entry_hash = entry.hash(prev_hash);
     conn.execute(
-        "INSERT INTO AuditLogs (username, action, target) VALUES (?1, ?2, ?3)",
-        params![username, action, target],
+        "INSERT INTO AuditLogs (username, action, target, result, session_id, client_addr, secondary_target, bytes, duration_us,
+                created_at, prev_hash, entry_hash)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+        params![
+            entry.username,
+            entry.action,
+            entry.target,
+            entry.result,
+            entry.session_id,
+            entry.client_addr,
+            entry.secondary_target,
+            entry.bytes.map(|bytes| bytes as i64),
+            entry.duration_us.map(|duration| duration as i64),
+            entry.created_at,
+            prev_hash,
+            entry_hash,
+        ],
+    )?;
+    Ok(entry_hash)
+}
+
+/// 最后一条记录的哈希，还没有带哈希的记录时为 GENESIS_HASH
+fn last_hash(conn: &Connection) -> Result<String, rusqlite::Error> {
+    let hash: Option<String> = conn
+        .query_row(
+            "SELECT entry_hash FROM AuditLogs WHERE entry_hash IS NOT NULL ORDER BY log_id DESC LIMIT 1",
+            params![],
+            |row| row.get(0),
+        )
+        .optional()?;
+    Ok(hash.unwrap_or_else(|| GENESIS_HASH.to_string()))
+}
+
+/// `audit verify` 的结果
+#[derive(Debug, Default)]
+pub struct ChainReport {
+    /// 检查通过的记录数
+    pub verified: u64,
+    /// 加入哈希链之前写入的记录，无法检查
+    pub legacy: u64,
+    /// 链中第一条记录的 id，以及它是否是整条链的开头（不是时说明更早的记录已经被清理）
+    pub first: Option<(i64, bool)>,
+    /// 最后一条检查通过的记录的 id 和哈希，可以保存到别处，之后用来确认整条链没有被重新计算
+    pub last: Option<(i64, String)>,
+    /// 第一条不一致的记录的 id 和原因
+    pub broken: Option<(i64, String)>,
+}
+
+/// 按顺序检查每条记录的哈希以及它与前一条记录的衔接，遇到第一条不一致的记录时停止
+pub fn verify_chain(conn: &Connection) -> anyhow::Result<ChainReport> {
+    let mut stmt = conn.prepare(
+        "SELECT log_id, username, action, target, result, session_id, client_addr,
+            secondary_target, bytes, duration_us, created_at, prev_hash, entry_hash
+        FROM AuditLogs ORDER BY log_id",
     )?;
+    let mut rows = stmt.query(params![])?;
+    let mut report = ChainReport::default();
+    let mut expected_prev: Option<String> = None;
+    while let Some(row) = rows.next()? {
+        let id: i64 = row.get(0)?;
+        let prev_hash: Option<String> = row.get(11)?;
+        let entry_hash: Option<String> = row.get(12)?;
+        let (Some(prev_hash), Some(entry_hash)) = (prev_hash, entry_hash) else {
+            if expected_prev.is_none() {
+                report.legacy += 1;
+                continue;
+            }
+            report.broken = Some((id, "hash is missing".to_string()));
+            break;
+        };
+        let entry = AuditEntry {
+            username: row.get(1)?,
+            action: row.get(2)?,
+            target: row.get(3)?,
+            result: row.get(4)?,
+            session_id: row.get(5)?,
+            client_addr: row.get(6)?,
+            secondary_target: row.get(7)?,
+            bytes: row.get::<_, Option<i64>>(8)?.map(|bytes| bytes as u64),
+            duration_us: row.get::<_, Option<i64>>(9)?.map(|duration| duration as u64),
+            created_at: row.get(10)?,
+        };
+        match &expected_prev {
+            Some(expected) if *expected != prev_hash => {
+                report.broken = Some((
+                    id,
+                    "previous hash does not match, a record before it was removed or changed"
+                        .to_string(),
+                ));
+                break;
+            }
+            Some(_) => {}
+            None => report.first = Some((id, prev_hash == GENESIS_HASH)),
+        }
+        if entry.hash(&prev_hash) != entry_hash {
+            report.broken = Some((id, "content does not match its hash".to_string()));
+            break;
+        }
+        report.verified += 1;
+        report.last = Some((id, entry_hash.clone()));
+        expected_prev = Some(entry_hash);
+    }
+    Ok(report)
+}
+
+/// 审计日志中的一条记录
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditRecord {
+    pub id: i64,
+    pub username: String,
+    pub action: String,
+    pub target: String,
+    pub result: String,
+    pub session_id: Option<String>,
+    pub client_addr: Option<String>,
+    pub secondary_target: Option<String>,
+    pub bytes: Option<i64>,
+    pub duration_us: Option<i64>,
+    pub created_at: String,
+}
+
+/// `audit query` 的过滤条件，为空的条件不限制
+#[derive(Debug, Default)]
+pub struct AuditFilter {
+    pub username: Option<String>,
+    /// 匹配其中任意一个操作，不区分大小写
+    pub actions: Vec<String>,
+    /// 路径前缀，按路径分量匹配，`a/b` 不会匹配 `a/bc`
+    pub path: Option<String>,
+    pub since: Option<NaiveDateTime>,
+    /// 不包括这个时间
+    pub until: Option<NaiveDateTime>,
+    /// 为 None 时返回全部记录
+    pub limit: Option<u64>,
+    pub offset: u64,
+}
+
+/// 解析查询的时间（UTC），接受 `YYYY-MM-DD` 或 `YYYY-MM-DD HH:MM:SS`
+pub fn parse_time(time: &str) -> anyhow::Result<NaiveDateTime> {
+    NaiveDateTime::parse_from_str(time, TIMESTAMP_FORMAT)
+        .or_else(|_| {
+            NaiveDate::parse_from_str(time, "%Y-%m-%d")
+                .map(|date| date.and_hms_opt(0, 0, 0).unwrap_or_default())
+        })
+        .with_context(|| format!("Invalid time: {}", time))
+}
+
+/// 按时间顺序返回符合条件的审计记录。从溢出文件导入的记录写入得晚，
+/// log_id 不代表发生的先后，因此按 created_at 排序，同一秒内再按 log_id
+pub fn query_audit_logs(conn: &Connection, filter: &AuditFilter) -> anyhow::Result<Vec<AuditRecord>> {
+    let mut conditions = vec![];
+    let mut values: Vec<Value> = vec![];
+    if let Some(username) = &filter.username {
+        conditions.push("username = ?".to_string());
+        values.push(Value::Text(username.clone()));
+    }
+    if !filter.actions.is_empty() {
+        let placeholders = vec!["?"; filter.actions.len()].join(", ");
+        conditions.push(format!("action COLLATE NOCASE IN ({})", placeholders));
+        values.extend(filter.actions.iter().cloned().map(Value::Text));
+    }
+    if let Some(path) = &filter.path {
+        // 末尾补上 /，按完整的路径分量比较（区分大小写，不使用 LIKE）
+        let prefix = format!("{}/", path.trim_end_matches('/'));
+        if prefix.starts_with('/') {
+            conditions.push("substr(target || '/', 1, length(?)) = ?".to_string());
+            values.push(Value::Text(prefix.clone()));
+            values.push(Value::Text(prefix));
+        } else {
+            // 记录的 target 一般是服务器上的绝对路径，相对路径可以出现在任意一级目录下
+            conditions.push("instr('/' || target || '/', ?) > 0".to_string());
+            values.push(Value::Text(format!("/{}", prefix)));
+        }
+    }
+    if let Some(since) = filter.since {
+        conditions.push("created_at >= ?".to_string());
+        values.push(Value::Text(since.format(TIMESTAMP_FORMAT).to_string()));
+    }
+    if let Some(until) = filter.until {
+        conditions.push("created_at < ?".to_string());
+        values.push(Value::Text(until.format(TIMESTAMP_FORMAT).to_string()));
+    }
+
+    let mut sql = "SELECT log_id, username, action, target, result, session_id, client_addr,
+            secondary_target, bytes, duration_us, created_at
+        FROM AuditLogs"
+        .to_string();
+    if !conditions.is_empty() {
+        sql.push_str(&format!(" WHERE {}", conditions.join(" AND ")));
+    }
+    // LIMIT -1 表示不限制条数
+    sql.push_str(" ORDER BY created_at, log_id LIMIT ? OFFSET ?");
+    values.push(Value::Integer(filter.limit.map_or(-1, |limit| limit as i64)));
+    values.push(Value::Integer(filter.offset as i64));
+
+    let mut stmt = conn.prepare(&sql)?;
+    let records = stmt
+        .query_map(params_from_iter(values), |row| {
+            Ok(AuditRecord {
+                id: row.get(0)?,
+                username: row.get(1)?,
+                action: row.get(2)?,
+                target: row.get(3)?,
+                result: row.get(4)?,
+                session_id: row.get(5)?,
+                client_addr: row.get(6)?,
+                secondary_target: row.get(7)?,
+                bytes: row.get(8)?,
+                duration_us: row.get(9)?,
+                created_at: row.get(10)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(records)
+}
+
+/// 导出为带表头的 CSV
+pub fn write_csv(writer: impl Write, records: &[AuditRecord]) -> anyhow::Result<()> {
+    let mut writer = csv::Writer::from_writer(writer);
+    for record in records {
+        writer.serialize(record)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// 导出为 JSON Lines，每行一条记录
+pub fn write_jsonl(mut writer: impl Write, records: &[AuditRecord]) -> anyhow::Result<()> {
+    for record in records {
+        serde_json::to_writer(&mut writer, record)?;
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()?;
     Ok(())
 }
 
@@ -118,13 +748,28 @@ mod tests {
                 username TEXT NOT NULL,
                 action TEXT NOT NULL,
                 target TEXT NOT NULL,
-                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+                result TEXT NOT NULL DEFAULT 'Ok',
+                session_id TEXT,
+                client_addr TEXT,
+                secondary_target TEXT,
+                bytes INTEGER,
+                duration_us INTEGER,
+                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+                prev_hash TEXT,
+                entry_hash TEXT
             )",
             params![],
         )
         .expect("Failed to create AuditLogs table");
 
-        log_action_to_audit_logs(&conn, "test", "read", "file.txt").unwrap();
+        let entry = AuditEntry {
+            username: "test".to_string(),
+            action: "read".to_string(),
+            target: "file.txt".to_string(),
+            result: "Ok".to_string(),
+            ..Default::default()
+        };
+        log_action_to_audit_logs(&conn, &entry, GENESIS_HASH).unwrap();
 
         let mut stmt = conn
             .prepare("SELECT * FROM AuditLogs WHERE username = ? AND action = ? AND target = ?")
@@ -142,16 +787,311 @@ mod tests {
         assert_eq!(log.2, "file.txt");
     }
 
+    #[test]
+    fn test_database_logger_records_result() {
+        let pool = MockDatabasePool::get_pool();
+        let logger = DatabaseLogger::new(pool.clone());
+        let subscriber = tracing_subscriber::Registry::default().with(logger.clone());
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(username = "admin", action = "Remove", target = "missing.txt", result = "NoSuchFile", "test log");
+        });
+        logger.flush();
+
+        let conn = pool.get().expect("Failed to get connection from pool");
+        let result: String = conn
+            .query_row(
+                "SELECT result FROM AuditLogs WHERE username = ? AND action = ?",
+                params!["admin", "Remove"],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(result, "NoSuchFile");
+    }
+
+    #[test]
+    fn test_database_logger_records_details() {
+        let pool = MockDatabasePool::get_pool();
+        let logger = DatabaseLogger::new(pool.clone());
+        let subscriber = tracing_subscriber::Registry::default().with(logger.clone());
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(
+                username = "admin",
+                action = "Rename",
+                target = "/srv/a.txt",
+                result = "Ok",
+                session_id = "0123abcd",
+                secondary_target = "/srv/b.txt",
+                bytes = 42u64,
+                duration_us = 1500u64,
+                "test log"
+            );
+        });
+        logger.flush();
+
+        let conn = pool.get().expect("Failed to get connection from pool");
+        let records = query_audit_logs(&conn, &AuditFilter::default()).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].session_id.as_deref(), Some("0123abcd"));
+        assert_eq!(records[0].secondary_target.as_deref(), Some("/srv/b.txt"));
+        assert_eq!(records[0].bytes, Some(42));
+        assert_eq!(records[0].duration_us, Some(1500));
+        assert_eq!(records[0].client_addr, None);
+    }
+
+    #[test]
+    fn test_database_logger_records_client_addr() {
+        let pool = MockDatabasePool::get_pool();
+        let logger = DatabaseLogger::new(pool.clone());
+        let subscriber = tracing_subscriber::Registry::default().with(logger.clone());
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(username = "admin", action = "Login", target = "password", result = "Ok", client_addr = "192.0.2.1:50022", "test log");
+        });
+        logger.flush();
+
+        let conn = pool.get().expect("Failed to get connection from pool");
+        let client_addr: Option<String> = conn
+            .query_row(
+                "SELECT client_addr FROM AuditLogs WHERE action = 'Login'",
+                params![],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(client_addr.as_deref(), Some("192.0.2.1:50022"));
+    }
+
+    #[test]
+    fn test_database_logger_multiple_sinks() {
+        let pool = MockDatabasePool::get_pool();
+        let dir = tempfile::tempdir().unwrap();
+        let config = AuditConfig {
+            sinks: vec![SinkConfig::Sqlite, SinkConfig::Jsonl(dir.path().to_path_buf())],
+            ..Default::default()
+        };
+        let logger = DatabaseLogger::with_config(pool.clone(), config).unwrap();
+        let subscriber = tracing_subscriber::Registry::default().with(logger.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(username = "admin", action = "Remove", target = "a.txt", result = "Ok", "test log");
+        });
+        logger.flush();
+
+        let conn = pool.get().expect("Failed to get connection from pool");
+        assert_eq!(query_audit_logs(&conn, &AuditFilter::default()).unwrap().len(), 1);
+        let file = fs::read_dir(dir.path()).unwrap().next().unwrap().unwrap().path();
+        let entry: AuditEntry = serde_json::from_str(fs::read_to_string(file).unwrap().trim()).unwrap();
+        assert_eq!((entry.action.as_str(), entry.target.as_str()), ("Remove", "a.txt"));
+        assert!(entry.created_at.is_some());
+    }
+
+    #[test]
+    fn test_query_audit_logs() {
+        let pool = MockDatabasePool::get_pool();
+        let conn = pool.get().expect("Failed to get connection from pool");
+        for username in ["alice", "bob", "42"] {
+            conn.execute(
+                "INSERT INTO Users (username, password, role) VALUES (?, '', 'user')",
+                params![username],
+            )
+            .unwrap();
+        }
+        for (username, action, target, created_at) in [
+            ("alice", "Write", "/srv/sftp/assignment3/report.pdf", "2024-05-01 10:00:00"),
+            ("bob", "Remove", "/srv/sftp/assignment3/report.pdf", "2024-05-02 10:00:00"),
+            ("bob", "RemoveDir", "/srv/sftp/assignment3", "2024-05-02 10:01:00"),
+            ("bob", "Remove", "/srv/sftp/assignment30/x_y.txt", "2024-05-03 10:00:00"),
+            ("42", "Read", "/srv/sftp/notes.txt", "2024-05-04 10:00:00"),
+        ] {
+            conn.execute(
+                "INSERT INTO AuditLogs (username, action, target, created_at) VALUES (?, ?, ?, ?)",
+                params![username, action, target, created_at],
+            )
+            .unwrap();
+        }
+
+        let query = |filter: AuditFilter| {
+            query_audit_logs(&conn, &filter)
+                .unwrap()
+                .into_iter()
+                .map(|record| record.id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(query(AuditFilter::default()), vec![1, 2, 3, 4, 5]);
+        // 谁删除了 assignment3/
+        let deleted = AuditFilter {
+            actions: vec!["remove".to_string(), "RemoveDir".to_string()],
+            path: Some("assignment3/".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(query(deleted), vec![2, 3]);
+        let by_path = |path: &str| AuditFilter {
+            path: Some(path.to_string()),
+            ..Default::default()
+        };
+        assert_eq!(query(by_path("/srv/sftp/assignment3")), vec![1, 2, 3]);
+        assert_eq!(query(by_path("/srv/sftp/Assignment3")), Vec::<i64>::new());
+        assert_eq!(query(by_path("/")), vec![1, 2, 3, 4, 5]);
+        let by_time = AuditFilter {
+            username: Some("bob".to_string()),
+            since: Some(parse_time("2024-05-02").unwrap()),
+            until: Some(parse_time("2024-05-03").unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(query(by_time), vec![2, 3]);
+        let page = AuditFilter {
+            limit: Some(2),
+            offset: 2,
+            ..Default::default()
+        };
+        assert_eq!(query(page), vec![3, 4]);
+        assert!(parse_time("yesterday").is_err());
+
+        // 数字用户名也按文本返回
+        let numeric = AuditFilter {
+            username: Some("42".to_string()),
+            ..Default::default()
+        };
+        let records = query_audit_logs(&conn, &numeric).unwrap();
+        assert_eq!(records[0].username, "42");
+
+        let mut csv = vec![];
+        write_csv(&mut csv, &records).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert!(csv.starts_with(
+            "id,username,action,target,result,session_id,client_addr,secondary_target,bytes,duration_us,created_at\n"
+        ));
+        assert!(csv.contains("5,42,Read,/srv/sftp/notes.txt,Ok,,,,,,2024-05-04 10:00:00"));
+
+        let mut jsonl = vec![];
+        write_jsonl(&mut jsonl, &records).unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&jsonl).unwrap();
+        assert_eq!(value["target"], "/srv/sftp/notes.txt");
+        assert_eq!(value["client_addr"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn test_overflow_policies() {
+        let entry = |target: &str| AuditEntry {
+            username: "admin".to_string(),
+            action: "Write".to_string(),
+            target: target.to_string(),
+            result: "Ok".to_string(),
+            created_at: Some(format!("2024-05-01 10:00:0{}", target.as_bytes()[0] - b'a')),
+            ..Default::default()
+        };
+        // 没有后台线程消费的队列，第二条记录开始溢出
+        let logger = |overflow: OverflowPolicy| {
+            let (sender, receiver) = mpsc::sync_channel(1);
+            let logger = DatabaseLogger {
+                sender,
+                overflow,
+                dropped: Arc::new(AtomicU64::new(0)),
+                spill_lock: Arc::new(Mutex::new(())),
+            };
+            (logger, receiver)
+        };
+
+        let (dropping, _receiver) = logger(OverflowPolicy::Drop);
+        for target in ["a", "b", "c"] {
+            dropping.enqueue(entry(target));
+        }
+        assert_eq!(dropping.dropped(), 2);
+
+        let dir = tempfile::tempdir().unwrap();
+        let spill_path = dir.path().join("spill.jsonl");
+        let (spilling, receiver) = logger(OverflowPolicy::Spill(spill_path.clone()));
+        // 队列中的 c 比溢出的 a、b 发生得晚，但先写入数据库
+        for target in ["c", "a", "b"] {
+            spilling.enqueue(entry(target));
+        }
+        assert_eq!(spilling.dropped(), 0);
+        assert_eq!(fs::read_to_string(&spill_path).unwrap().lines().count(), 2);
+
+        // 后台线程写完队列中的记录后导入溢出文件，所有 DatabaseLogger 被丢弃后退出
+        let pool = MockDatabasePool::get_pool();
+        let worker = AuditWorker {
+            sinks: vec![Box::new(DatabaseSink::new(pool.clone()))],
+            receiver,
+            batch_size: 2,
+            spill_path: Some(spill_path.clone()),
+            spill_lock: spilling.spill_lock.clone(),
+            dropped: spilling.dropped.clone(),
+            reported_dropped: 0,
+        };
+        drop(spilling);
+        worker.run();
+        assert!(!spill_path.exists());
+        let conn = pool.get().expect("Failed to get connection from pool");
+        let targets: Vec<_> = query_audit_logs(&conn, &AuditFilter::default())
+            .unwrap()
+            .into_iter()
+            .map(|record| record.target)
+            .collect();
+        assert_eq!(targets, ["a", "b", "c"]);
+        let report = verify_chain(&conn).unwrap();
+        assert!(report.broken.is_none());
+        assert_eq!(report.verified, 3);
+    }
+
+    #[test]
+    fn test_verify_chain() {
+        let pool = MockDatabasePool::get_pool();
+        let conn = pool.get().expect("Failed to get connection from pool");
+        // 加入哈希链之前的旧记录
+        conn.execute(
+            "INSERT INTO AuditLogs (username, action, target) VALUES ('admin', 'Read', 'old.txt')",
+            params![],
+        )
+        .unwrap();
+        drop(conn);
+
+        let logger = DatabaseLogger::new(pool.clone());
+        let subscriber = tracing_subscriber::Registry::default().with(logger.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            for target in ["a.txt", "b.txt", "c.txt", "d.txt"] {
+                tracing::info!(username = "admin", action = "Write", target = target, bytes = 5u64, "test log");
+            }
+        });
+        logger.flush();
+
+        let conn = pool.get().expect("Failed to get connection from pool");
+        let report = verify_chain(&conn).unwrap();
+        assert_eq!((report.verified, report.legacy), (4, 1));
+        assert_eq!(report.first, Some((2, true)));
+        assert_eq!(report.last.as_ref().map(|(id, _)| *id), Some(5));
+        assert!(report.broken.is_none());
+
+        // 修改内容
+        conn.execute("UPDATE AuditLogs SET target = 'x.txt' WHERE log_id = 3", params![]).unwrap();
+        assert_eq!(verify_chain(&conn).unwrap().broken.unwrap().0, 3);
+        conn.execute("UPDATE AuditLogs SET target = 'b.txt' WHERE log_id = 3", params![]).unwrap();
+        assert!(verify_chain(&conn).unwrap().broken.is_none());
+
+        // 删除中间的记录
+        conn.execute("DELETE FROM AuditLogs WHERE log_id = 4", params![]).unwrap();
+        let report = verify_chain(&conn).unwrap();
+        assert_eq!(report.broken.unwrap().0, 5);
+        assert_eq!(report.verified, 2);
+
+        // 从开头清理掉的记录不算不一致，但链不再从头开始
+        conn.execute("DELETE FROM AuditLogs WHERE log_id <= 4", params![]).unwrap();
+        let report = verify_chain(&conn).unwrap();
+        assert!(report.broken.is_none());
+        assert_eq!(report.first, Some((5, false)));
+    }
+
     #[test]
     fn test_database_logger() {
         let _manager = SqliteConnectionManager::memory();
         let pool = MockDatabasePool::get_pool();
 
         let logger = DatabaseLogger::new(pool.clone());
-        let subscriber = tracing_subscriber::Registry::default().with(logger);
+        let subscriber = tracing_subscriber::Registry::default().with(logger.clone());
 
         tracing::subscriber::set_global_default(subscriber).expect("Failed to set subscriber");
         tracing::info!(username = "admin", action = "read", target = "file.txt", "test log");
+        logger.flush();
 
         let conn = pool.get().expect("Failed to get connection from pool");
         let mut stmt = conn
@@ -1,9 +1,15 @@
+use crate::authorized_keys;
 use crate::database::DatabasePool;
+use crate::password::{hash_password, needs_rehash, verify_password, PasswordPolicy};
+use crate::totp;
 use anyhow::Result;
-use bcrypt::{hash, verify};
+use log::warn;
 use r2d2::Pool;
 use r2d2_sqlite::SqliteConnectionManager;
-use rusqlite::params;
+use russh_keys::key::PublicKey;
+use rusqlite::{params, OptionalExtension};
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 pub trait Auther {
@@ -16,6 +22,37 @@ pub trait Auther {
         password: &str,
         old_password: &str,
     ) -> Result<()>;
+    /// 设置用户的根目录，create 为 true 时目录不存在则创建
+    fn set_root(&self, username: &str, root: &Path, create: bool) -> Result<()>;
+    /// 用户的根目录；未设置时返回 None，使用全局的 VIRTUAL_ROOT_PATH
+    fn get_root(&self, username: &str) -> Result<Option<PathBuf>>;
+    /// 公钥是否登记在该用户名下且未过期，用于回应客户端的公钥询问
+    fn is_authorized_key(&self, username: &str, key: &PublicKey) -> Result<bool>;
+    /// 客户端已证明持有私钥后，用公钥完成认证
+    fn authenticate_publickey(&mut self, username: &str, key: &PublicKey) -> Result<()>;
+    /// 用户是否登记了 TOTP 两步验证
+    fn is_totp_enrolled(&self, username: &str) -> Result<bool>;
+    /// 检查密码之后的第二步：TOTP 验证码或恢复码
+    fn verify_second_factor(&self, username: &str, code: &str) -> Result<()>;
+    /// 全部用户，按用户名排序
+    fn list_users(&self) -> Result<Vec<UserInfo>>;
+    /// 删除用户以及登记在该用户名下的公钥、恢复码、访问规则、配额和封禁
+    fn delete_user(&self, username: &str) -> Result<()>;
+    /// 停用或重新启用用户，停用的用户不能登录
+    fn set_disabled(&self, username: &str, disabled: bool) -> Result<()>;
+    fn set_role(&self, username: &str, role: &str) -> Result<()>;
+    /// 管理员重置密码，不需要旧密码，但仍然检查密码强度
+    fn reset_password(&self, username: &str, password: &str) -> Result<()>;
+}
+
+/// `auth list` 显示的一个用户
+pub struct UserInfo {
+    pub username: String,
+    pub role: String,
+    pub disabled: bool,
+    pub created_at: String,
+    /// 最近一次成功登录的时间，从未登录过为 None
+    pub last_login: Option<String>,
 }
 
 pub struct User<P: DatabasePool> {
@@ -45,12 +82,30 @@ impl<P: DatabasePool> User<P> {
             authed: false,
         }
     }
+
+    /// 删除、停用或降级 username 之前检查：不能让服务器失去最后一个可用的管理员
+    fn ensure_other_admin(&self, username: &str) -> Result<()> {
+        if !self.check_permission(username, "admin").unwrap_or(false) {
+            return Ok(());
+        }
+        let conn = self.pool.get()?;
+        let others: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM Users WHERE role = 'admin' AND disabled = 0 AND username != ?",
+            params![username],
+            |row| row.get(0),
+        )?;
+        if others == 0 {
+            return Err(anyhow::anyhow!("{} is the last enabled admin", username));
+        }
+        Ok(())
+    }
 }
 
 impl<P: DatabasePool> Auther for User<P> {
     fn register(&self, username: &str, password: &str) -> Result<()> {
+        PasswordPolicy::from_env()?.check(username, password)?;
         let conn = self.pool.get()?;
-        let hashed_password = hash(password, bcrypt::DEFAULT_COST)?;
+        let hashed_password = hash_password(password)?;
         conn.execute(
             "INSERT INTO Users (username, password, role) VALUES (?, ?, ?)",
             params![username, hashed_password, "user"],
@@ -59,9 +114,26 @@ impl<P: DatabasePool> Auther for User<P> {
     }
     fn authenticate(&mut self, username: &str, password: &str) -> Result<()> {
         let conn = self.pool.get()?;
-        let mut stmt = conn.prepare("SELECT password FROM Users WHERE username = ?")?;
-        let hash: String = stmt.query_row(params![username], |row| row.get(0))?;
-        if verify(password, &hash)? {
+        let mut stmt = conn.prepare("SELECT password, disabled FROM Users WHERE username = ?")?;
+        let (hash, disabled): (String, bool) =
+            stmt.query_row(params![username], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        if verify_password(password, &hash)? {
+            if disabled {
+                return Err(anyhow::anyhow!("User {} is disabled", username));
+            }
+            // 旧的 bcrypt 哈希或过时的参数在登录成功时用当前算法重新计算
+            if needs_rehash(&hash) {
+                let upgraded = hash_password(password).and_then(|hashed_password| {
+                    conn.execute(
+                        "UPDATE Users SET password = ? WHERE username = ?",
+                        params![hashed_password, username],
+                    )?;
+                    Ok(())
+                });
+                if let Err(err) = upgraded {
+                    warn!("failed to upgrade password hash of {}: {}", username, err);
+                }
+            }
             self.username = username.to_string();
             self.authed = true;
             Ok(())
@@ -84,8 +156,9 @@ impl<P: DatabasePool> Auther for User<P> {
         let conn = self.pool.get()?;
         let mut stmt = conn.prepare("SELECT password FROM Users WHERE username = ?")?;
         let hash_old: String = stmt.query_row(params![username], |row| row.get(0))?;
-        if verify(old_password, &hash_old)? {
-            let hashed_password = hash(password, bcrypt::DEFAULT_COST)?;
+        if verify_password(old_password, &hash_old)? {
+            PasswordPolicy::from_env()?.check(username, password)?;
+            let hashed_password = hash_password(password)?;
             conn.execute(
                 "UPDATE Users SET password = ? WHERE username = ?",
                 params![hashed_password, username],
@@ -95,6 +168,164 @@ impl<P: DatabasePool> Auther for User<P> {
             Err(anyhow::anyhow!("Invalid password"))
         }
     }
+    fn set_root(&self, username: &str, root: &Path, create: bool) -> Result<()> {
+        if create {
+            fs::create_dir_all(root)?;
+        }
+        let root = root
+            .canonicalize()
+            .map_err(|err| anyhow::anyhow!("Invalid root {}: {}", root.display(), err))?;
+        if !root.is_dir() {
+            return Err(anyhow::anyhow!("Root {} is not a directory", root.display()));
+        }
+        let conn = self.pool.get()?;
+        let updated = conn.execute(
+            "UPDATE Users SET root = ? WHERE username = ?",
+            params![root.to_string_lossy(), username],
+        )?;
+        if updated == 0 {
+            return Err(anyhow::anyhow!("No such user: {}", username));
+        }
+        Ok(())
+    }
+    fn get_root(&self, username: &str) -> Result<Option<PathBuf>> {
+        let conn = self.pool.get()?;
+        let root: Option<Option<String>> = conn
+            .query_row(
+                "SELECT root FROM Users WHERE username = ?",
+                params![username],
+                |row| row.get(0),
+            )
+            .optional()?;
+        match root {
+            Some(root) => Ok(root.map(PathBuf::from)),
+            None => Err(anyhow::anyhow!("No such user: {}", username)),
+        }
+    }
+    fn is_authorized_key(&self, username: &str, key: &PublicKey) -> Result<bool> {
+        let conn = self.pool.get()?;
+        // 停用的用户的公钥一律视为未登记
+        let disabled: Option<bool> = conn
+            .query_row(
+                "SELECT disabled FROM Users WHERE username = ?",
+                params![username],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if disabled != Some(false) {
+            return Ok(false);
+        }
+        authorized_keys::is_authorized(&conn, username, key)
+    }
+    fn authenticate_publickey(&mut self, username: &str, key: &PublicKey) -> Result<()> {
+        if self.is_authorized_key(username, key)? {
+            self.username = username.to_string();
+            self.authed = true;
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!("Public key is not authorized"))
+        }
+    }
+    fn is_totp_enrolled(&self, username: &str) -> Result<bool> {
+        let conn = self.pool.get()?;
+        totp::is_enrolled(&conn, username)
+    }
+    fn verify_second_factor(&self, username: &str, code: &str) -> Result<()> {
+        let conn = self.pool.get()?;
+        if totp::verify(&conn, username, code)? {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!("Invalid verification code"))
+        }
+    }
+    fn list_users(&self) -> Result<Vec<UserInfo>> {
+        let conn = self.pool.get()?;
+        // 最近一次登录取自 LoginAttempts，忽略同名用户被删除之前的记录
+        let mut stmt = conn.prepare(
+            "SELECT username, role, disabled, created_at,
+                (SELECT MAX(created_at) FROM LoginAttempts
+                    WHERE LoginAttempts.username = Users.username AND success = 1
+                        AND LoginAttempts.created_at >= Users.created_at)
+            FROM Users ORDER BY username",
+        )?;
+        let users = stmt
+            .query_map(params![], |row| {
+                Ok(UserInfo {
+                    username: row.get(0)?,
+                    role: row.get(1)?,
+                    disabled: row.get(2)?,
+                    created_at: row.get(3)?,
+                    last_login: row.get(4)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(users)
+    }
+    fn delete_user(&self, username: &str) -> Result<()> {
+        self.ensure_other_admin(username)?;
+        let mut conn = self.pool.get()?;
+        let tx = conn.transaction()?;
+        let deleted = tx.execute("DELETE FROM Users WHERE username = ?", params![username])?;
+        if deleted == 0 {
+            return Err(anyhow::anyhow!("No such user: {}", username));
+        }
+        for table in ["AuthorizedKeys", "RecoveryCodes", "AccessRules", "Quotas", "QuotaUsage"] {
+            tx.execute(
+                &format!("DELETE FROM {} WHERE username = ?", table),
+                params![username],
+            )?;
+        }
+        tx.execute(
+            "DELETE FROM Bans WHERE kind = 'user' AND subject = ?",
+            params![username],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+    fn set_disabled(&self, username: &str, disabled: bool) -> Result<()> {
+        if disabled {
+            self.ensure_other_admin(username)?;
+        }
+        let conn = self.pool.get()?;
+        let updated = conn.execute(
+            "UPDATE Users SET disabled = ? WHERE username = ?",
+            params![disabled, username],
+        )?;
+        if updated == 0 {
+            return Err(anyhow::anyhow!("No such user: {}", username));
+        }
+        Ok(())
+    }
+    fn set_role(&self, username: &str, role: &str) -> Result<()> {
+        if role.trim().is_empty() {
+            return Err(anyhow::anyhow!("Role must not be empty"));
+        }
+        if role != "admin" {
+            self.ensure_other_admin(username)?;
+        }
+        let conn = self.pool.get()?;
+        let updated = conn.execute(
+            "UPDATE Users SET role = ? WHERE username = ?",
+            params![role, username],
+        )?;
+        if updated == 0 {
+            return Err(anyhow::anyhow!("No such user: {}", username));
+        }
+        Ok(())
+    }
+    fn reset_password(&self, username: &str, password: &str) -> Result<()> {
+        PasswordPolicy::from_env()?.check(username, password)?;
+        let conn = self.pool.get()?;
+        let hashed_password = hash_password(password)?;
+        let updated = conn.execute(
+            "UPDATE Users SET password = ? WHERE username = ?",
+            params![hashed_password, username],
+        )?;
+        if updated == 0 {
+            return Err(anyhow::anyhow!("No such user: {}", username));
+        }
+        Ok(())
+    }
 }
 
 // Test
@@ -109,7 +340,8 @@ mod tests {
         let pool = MockDatabasePool::get_pool();
         let conn = pool.get().unwrap();
         let auth = User::<MockDatabasePool>::new_with_pool(pool);
-        auth.register("test", "password").unwrap();
+        assert!(auth.register("test", "password").is_err());
+        auth.register("test", "Correct-Horse-9").unwrap();
         let mut stmt = conn
             .prepare("SELECT * FROM Users WHERE username = ?")
             .unwrap();
@@ -121,7 +353,8 @@ mod tests {
             })
             .unwrap();
         assert_eq!(user.0, "test");
-        assert!(bcrypt::verify("password", &user.1).unwrap());
+        assert!(user.1.starts_with("$argon2id$"));
+        assert!(verify_password("Correct-Horse-9", &user.1).unwrap());
     }
 
     #[test]
@@ -139,6 +372,15 @@ mod tests {
         .unwrap();
         let mut auth = User::<MockDatabasePool>::new_with_pool(pool);
         auth.authenticate("test", "password").unwrap();
+
+        // 登录成功后 bcrypt 哈希被透明地升级为 argon2id
+        let hash: String = conn
+            .query_row("SELECT password FROM Users WHERE username = 'test'", params![], |row| row.get(0))
+            .unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+        assert!(verify_password("password", &hash).unwrap());
+        auth.authenticate("test", "password").unwrap();
+        assert!(auth.authenticate("test", "wrong").is_err());
     }
 
     #[test]
@@ -173,12 +415,107 @@ mod tests {
         )
         .unwrap();
         let auth = User::<MockDatabasePool>::new_with_pool(pool);
+        assert!(auth.update_user_password("test", "", "password").is_err());
         auth.update_user_password("test", "new_password", "password")
             .unwrap();
         let mut stmt = conn
             .prepare("SELECT password FROM Users WHERE username = ?")
             .unwrap();
         let hash: String = stmt.query_row(params!["test"], |row| row.get(0)).unwrap();
-        assert!(bcrypt::verify("new_password", &hash).unwrap());
+        assert!(verify_password("new_password", &hash).unwrap());
+    }
+
+    #[test]
+    fn test_set_root() {
+        let pool = MockDatabasePool::get_pool();
+        let auth = User::<MockDatabasePool>::new_with_pool(pool);
+        auth.register("test", "Correct-Horse-9").unwrap();
+        assert_eq!(auth.get_root("test").unwrap(), None);
+
+        let dir = tempfile::tempdir().unwrap();
+        let home = dir.path().join("homes/test");
+        assert!(auth.set_root("test", &home, false).is_err());
+        auth.set_root("test", &home, true).unwrap();
+        assert!(home.is_dir());
+        assert_eq!(auth.get_root("test").unwrap(), Some(home.canonicalize().unwrap()));
+
+        assert!(auth.set_root("nobody", &home, false).is_err());
+        assert!(auth.get_root("nobody").is_err());
+    }
+
+    #[test]
+    fn test_authenticate_publickey() {
+        let pool = MockDatabasePool::get_pool();
+        let conn = pool.get().unwrap();
+        let mut auth = User::<MockDatabasePool>::new_with_pool(pool);
+        auth.register("test", "Correct-Horse-9").unwrap();
+        let key = russh_keys::key::KeyPair::generate_ed25519()
+            .unwrap()
+            .clone_public_key()
+            .unwrap();
+        assert!(!auth.is_authorized_key("test", &key).unwrap());
+        assert!(auth.authenticate_publickey("test", &key).is_err());
+
+        authorized_keys::add_key(&conn, "test", &key, None, None).unwrap();
+        assert!(auth.is_authorized_key("test", &key).unwrap());
+        assert!(!auth.is_authorized_key("admin", &key).unwrap());
+        auth.authenticate_publickey("test", &key).unwrap();
+        assert_eq!(auth.username, "test");
+
+        auth.set_disabled("test", true).unwrap();
+        assert!(!auth.is_authorized_key("test", &key).unwrap());
+        assert!(auth.authenticate_publickey("test", &key).is_err());
+    }
+
+    #[test]
+    fn test_disable_and_delete() {
+        let pool = MockDatabasePool::get_pool();
+        let conn = pool.get().unwrap();
+        let mut auth = User::<MockDatabasePool>::new_with_pool(pool);
+        auth.register("test", "Correct-Horse-9").unwrap();
+        auth.set_disabled("test", true).unwrap();
+        assert!(auth.authenticate("test", "Correct-Horse-9").is_err());
+        auth.set_disabled("test", false).unwrap();
+        auth.authenticate("test", "Correct-Horse-9").unwrap();
+
+        // 最后一个管理员不能被停用、降级或删除
+        assert!(auth.set_disabled("admin", true).is_err());
+        assert!(auth.set_role("admin", "user").is_err());
+        assert!(auth.delete_user("admin").is_err());
+        auth.set_role("test", "admin").unwrap();
+        auth.set_role("admin", "user").unwrap();
+
+        conn.execute(
+            "INSERT INTO LoginAttempts (username, method, success) VALUES ('admin', 'password', 1)",
+            params![],
+        )
+        .unwrap();
+        let users = auth.list_users().unwrap();
+        assert_eq!(users.len(), 2);
+        assert_eq!((users[0].username.as_str(), users[0].role.as_str()), ("admin", "user"));
+        assert!(users[0].last_login.is_some());
+        assert_eq!(users[1].last_login, None);
+
+        let key = russh_keys::key::KeyPair::generate_ed25519()
+            .unwrap()
+            .clone_public_key()
+            .unwrap();
+        authorized_keys::add_key(&conn, "admin", &key, None, None).unwrap();
+        auth.delete_user("admin").unwrap();
+        assert!(auth.delete_user("admin").is_err());
+        assert!(authorized_keys::list_keys(&conn, "admin").unwrap().is_empty());
+        assert_eq!(auth.list_users().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_reset_password() {
+        let pool = MockDatabasePool::get_pool();
+        let mut auth = User::<MockDatabasePool>::new_with_pool(pool);
+        auth.register("test", "Correct-Horse-9").unwrap();
+        assert!(auth.reset_password("test", "short").is_err());
+        assert!(auth.reset_password("nobody", "Battery-Staple-7").is_err());
+        auth.reset_password("test", "Battery-Staple-7").unwrap();
+        assert!(auth.authenticate("test", "Correct-Horse-9").is_err());
+        auth.authenticate("test", "Battery-Staple-7").unwrap();
     }
 }
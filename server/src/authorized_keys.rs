@@ -0,0 +1,187 @@
+//! 数据库中保存的用户公钥（相当于 ~/.ssh/authorized_keys）
+use anyhow::{Context, Result};
+use chrono::{NaiveDate, NaiveDateTime};
+use russh_keys::key::PublicKey;
+use russh_keys::PublicKeyBase64;
+use rusqlite::{params, Connection};
+
+/// 与 SQLite 的 CURRENT_TIMESTAMP 相同的格式（UTC），可以直接比较
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+#[derive(Debug, Clone)]
+pub struct AuthorizedKey {
+    pub id: i64,
+    pub algorithm: String,
+    pub fingerprint: String,
+    pub comment: Option<String>,
+    pub expires_at: Option<String>,
+    pub created_at: String,
+}
+
+/// 解析 OpenSSH 格式的公钥，例如 `ssh-ed25519 AAAAC3N... alice@laptop`，
+/// 也接受只有 base64 部分的公钥。返回公钥和行内的注释
+pub fn parse_key_line(line: &str) -> Result<(PublicKey, Option<String>)> {
+    let mut parts = line.split_whitespace();
+    let first = parts.next().context("Empty public key")?;
+    let (base64, comment) = if first.starts_with("AAAA") {
+        (first, parts.collect::<Vec<_>>())
+    } else {
+        let base64 = parts.next().context("Missing base64 part of the public key")?;
+        (base64, parts.collect::<Vec<_>>())
+    };
+    let key = russh_keys::parse_public_key_base64(base64).context("Invalid public key")?;
+    let comment = (!comment.is_empty()).then(|| comment.join(" "));
+    Ok((key, comment))
+}
+
+/// 解析到期时间（UTC），接受 `YYYY-MM-DD` 或 `YYYY-MM-DD HH:MM:SS`
+pub fn parse_expiry(expiry: &str) -> Result<NaiveDateTime> {
+    NaiveDateTime::parse_from_str(expiry, TIMESTAMP_FORMAT)
+        .or_else(|_| {
+            NaiveDate::parse_from_str(expiry, "%Y-%m-%d")
+                .map(|date| date.and_hms_opt(0, 0, 0).unwrap_or_default())
+        })
+        .with_context(|| format!("Invalid expiry time: {}", expiry))
+}
+
+/// 为用户添加一个公钥，返回其 id
+pub fn add_key(
+    conn: &Connection,
+    username: &str,
+    key: &PublicKey,
+    comment: Option<&str>,
+    expires_at: Option<NaiveDateTime>,
+) -> Result<i64> {
+    let user_exists = conn
+        .prepare("SELECT 1 FROM Users WHERE username = ?")?
+        .exists(params![username])?;
+    if !user_exists {
+        return Err(anyhow::anyhow!("No such user: {}", username));
+    }
+    conn.execute(
+        "INSERT INTO AuthorizedKeys (username, algorithm, public_key, fingerprint, comment, expires_at)
+            VALUES (?, ?, ?, ?, ?, ?)",
+        params![
+            username,
+            key.name(),
+            key.public_key_base64(),
+            key.fingerprint(),
+            comment,
+            expires_at.map(|time| time.format(TIMESTAMP_FORMAT).to_string())
+        ],
+    )
+    .context("Failed to add public key, is it already registered?")?;
+    Ok(conn.last_insert_rowid())
+}
+
+pub fn list_keys(conn: &Connection, username: &str) -> Result<Vec<AuthorizedKey>> {
+    let mut stmt = conn.prepare(
+        "SELECT key_id, algorithm, fingerprint, comment, expires_at, created_at
+            FROM AuthorizedKeys WHERE username = ? ORDER BY key_id",
+    )?;
+    let keys = stmt
+        .query_map(params![username], |row| {
+            Ok(AuthorizedKey {
+                id: row.get(0)?,
+                algorithm: row.get(1)?,
+                fingerprint: row.get(2)?,
+                comment: row.get(3)?,
+                expires_at: row.get(4)?,
+                created_at: row.get(5)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(keys)
+}
+
+pub fn revoke_key(conn: &Connection, username: &str, id: i64) -> Result<()> {
+    let removed = conn.execute(
+        "DELETE FROM AuthorizedKeys WHERE username = ? AND key_id = ?",
+        params![username, id],
+    )?;
+    if removed == 0 {
+        return Err(anyhow::anyhow!("User {} has no key {}", username, id));
+    }
+    Ok(())
+}
+
+/// 公钥是否属于该用户且尚未过期
+pub fn is_authorized(conn: &Connection, username: &str, key: &PublicKey) -> Result<bool> {
+    let authorized = conn
+        .prepare(
+            "SELECT 1 FROM AuthorizedKeys
+                WHERE username = ? AND public_key = ?
+                AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)",
+        )?
+        .exists(params![username, key.public_key_base64()])?;
+    Ok(authorized)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{DatabasePool, MockDatabasePool};
+    use russh_keys::key::KeyPair;
+
+    fn public_key() -> PublicKey {
+        KeyPair::generate_ed25519().unwrap().clone_public_key().unwrap()
+    }
+
+    #[test]
+    fn test_parse_key_line() {
+        let key = public_key();
+        let line = format!("ssh-ed25519 {} alice@laptop", key.public_key_base64());
+        let (parsed, comment) = parse_key_line(&line).unwrap();
+        assert_eq!(parsed, key);
+        assert_eq!(comment.as_deref(), Some("alice@laptop"));
+
+        let (parsed, comment) = parse_key_line(&key.public_key_base64()).unwrap();
+        assert_eq!(parsed, key);
+        assert_eq!(comment, None);
+
+        assert!(parse_key_line("ssh-ed25519 not-base64").is_err());
+        assert!(parse_key_line("").is_err());
+    }
+
+    #[test]
+    fn test_parse_expiry() {
+        assert_eq!(
+            parse_expiry("2030-01-02").unwrap().to_string(),
+            "2030-01-02 00:00:00"
+        );
+        assert_eq!(
+            parse_expiry("2030-01-02 03:04:05").unwrap().to_string(),
+            "2030-01-02 03:04:05"
+        );
+        assert!(parse_expiry("tomorrow").is_err());
+    }
+
+    #[test]
+    fn test_authorized_keys() {
+        let pool = MockDatabasePool::get_pool();
+        let conn = pool.get().unwrap();
+        let key = public_key();
+        let other = public_key();
+
+        assert!(add_key(&conn, "nobody", &key, None, None).is_err());
+        let id = add_key(&conn, "admin", &key, Some("laptop"), None).unwrap();
+        assert!(add_key(&conn, "admin", &key, None, None).is_err());
+        assert!(is_authorized(&conn, "admin", &key).unwrap());
+        assert!(!is_authorized(&conn, "admin", &other).unwrap());
+
+        // 已过期的公钥不能用于登录
+        let expired = parse_expiry("2000-01-01").unwrap();
+        add_key(&conn, "admin", &other, None, Some(expired)).unwrap();
+        assert!(!is_authorized(&conn, "admin", &other).unwrap());
+
+        let keys = list_keys(&conn, "admin").unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0].comment.as_deref(), Some("laptop"));
+        assert_eq!(keys[0].fingerprint, key.fingerprint());
+        assert_eq!(keys[1].expires_at.as_deref(), Some("2000-01-01 00:00:00"));
+
+        revoke_key(&conn, "admin", id).unwrap();
+        assert!(!is_authorized(&conn, "admin", &key).unwrap());
+        assert!(revoke_key(&conn, "admin", id).is_err());
+    }
+}
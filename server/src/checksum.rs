@@ -0,0 +1,201 @@
+//! check-file 扩展使用的哈希算法
+use std::fs;
+use std::io;
+use std::os::unix::fs::FileExt;
+
+use sha1::Digest;
+
+use crate::extensions::MAX_CHECK_FILE_HASH_LEN;
+
+/// 每次从文件读取的数据量
+const CHUNK_SIZE: usize = 64 * 1024;
+/// 分块哈希时允许的最小块大小（draft-ietf-secsh-filexfer-extensions 的要求）
+const MIN_BLOCK_SIZE: u32 = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Md5,
+    Sha1,
+    Sha256,
+}
+
+impl HashAlgorithm {
+    /// 按客户端给出的顺序（逗号分隔）选择第一个支持的算法
+    pub fn negotiate(algorithms: &str) -> Option<Self> {
+        algorithms
+            .split(',')
+            .find_map(|name| match name.trim() {
+                "md5" => Some(Self::Md5),
+                "sha1" => Some(Self::Sha1),
+                "sha256" => Some(Self::Sha256),
+                _ => None,
+            })
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Md5 => "md5",
+            Self::Sha1 => "sha1",
+            Self::Sha256 => "sha256",
+        }
+    }
+
+    /// 一个哈希值的字节数
+    fn digest_len(&self) -> u64 {
+        match self {
+            Self::Md5 => 16,
+            Self::Sha1 => 20,
+            Self::Sha256 => 32,
+        }
+    }
+
+    fn hasher(&self) -> Hasher {
+        match self {
+            Self::Md5 => Hasher::Md5(md5::Context::new()),
+            Self::Sha1 => Hasher::Sha1(sha1::Sha1::new()),
+            Self::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+        }
+    }
+}
+
+enum Hasher {
+    Md5(md5::Context),
+    Sha1(sha1::Sha1),
+    Sha256(sha2::Sha256),
+}
+
+impl Hasher {
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Md5(context) => context.consume(data),
+            Self::Sha1(hasher) => hasher.update(data),
+            Self::Sha256(hasher) => hasher.update(data),
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        match self {
+            Self::Md5(context) => context.compute().to_vec(),
+            Self::Sha1(hasher) => hasher.finalize().to_vec(),
+            Self::Sha256(hasher) => hasher.finalize().to_vec(),
+        }
+    }
+}
+
+/// 计算文件 [start, start + length) 范围内数据的哈希，length 为 0 时一直到文件末尾。
+/// block_size 为 0 时返回整个范围的哈希，否则按块分别计算后依次拼接。
+/// 拼接后的哈希放不进一个应答包时返回 InvalidInput，不会读取文件。
+pub fn hash_range(
+    file: &fs::File,
+    algorithm: HashAlgorithm,
+    start: u64,
+    length: u64,
+    block_size: u32,
+) -> io::Result<Vec<u8>> {
+    if block_size != 0 && block_size < MIN_BLOCK_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Block size must be at least {} bytes", MIN_BLOCK_SIZE),
+        ));
+    }
+    let end = match length {
+        0 => u64::MAX,
+        length => start.saturating_add(length),
+    };
+    let block_size = match block_size {
+        0 => u64::MAX,
+        block_size => block_size as u64,
+    };
+    // 实际计算的范围到文件末尾为止，空范围也有一个哈希
+    let range = file.metadata()?.len().saturating_sub(start).min(end - start);
+    let blocks = range.div_ceil(block_size).max(1);
+    if blocks.saturating_mul(algorithm.digest_len()) > MAX_CHECK_FILE_HASH_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Too many blocks to hash, use a larger block size or a shorter range",
+        ));
+    }
+
+    let mut hashes = vec![];
+    let mut hasher = algorithm.hasher();
+    let mut block_filled = 0;
+    let mut offset = start;
+    let mut buf = vec![0; CHUNK_SIZE];
+    while offset < end {
+        let want = (end - offset).min(block_size - block_filled).min(CHUNK_SIZE as u64);
+        let bytes_read = file.read_at(&mut buf[..want as usize], offset)?;
+        if bytes_read == 0 {
+            break;
+        }
+        hasher.update(&buf[..bytes_read]);
+        offset += bytes_read as u64;
+        block_filled += bytes_read as u64;
+        if block_filled == block_size {
+            hashes.extend(hasher.finish());
+            hasher = algorithm.hasher();
+            block_filled = 0;
+        }
+    }
+    // 最后一个不完整的块；没有分块时即为整个范围的哈希
+    if block_filled > 0 || hashes.is_empty() {
+        hashes.extend(hasher.finish());
+    }
+    Ok(hashes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(HashAlgorithm::negotiate("sha512,sha256,md5"), Some(HashAlgorithm::Sha256));
+        assert_eq!(HashAlgorithm::negotiate("md5"), Some(HashAlgorithm::Md5));
+        assert_eq!(HashAlgorithm::negotiate("crc32"), None);
+    }
+
+    #[test]
+    fn test_hash_range() {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(&[b'a'; 600]).unwrap();
+
+        let whole = hash_range(&file, HashAlgorithm::Sha256, 0, 0, 0).unwrap();
+        assert_eq!(whole, sha2::Sha256::digest([b'a'; 600]).to_vec());
+
+        let md5 = hash_range(&file, HashAlgorithm::Md5, 100, 10, 0).unwrap();
+        assert_eq!(md5, md5::compute([b'a'; 10]).to_vec());
+
+        // 600 字节按 256 字节分块：256 + 256 + 88
+        let blocks = hash_range(&file, HashAlgorithm::Sha1, 0, 0, 256).unwrap();
+        let mut expected = sha1::Sha1::digest([b'a'; 256]).to_vec();
+        expected.extend(sha1::Sha1::digest([b'a'; 256]));
+        expected.extend(sha1::Sha1::digest([b'a'; 88]));
+        assert_eq!(blocks, expected);
+
+        let err = hash_range(&file, HashAlgorithm::Sha1, 0, 0, 16).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_hash_range_reply_limit() {
+        let file = tempfile::tempfile().unwrap();
+        file.set_len(4 * 1024 * 1024).unwrap();
+
+        // 4 MiB 按 256 字节分块需要 16384 个 sha256，超过一个包的长度
+        let err = hash_range(&file, HashAlgorithm::Sha256, 0, 0, 256).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        // 超出文件末尾的部分不计算
+        let err = hash_range(&file, HashAlgorithm::Sha256, 0, u64::MAX, 256).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        // 最多 MAX_CHECK_FILE_HASH_LEN / 32 个块
+        let max_range = MAX_CHECK_FILE_HASH_LEN / 32 * 256;
+        let hashes = hash_range(&file, HashAlgorithm::Sha256, 0, max_range, 256).unwrap();
+        assert_eq!(hashes.len() as u64, MAX_CHECK_FILE_HASH_LEN);
+        let err = hash_range(&file, HashAlgorithm::Sha256, 0, max_range + 1, 256).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let tail = hash_range(&file, HashAlgorithm::Sha256, 4 * 1024 * 1024 - 512, 0, 256).unwrap();
+        assert_eq!(tail.len(), 64);
+    }
+}
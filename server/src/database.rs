@@ -2,7 +2,7 @@ use anyhow::{Context, Result};
 use lazy_static::lazy_static;
 use r2d2::Pool;
 use r2d2_sqlite::SqliteConnectionManager;
-use rusqlite::{params, Connection};
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
 use std::{env, sync::Arc};
 
 // Define a trait for the database connection pool
@@ -19,23 +19,107 @@ impl DatabasePool for GlobalDatabasePool {
     }
 }
 
+#[cfg(test)]
 pub struct MockDatabasePool;
 
+#[cfg(test)]
 impl DatabasePool for MockDatabasePool {
     fn get_pool() -> Arc<Pool<SqliteConnectionManager>> {
-        // Create a mock pool
-        let manager = SqliteConnectionManager::memory();
+        // Create a mock pool. 普通的内存连接各自是一个独立的数据库，
+        // 这里用共享缓存的命名内存数据库，池中的所有连接都访问同一个已经迁移过的数据库
+        let name = format!("file:memdb_{:016x}?mode=memory&cache=shared", rand::random::<u64>());
+        let manager = SqliteConnectionManager::file(name);
         let pool = Pool::new(manager).expect("Failed to create database connection pool");
 
         // Initialize the mock database
-        let conn = pool.get().expect("Failed to get connection from pool");
-        initialize_database(&conn).expect("Failed to initialize database");
+        let mut conn = pool.get().expect("Failed to get connection from pool");
+        migrate(&mut conn).expect("Failed to migrate database");
+        drop(conn);
 
         Arc::new(pool)
     }
 }
 
-fn initialize_database(conn: &Connection) -> Result<()> {
+/// 一个数据库迁移，version 从 1 开始连续编号。已经发布的迁移不能修改，修改表结构时追加新的迁移
+pub struct Migration {
+    pub version: i64,
+    pub description: &'static str,
+    apply: fn(&Connection) -> Result<()>,
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "baseline schema",
+        apply: baseline,
+    },
+    Migration {
+        version: 2,
+        description: "store AuditLogs.username as text without a foreign key",
+        apply: audit_logs_username_text,
+    },
+];
+
+/// 在各自的事务中依次执行还没有执行过的迁移，返回这次执行的迁移
+pub fn migrate(conn: &mut Connection) -> Result<Vec<&'static Migration>> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY,
+            description TEXT NOT NULL,
+            applied_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+        )",
+        params![],
+    )
+    .context("Failed to create schema_version table")?;
+
+    let mut applied = vec![];
+    for migration in MIGRATIONS {
+        // IMMEDIATE 事务：同时启动的多个进程中只有一个会执行同一个迁移
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let done: bool = tx
+            .prepare("SELECT 1 FROM schema_version WHERE version = ?1")?
+            .exists(params![migration.version])?;
+        if done {
+            continue;
+        }
+        (migration.apply)(&tx).with_context(|| {
+            format!("Failed to apply migration {}: {}", migration.version, migration.description)
+        })?;
+        tx.execute(
+            "INSERT INTO schema_version (version, description) VALUES (?1, ?2)",
+            params![migration.version, migration.description],
+        )?;
+        tx.commit()?;
+        applied.push(migration);
+    }
+    Ok(applied)
+}
+
+/// 每个迁移的执行时间，还没有执行的为 None；不会修改数据库
+pub fn migration_status(conn: &Connection) -> Result<Vec<(&'static Migration, Option<String>)>> {
+    let exists: bool = conn
+        .prepare("SELECT name FROM sqlite_master WHERE type='table' AND name='schema_version'")?
+        .exists(params![])?;
+    MIGRATIONS
+        .iter()
+        .map(|migration| {
+            let applied_at = if exists {
+                conn.query_row(
+                    "SELECT CAST(applied_at AS TEXT) FROM schema_version WHERE version = ?1",
+                    params![migration.version],
+                    |row| row.get(0),
+                )
+                .optional()?
+            } else {
+                None
+            };
+            Ok((migration, applied_at))
+        })
+        .collect()
+}
+
+/// 引入迁移之前的表结构。旧数据库中的表可能不存在或缺少后来加入的列，都在这里补上
+fn baseline(conn: &Connection) -> Result<()> {
     // 检查 Users 表是否存在
     let mut stmt = conn
         .prepare("SELECT name FROM sqlite_master WHERE type='table' AND name='Users'")
@@ -51,6 +135,10 @@ fn initialize_database(conn: &Connection) -> Result<()> {
                 username TEXT UNIQUE NOT NULL,
                 password TEXT NOT NULL,
                 role TEXT NOT NULL,
+                root TEXT,
+                totp_secret TEXT,
+                totp_last_step INTEGER,
+                disabled INTEGER NOT NULL DEFAULT 0,
                 created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
             )",
             params![],
@@ -63,44 +151,211 @@ fn initialize_database(conn: &Connection) -> Result<()> {
             params!["admin", hashed_admin_password, "admin"],
         )
         .context("Failed to insert initial data into Users table")?;
+    }
 
-        // 检查 AuditLogs 表是否存在
-        let mut stmt = conn
-            .prepare("SELECT name FROM sqlite_master WHERE type='table' AND name='AuditLogs'")
-            .context("Failed to prepare statement to check for AuditLogs table")?;
+    // 旧版本只在创建 Users 时创建 AuditLogs，Users 已经存在的数据库可能没有这张表
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS AuditLogs (
+            log_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            username INTEGER NOT NULL references Users(username),
+            action TEXT NOT NULL,
+            target TEXT NOT NULL,
+            result TEXT NOT NULL DEFAULT 'Ok',
+            session_id TEXT,
+            client_addr TEXT,
+            secondary_target TEXT,
+            bytes INTEGER,
+            duration_us INTEGER,
+            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+            prev_hash TEXT,
+            entry_hash TEXT
+        )",
+        params![],
+    )
+    .context("Failed to create AuditLogs table")?;
 
-        let audit_logs_table_exists: bool = stmt.exists(params![])?;
+    // 旧版本创建的 AuditLogs 没有 result 列
+    add_column_if_missing(conn, "AuditLogs", "result", "TEXT NOT NULL DEFAULT 'Ok'")?;
+    add_column_if_missing(conn, "AuditLogs", "client_addr", "TEXT")?;
+    // 会话 id、第二个路径（例如重命名的目标）、读写的字节数和操作耗时（微秒）
+    add_column_if_missing(conn, "AuditLogs", "session_id", "TEXT")?;
+    add_column_if_missing(conn, "AuditLogs", "secondary_target", "TEXT")?;
+    add_column_if_missing(conn, "AuditLogs", "bytes", "INTEGER")?;
+    add_column_if_missing(conn, "AuditLogs", "duration_us", "INTEGER")?;
+    // 哈希链：prev_hash 为前一条记录的 entry_hash，旧记录为空
+    add_column_if_missing(conn, "AuditLogs", "prev_hash", "TEXT")?;
+    add_column_if_missing(conn, "AuditLogs", "entry_hash", "TEXT")?;
+    // 旧版本创建的 Users 没有 root 列，为空时使用 VIRTUAL_ROOT_PATH
+    add_column_if_missing(conn, "Users", "root", "TEXT")?;
+    // 两步验证：base32 编码的 TOTP 密钥，以及最近一次使用的时间步（防止验证码重放）
+    add_column_if_missing(conn, "Users", "totp_secret", "TEXT")?;
+    add_column_if_missing(conn, "Users", "totp_last_step", "INTEGER")?;
+    // 被停用的用户不能登录
+    add_column_if_missing(conn, "Users", "disabled", "INTEGER NOT NULL DEFAULT 0")?;
 
-        if !audit_logs_table_exists {
-            conn.execute(
-            "CREATE TABLE AuditLogs (
-                log_id INTEGER PRIMARY KEY AUTOINCREMENT,
-                username INTEGER NOT NULL references Users(username),
-                action TEXT NOT NULL,
-                target TEXT NOT NULL,
-                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
-            )",
-            // CHECK(action IN ('Open', 'Close', 'Read', 'Write', 'Remove', 'OpenDir', 'ReadDir', 'MakeDir', 'RemoveDir', 'RealPath', 'Rename'))
+    // 访问控制规则：username 和 role 只有一个非空，path 为虚拟路径前缀
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS AccessRules (
+            rule_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            username TEXT,
+            role TEXT,
+            path TEXT NOT NULL,
+            rights TEXT NOT NULL,
+            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+        )",
+        params![],
+    )
+    .context("Failed to create AccessRules table")?;
+
+    // 配额：username 和 role 只有一个非空，上限为 NULL 表示不限制
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS Quotas (
+            quota_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            username TEXT,
+            role TEXT,
+            max_bytes INTEGER,
+            max_files INTEGER
+        )",
+        params![],
+    )
+    .context("Failed to create Quotas table")?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS QuotaUsage (
+            username TEXT PRIMARY KEY,
+            bytes INTEGER NOT NULL DEFAULT 0,
+            files INTEGER NOT NULL DEFAULT 0,
+            updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+        )",
+        params![],
+    )
+    .context("Failed to create QuotaUsage table")?;
+
+    // 用户的公钥：public_key 为 base64 编码的公钥，expires_at 为 NULL 表示永不过期
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS AuthorizedKeys (
+            key_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            username TEXT NOT NULL,
+            algorithm TEXT NOT NULL,
+            public_key TEXT NOT NULL,
+            fingerprint TEXT NOT NULL,
+            comment TEXT,
+            expires_at TIMESTAMP,
+            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+            UNIQUE (username, public_key)
+        )",
+        params![],
+    )
+    .context("Failed to create AuthorizedKeys table")?;
+
+    // 两步验证的恢复码，只保存哈希，used_at 非空表示已经用过
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS RecoveryCodes (
+            code_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            username TEXT NOT NULL,
+            code_hash TEXT NOT NULL,
+            used_at TIMESTAMP,
+            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+        )",
+        params![],
+    )
+    .context("Failed to create RecoveryCodes table")?;
+
+    // 每次登录尝试，用于统计失败次数
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS LoginAttempts (
+            attempt_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            username TEXT NOT NULL,
+            client_addr TEXT,
+            method TEXT NOT NULL,
+            success INTEGER NOT NULL,
+            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+        )",
+        params![],
+    )
+    .context("Failed to create LoginAttempts table")?;
+    // 账号（kind = 'user'）或来源地址（kind = 'addr'）的封禁，level 为连续被封禁的次数
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS Bans (
+            ban_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            kind TEXT NOT NULL,
+            subject TEXT NOT NULL,
+            level INTEGER NOT NULL DEFAULT 0,
+            banned_until TIMESTAMP NOT NULL,
+            last_attempt_id INTEGER NOT NULL DEFAULT 0,
+            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+            UNIQUE (kind, subject)
+        )",
+        params![],
+    )
+    .context("Failed to create Bans table")?;
+
+    Ok(())
+}
+
+/// AuditLogs.username 原来声明为 INTEGER 并引用 Users(username)：数字用户名被存成整数，
+/// 删除有审计记录的用户也会失败。SQLite 不能修改列的类型，只能重建这张表
+fn audit_logs_username_text(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE AuditLogs_new (
+            log_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            username TEXT NOT NULL,
+            action TEXT NOT NULL,
+            target TEXT NOT NULL,
+            result TEXT NOT NULL DEFAULT 'Ok',
+            session_id TEXT,
+            client_addr TEXT,
+            secondary_target TEXT,
+            bytes INTEGER,
+            duration_us INTEGER,
+            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+            prev_hash TEXT,
+            entry_hash TEXT
+        )",
+        params![],
+    )?;
+    conn.execute(
+        "INSERT INTO AuditLogs_new (log_id, username, action, target, result, session_id, client_addr,
+                secondary_target, bytes, duration_us, created_at, prev_hash, entry_hash)
+            SELECT log_id, CAST(username AS TEXT), action, target, result, session_id, client_addr,
+                secondary_target, bytes, duration_us, created_at, prev_hash, entry_hash
+            FROM AuditLogs",
+        params![],
+    )?;
+    conn.execute("DROP TABLE AuditLogs", params![])?;
+    conn.execute("ALTER TABLE AuditLogs_new RENAME TO AuditLogs", params![])?;
+    Ok(())
+}
+
+/// 为已存在的表补充缺少的列，表不存在时什么也不做
+fn add_column_if_missing(conn: &Connection, table: &str, column: &str, definition: &str) -> Result<()> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let columns = stmt
+        .query_map(params![], |row| row.get::<_, String>(1))?
+        .collect::<Result<Vec<_>, _>>()?;
+    if !columns.is_empty() && !columns.iter().any(|name| name == column) {
+        conn.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
             params![],
-            )
-            .context("Failed to create AuditLogs table")?;
-        }
+        )
+        .with_context(|| format!("Failed to add column {} to {}", column, table))?;
     }
-
     Ok(())
 }
 
+/// 从环境变量获取数据库路径
+pub fn database_path() -> String {
+    env::var("DATABASE_PATH").unwrap_or("my_database.db".to_string())
+}
+
 lazy_static! {
     static ref GLOBAL_DB_POOL: Arc<Pool<SqliteConnectionManager>> = {
-        // 从环境变量获取数据库路径
-        let db_path = env::var("DATABASE_PATH").unwrap_or("my_database.db".to_string());
-
         // 创建连接池管理器
-        let manager = SqliteConnectionManager::file(db_path.clone());
+        let manager = SqliteConnectionManager::file(database_path());
         let pool = Pool::new(manager).expect("Failed to create database connection pool");
 
-        let conn = Connection::open(db_path).expect("Failed to open database connection");
-        initialize_database(&conn).expect("Failed to initialize database");
+        let mut conn = pool.get().expect("Failed to get connection from pool");
+        migrate(&mut conn).expect("Failed to migrate database");
+        drop(conn);
 
         Arc::new(pool)
     };
@@ -111,16 +366,120 @@ mod tests {
     use super::*;
 
     #[test]
-    fn test_initialize_database() {
+    fn test_mock_pool_shares_one_database() {
+        let pool = MockDatabasePool::get_pool();
+        let first = pool.get().unwrap();
+        let second = pool.get().unwrap();
+        first
+            .execute("INSERT INTO QuotaUsage (username, bytes, files) VALUES ('admin', 1, 1)", params![])
+            .unwrap();
+        let count: i64 = second
+            .query_row("SELECT COUNT(*) FROM QuotaUsage", params![], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 1);
+
+        // 不同的池互不影响
+        let other = MockDatabasePool::get_pool();
+        let count: i64 = other
+            .get()
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM QuotaUsage", params![], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 0);
+    }
+
+    #[test]
+    fn test_migrate() {
+        let manager = SqliteConnectionManager::memory();
+        let pool = Pool::new(manager).expect("Failed to create database connection pool");
+        let mut conn = pool.get().expect("Failed to get connection from pool");
+
+        assert!(migration_status(&conn).unwrap().iter().all(|(_, applied_at)| applied_at.is_none()));
+        let applied = migrate(&mut conn).expect("Failed to migrate database");
+        assert_eq!(applied.len(), MIGRATIONS.len());
+        assert!(migration_status(&conn).unwrap().iter().all(|(_, applied_at)| applied_at.is_some()));
+        // 已经执行过的迁移不会再执行
+        assert!(migrate(&mut conn).unwrap().is_empty());
+        let versions: i64 = conn
+            .query_row("SELECT COUNT(*) FROM schema_version", params![], |row| row.get(0))
+            .unwrap();
+        assert_eq!(versions, MIGRATIONS.len() as i64);
+    }
+
+    #[test]
+    fn test_migrate_audit_logs_username() {
+        let manager = SqliteConnectionManager::memory();
+        let pool = Pool::new(manager).expect("Failed to create database connection pool");
+        let mut conn = pool.get().expect("Failed to get connection from pool");
+        // 旧版本只有在创建 Users 时才会创建 AuditLogs
+        conn.execute(
+            "CREATE TABLE Users (
+                user_id INTEGER PRIMARY KEY AUTOINCREMENT,
+                username TEXT UNIQUE NOT NULL,
+                password TEXT NOT NULL,
+                role TEXT NOT NULL,
+                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+            )",
+            params![],
+        )
+        .unwrap();
+        conn.execute("INSERT INTO Users (username, password, role) VALUES ('42', '', 'user')", params![])
+            .unwrap();
+        migrate(&mut conn).expect("Failed to migrate database");
+
+        conn.execute(
+            "INSERT INTO AuditLogs (username, action, target) VALUES ('42', 'Read', 'file.txt')",
+            params![],
+        )
+        .unwrap();
+        let username_type: String = conn
+            .query_row("SELECT typeof(username) FROM AuditLogs", params![], |row| row.get(0))
+            .unwrap();
+        assert_eq!(username_type, "text");
+        // 审计记录不再引用 Users，删除用户时保留
+        conn.execute("DELETE FROM Users WHERE username = '42'", params![]).unwrap();
+        conn.execute(
+            "INSERT INTO AuditLogs (username, action, target) VALUES ('nobody', 'Login', 'password')",
+            params![],
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_upgrade_audit_logs_result_column() {
         let manager = SqliteConnectionManager::memory();
         let pool = Pool::new(manager).expect("Failed to create database connection pool");
+        let mut conn = pool.get().expect("Failed to get connection from pool");
+        conn.execute(
+            "CREATE TABLE AuditLogs (
+                log_id INTEGER PRIMARY KEY AUTOINCREMENT,
+                username TEXT NOT NULL,
+                action TEXT NOT NULL,
+                target TEXT NOT NULL,
+                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+            )",
+            params![],
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO AuditLogs (username, action, target) VALUES ('admin', 'Read', 'file.txt')",
+            params![],
+        )
+        .unwrap();
+
+        migrate(&mut conn).expect("Failed to migrate database");
 
-        let conn = pool.get().expect("Failed to get connection from pool");
-        initialize_database(&conn).expect("Failed to initialize database");
+        let result: String = conn
+            .query_row("SELECT result FROM AuditLogs", params![], |row| row.get(0))
+            .unwrap();
+        assert_eq!(result, "Ok");
     }
 
     #[test]
     fn test_get_pool() {
+        // 全局连接池只在第一次使用时打开数据库，测试中不能写到工作目录
+        let dir = tempfile::tempdir().unwrap();
+        env::set_var("DATABASE_PATH", dir.path().join("test.db"));
         let pool = GlobalDatabasePool::get_pool();
         assert!(pool.get().is_ok());
     }
@@ -130,4 +489,31 @@ mod tests {
         let pool = MockDatabasePool::get_pool();
         assert!(pool.get().is_ok());
     }
+
+    #[test]
+    fn test_upgrade_users_root_column() {
+        let manager = SqliteConnectionManager::memory();
+        let pool = Pool::new(manager).expect("Failed to create database connection pool");
+        let mut conn = pool.get().expect("Failed to get connection from pool");
+        conn.execute(
+            "CREATE TABLE Users (
+                user_id INTEGER PRIMARY KEY AUTOINCREMENT,
+                username TEXT UNIQUE NOT NULL,
+                password TEXT NOT NULL,
+                role TEXT NOT NULL,
+                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+            )",
+            params![],
+        )
+        .unwrap();
+
+        migrate(&mut conn).expect("Failed to migrate database");
+
+        conn.execute(
+            "INSERT INTO Users (username, password, role, root, totp_secret, totp_last_step, disabled)
+                VALUES ('test', '', 'user', '/srv/test', NULL, NULL, 1)",
+            params![],
+        )
+        .unwrap();
+    }
 }
@@ -0,0 +1,161 @@
+//! OpenSSH 的 SFTP 协议扩展（PROTOCOL 文件第 4 节）
+use std::collections::HashMap;
+
+use bytes::Bytes;
+use russh_sftp::protocol::{ExtendedReply, FileAttributes, Packet, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+pub use russh_sftp::extensions::{
+    FsyncExtension, HardlinkExtension, LimitsExtension, StatvfsExtension, FSYNC, HARDLINK, LIMITS,
+    STATVFS,
+};
+
+pub const POSIX_RENAME: &str = "posix-rename@openssh.com";
+pub const FSTATVFS: &str = "fstatvfs@openssh.com";
+pub const LSETSTAT: &str = "lsetstat@openssh.com";
+// draft-ietf-secsh-filexfer-extensions-00
+pub const COPY_DATA: &str = "copy-data";
+pub const CHECK_FILE_NAME: &str = "check-file-name";
+pub const CHECK_FILE_HANDLE: &str = "check-file-handle";
+/// check-file 应答中的扩展名
+pub const CHECK_FILE: &str = "check-file";
+
+/// 单个 SFTP 包的最大长度，与 OpenSSH 的 SFTP_MAX_MSG_LENGTH 一致
+pub const MAX_PACKET_LEN: u64 = 256 * 1024;
+/// 单次读写的最大数据长度，为包头留出余量
+pub const MAX_READ_LEN: u64 = MAX_PACKET_LEN - 1024;
+pub const MAX_WRITE_LEN: u64 = MAX_PACKET_LEN - 1024;
+/// check-file 应答中拼接的哈希的最大总长度
+pub const MAX_CHECK_FILE_HASH_LEN: u64 = MAX_PACKET_LEN - 1024;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PosixRenameExtension {
+    pub oldpath: String,
+    pub newpath: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FstatvfsExtension {
+    pub handle: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LsetstatExtension {
+    pub path: String,
+    pub attrs: FileAttributes,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CopyDataExtension {
+    pub read_from_handle: String,
+    pub read_from_offset: u64,
+    /// 为 0 时一直复制到源文件末尾
+    pub read_data_length: u64,
+    pub write_to_handle: String,
+    pub write_to_offset: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CheckFileHandleExtension {
+    pub handle: String,
+    /// 逗号分隔的候选算法，服务器选用第一个支持的
+    pub hash_algorithms: String,
+    pub start_offset: u64,
+    /// 为 0 时一直计算到文件末尾
+    pub length: u64,
+    /// 为 0 时整个范围只计算一个哈希
+    pub block_size: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CheckFileNameExtension {
+    pub filename: String,
+    pub hash_algorithms: String,
+    pub start_offset: u64,
+    pub length: u64,
+    pub block_size: u32,
+}
+
+/// check-file 的应答：扩展名、选用的算法以及依次拼接的哈希值（不带长度前缀）
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CheckFileReply {
+    pub extension: String,
+    pub hash_algorithm: String,
+    #[serde(serialize_with = "russh_sftp::ser::data_serialize")]
+    #[serde(deserialize_with = "russh_sftp::de::data_deserialize")]
+    pub hash: Vec<u8>,
+}
+
+/// 版本协商时向客户端公布的扩展及其版本号
+pub fn advertised() -> HashMap<String, String> {
+    [
+        (POSIX_RENAME, "1"),
+        (STATVFS, "2"),
+        (FSTATVFS, "2"),
+        (HARDLINK, "1"),
+        (FSYNC, "1"),
+        (LIMITS, "1"),
+        (LSETSTAT, "1"),
+        (COPY_DATA, "1"),
+        (CHECK_FILE_NAME, "1"),
+        (CHECK_FILE_HANDLE, "1"),
+    ]
+    .into_iter()
+    .map(|(name, version)| (name.to_string(), version.to_string()))
+    .collect()
+}
+
+/// 解析 SSH_FXP_EXTENDED 请求中的扩展数据
+pub fn parse_request<T: DeserializeOwned>(data: Vec<u8>) -> Result<T, StatusCode> {
+    russh_sftp::de::from_bytes(&mut Bytes::from(data)).map_err(|_| StatusCode::BadMessage)
+}
+
+/// 将扩展的应答数据包装为 SSH_FXP_EXTENDED_REPLY
+pub fn reply<T: Serialize>(id: u32, data: &T) -> Result<Packet, StatusCode> {
+    let data = russh_sftp::ser::to_bytes(data).map_err(|_| StatusCode::Failure)?;
+    Ok(Packet::ExtendedReply(ExtendedReply {
+        id,
+        data: data.to_vec(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_request() {
+        let data = russh_sftp::ser::to_bytes(&PosixRenameExtension {
+            oldpath: "/a".to_string(),
+            newpath: "/b".to_string(),
+        })
+        .unwrap()
+        .to_vec();
+        let request: PosixRenameExtension = parse_request(data).unwrap();
+        assert_eq!(request.oldpath, "/a");
+        assert_eq!(request.newpath, "/b");
+
+        let err = parse_request::<PosixRenameExtension>(vec![0, 0, 0, 9, b'/']).unwrap_err();
+        assert_eq!(err, StatusCode::BadMessage);
+    }
+
+    #[test]
+    fn test_advertised() {
+        let extensions = advertised();
+        for name in [
+            POSIX_RENAME,
+            STATVFS,
+            FSTATVFS,
+            HARDLINK,
+            FSYNC,
+            LIMITS,
+            LSETSTAT,
+            COPY_DATA,
+            CHECK_FILE_NAME,
+            CHECK_FILE_HANDLE,
+        ] {
+            assert!(extensions.contains_key(name), "{} not advertised", name);
+        }
+    }
+}
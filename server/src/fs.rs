@@ -1,13 +1,58 @@
+//! 虚拟根目录下的路径解析和文件操作
+//!
+//! VirtualRoot 按路径字符串检查是否位于虚拟根目录之下，处理函数随后再按这个路径访问文件，
+//! 两步之间其他用户可以把某个目录换成指向根目录之外的符号链接。open、opendir 和 remove
+//! 以 O_NOFOLLOW 打开最后一个分量（remove 打开的是所在目录），它被换成符号链接时操作失败
+//! （ELOOP 或 ENOTDIR）。目录句柄经由 /proc/self/fd 遍历已打开的目录。
+//! 中间目录被替换的竞争仍然存在，完全避免需要用 openat 逐级打开每个分量。
 use chrono::{DateTime, Local};
+use russh_sftp::extensions::Statvfs;
+use russh_sftp::protocol::FileAttributes;
+use std::collections::VecDeque;
+use std::ffi::{CString, OsString};
 use std::io;
-use std::os::unix::fs::MetadataExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileExt, MetadataExt, OpenOptionsExt};
 use std::os::unix::fs::PermissionsExt;
-use std::path::{Path, PathBuf};
-use std::time::SystemTime;
+use std::os::unix::io::AsRawFd;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::fs;
 use users::{get_group_by_gid, get_user_by_uid};
 
 
+/// 跟随符号链接的最大次数，与 Linux 的 MAXSYMLINKS 保持一致
+const MAX_SYMLINK_FOLLOWS: usize = 40;
+
+/// 客户端可以设置的权限位，不允许设置 setuid、setgid 和 sticky
+const PERMISSION_MASK: u32 = 0o777;
+
+// statvfs@openssh.com 应答中的挂载标志
+const SSH_FXE_STATVFS_ST_RDONLY: u64 = 0x1;
+const SSH_FXE_STATVFS_ST_NOSUID: u64 = 0x2;
+
+fn outside_root_error() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        "Path is outside the virtual root.",
+    )
+}
+
+/// 对虚拟路径做词法规范化：去掉 `.`，`..` 在根目录处停止（与 chroot 行为一致）
+pub fn normalize_virtual_path(virtual_path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::from("/");
+    for component in virtual_path.components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+    normalized
+}
+
 pub struct VirtualRoot {
     virtual_root: PathBuf,
 }
@@ -20,8 +65,9 @@ impl VirtualRoot {
                 "Virtual root directory does not exist or is not a directory.",
             ));
         }
+        // 根目录本身可能经过符号链接，先取得其规范路径，后续的包含检查都基于它
         Ok(Self {
-            virtual_root: virtual_root.to_path_buf(),
+            virtual_root: virtual_root.canonicalize()?,
         })
     }
 
@@ -32,36 +78,133 @@ impl VirtualRoot {
     pub fn to_virtual_path(&self, real_path: &Path) -> io::Result<PathBuf> {
         let relative_path = real_path
             .strip_prefix(&self.virtual_root)
-            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+            .map_err(io::Error::other)?;
         Ok(PathBuf::from("/").join(relative_path))
     }
 
+    /// 将虚拟路径转换为真实路径，并解析路径中（包括最后一个分量）的符号链接
     pub fn to_real_path(&self, virtual_path: &Path) -> io::Result<PathBuf> {
-        let binding = PathBuf::from(virtual_path);
-        let delta = binding.strip_prefix("/");
-        if delta.is_err() {
+        self.resolve(virtual_path, true)
+    }
+
+    /// 与 to_real_path 相同，但不跟随最后一个分量的符号链接（用于 lstat、remove 等）
+    pub fn to_real_path_nofollow(&self, virtual_path: &Path) -> io::Result<PathBuf> {
+        self.resolve(virtual_path, false)
+    }
+
+    /// 逐个分量地解析路径，类似 openat 的方式：
+    /// 遇到符号链接时读取其目标并继续解析，任何离开虚拟根目录的链接都会被拒绝。
+    fn resolve(&self, virtual_path: &Path, follow_final: bool) -> io::Result<PathBuf> {
+        if !virtual_path.has_root() {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
                 "Path does not start with a slash.",
             ));
         }
-        let delta = delta.unwrap();
-        let real_path = self.virtual_root.join(delta);
-        if !real_path.starts_with(&self.virtual_root) {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "Path is outside the virtual root.",
-            ));
+        let mut pending: VecDeque<OsString> = normalize_virtual_path(virtual_path)
+            .components()
+            .filter_map(|component| match component {
+                Component::Normal(name) => Some(name.to_os_string()),
+                _ => None,
+            })
+            .collect();
+        let mut resolved = self.virtual_root.clone();
+        let mut depth = 0usize;
+        let mut follows = 0usize;
+
+        while let Some(name) = pending.pop_front() {
+            if name == ".." {
+                if depth == 0 {
+                    return Err(outside_root_error());
+                }
+                resolved.pop();
+                depth -= 1;
+                continue;
+            }
+            if name == "." {
+                continue;
+            }
+            let candidate = resolved.join(&name);
+            let is_final = pending.is_empty();
+            let is_symlink = fs::symlink_metadata(&candidate)
+                .map(|metadata| metadata.file_type().is_symlink())
+                .unwrap_or(false);
+            if !is_symlink || (is_final && !follow_final) {
+                resolved = candidate;
+                depth += 1;
+                continue;
+            }
+
+            follows += 1;
+            if follows > MAX_SYMLINK_FOLLOWS {
+                return Err(io::Error::from_raw_os_error(libc::ELOOP));
+            }
+            let target = fs::read_link(&candidate)?;
+            let target = if target.is_absolute() {
+                // 绝对链接目标是真实路径，必须位于虚拟根目录之下
+                let relative = target
+                    .strip_prefix(&self.virtual_root)
+                    .map_err(|_| outside_root_error())?
+                    .to_path_buf();
+                resolved = self.virtual_root.clone();
+                depth = 0;
+                relative
+            } else {
+                target
+            };
+            for component in target.components().rev() {
+                match component {
+                    Component::Normal(name) => pending.push_front(name.to_os_string()),
+                    Component::ParentDir => pending.push_front(OsString::from("..")),
+                    _ => {}
+                }
+            }
+        }
+
+        self.verify_real_path(&resolved)?;
+        Ok(resolved)
+    }
+
+    /// 将客户端给出的链接目标转换为写入磁盘的目标
+    ///
+    /// 绝对目标视为虚拟路径并转换为真实路径；相对目标保持原样，
+    /// 但相对于链接所在目录解析后不能离开虚拟根目录。
+    pub fn to_link_target(&self, link_virtual_path: &Path, target: &Path) -> io::Result<PathBuf> {
+        if target.is_absolute() {
+            return self.to_real_path(target);
+        }
+        // 链接所在目录可能本身经过了符号链接，以其真实位置为准
+        let parent = link_virtual_path.parent().unwrap_or(Path::new("/"));
+        let parent = self.to_virtual_path(&self.to_real_path(parent)?)?;
+        let mut depth = 0usize;
+        for component in parent.join(target).components() {
+            match component {
+                Component::Normal(_) => depth += 1,
+                Component::ParentDir => {
+                    if depth == 0 {
+                        return Err(outside_root_error());
+                    }
+                    depth -= 1;
+                }
+                _ => {}
+            }
         }
-        Ok(real_path)
+        Ok(target.to_path_buf())
+    }
+
+    /// 将磁盘上的链接目标转换为客户端可见的目标
+    pub fn to_virtual_link_target(&self, target: &Path) -> io::Result<PathBuf> {
+        if target.is_absolute() {
+            return self
+                .to_virtual_path(target)
+                .map_err(|_| outside_root_error());
+        }
+        Ok(target.to_path_buf())
     }
 
     pub fn verify_real_path(&self, real_path: &Path) -> io::Result<()> {
         if !real_path.starts_with(&self.virtual_root) {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "Path is outside the virtual root.",
-            ));
+            return Err(outside_root_error());
         }
         Ok(())
     }
@@ -70,10 +213,8 @@ impl Default for VirtualRoot {
     fn default() -> Self {
         let virtual_root_str = std::env::var("VIRTUAL_ROOT_PATH").unwrap_or_else(|_| ".".to_string());
         let virtual_root = PathBuf::from(virtual_root_str);
-        if !virtual_root.exists() || !virtual_root.is_dir() {
-            panic!("Virtual root directory does not exist or is not a directory.");
-        }
-        Self { virtual_root }
+        Self::new(&virtual_root)
+            .expect("Virtual root directory does not exist or is not a directory.")
     }
 }
 
@@ -138,24 +279,280 @@ pub fn get_file_file_attributes(
     // 构造 FileAttributes
     let mut file_attr = russh_sftp::protocol::FileAttributes::from(&metadata);
 
-    let user = get_user_by_uid(metadata.uid()).unwrap();
-    let group = get_group_by_gid(metadata.gid()).unwrap();
-    file_attr.user = Some(user.name().to_str().unwrap().to_string());
-    file_attr.group = Some(group.name().to_str().unwrap().to_string());
+    // 没有对应用户/组的 uid、gid 只返回数字
+    file_attr.user = get_user_by_uid(metadata.uid())
+        .map(|user| user.name().to_string_lossy().to_string());
+    file_attr.group = get_group_by_gid(metadata.gid())
+        .map(|group| group.name().to_string_lossy().to_string());
 
     Ok(file_attr)
 }
 
+fn to_system_time(secs: Option<u32>) -> Option<SystemTime> {
+    secs.map(|secs| UNIX_EPOCH + Duration::from_secs(secs as u64))
+}
+
+fn to_timespec(secs: Option<u32>) -> libc::timespec {
+    match secs {
+        Some(secs) => libc::timespec {
+            tv_sec: secs as libc::time_t,
+            tv_nsec: 0,
+        },
+        None => libc::timespec {
+            tv_sec: 0,
+            tv_nsec: libc::UTIME_OMIT,
+        },
+    }
+}
+
+fn to_c_path(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+}
+
+/// 通过 utimensat 设置访问/修改时间，未给出的时间保持不变
+fn set_path_times(
+    path: &Path,
+    atime: Option<u32>,
+    mtime: Option<u32>,
+    flags: libc::c_int,
+) -> io::Result<()> {
+    let c_path = to_c_path(path)?;
+    let times = [to_timespec(atime), to_timespec(mtime)];
+    let ret = unsafe { libc::utimensat(libc::AT_FDCWD, c_path.as_ptr(), times.as_ptr(), flags) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// 计算需要修改的属主，与文件当前属主相同的 uid/gid 不再传给 chown。
+/// 普通用户运行时由内核决定是否允许（EPERM 映射为 PermissionDenied）；
+/// 以 root 运行时内核不会拒绝，任何用户都能把文件交给其他用户，因此直接拒绝。
+fn owner_change(
+    metadata: &fs::Metadata,
+    attrs: &FileAttributes,
+) -> io::Result<Option<(Option<u32>, Option<u32>)>> {
+    let uid = attrs.uid.filter(|&uid| uid != metadata.uid());
+    let gid = attrs.gid.filter(|&gid| gid != metadata.gid());
+    if uid.is_none() && gid.is_none() {
+        return Ok(None);
+    }
+    if unsafe { libc::geteuid() } == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "Changing the owner of a file is not allowed when the server runs as root",
+        ));
+    }
+    Ok(Some((uid, gid)))
+}
+
+/// 将 SFTP 的 FileAttributes（大小、权限、时间、属主）应用到路径上
+pub fn set_path_attributes(path: &Path, attrs: &FileAttributes) -> io::Result<()> {
+    // 先修改属主，被拒绝时其他属性保持不变
+    if let Some((uid, gid)) = owner_change(&fs::metadata(path)?, attrs)? {
+        std::os::unix::fs::chown(path, uid, gid)?;
+    }
+    if let Some(size) = attrs.size {
+        fs::OpenOptions::new().write(true).open(path)?.set_len(size)?;
+    }
+    if let Some(permissions) = attrs.permissions {
+        fs::set_permissions(path, fs::Permissions::from_mode(permissions & PERMISSION_MASK))?;
+    }
+    if attrs.atime.is_some() || attrs.mtime.is_some() {
+        set_path_times(path, attrs.atime, attrs.mtime, 0)?;
+    }
+    Ok(())
+}
+
+/// lsetstat：与 set_path_attributes 相同，但路径是符号链接时作用于链接本身
+pub fn set_symlink_attributes(path: &Path, attrs: &FileAttributes) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(path)?;
+    if !metadata.file_type().is_symlink() {
+        return set_path_attributes(path, attrs);
+    }
+    // Linux 上符号链接没有自己的大小和权限
+    if attrs.size.is_some() || attrs.permissions.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Cannot change size or permissions of a symbolic link",
+        ));
+    }
+    // 先修改属主，被拒绝时其他属性保持不变
+    if let Some((uid, gid)) = owner_change(&metadata, attrs)? {
+        std::os::unix::fs::lchown(path, uid, gid)?;
+    }
+    if attrs.atime.is_some() || attrs.mtime.is_some() {
+        set_path_times(path, attrs.atime, attrs.mtime, libc::AT_SYMLINK_NOFOLLOW)?;
+    }
+    Ok(())
+}
+
+/// 与 set_path_attributes 相同，但直接作用于已打开的文件
+pub fn set_file_attributes(file: &fs::File, attrs: &FileAttributes) -> io::Result<()> {
+    // 先修改属主，被拒绝时其他属性保持不变
+    if let Some((uid, gid)) = owner_change(&file.metadata()?, attrs)? {
+        std::os::unix::fs::fchown(file, uid, gid)?;
+    }
+    if let Some(size) = attrs.size {
+        file.set_len(size)?;
+    }
+    if let Some(permissions) = attrs.permissions {
+        file.set_permissions(fs::Permissions::from_mode(permissions & PERMISSION_MASK))?;
+    }
+    if attrs.atime.is_some() || attrs.mtime.is_some() {
+        let mut times = fs::FileTimes::new();
+        if let Some(atime) = to_system_time(attrs.atime) {
+            times = times.set_accessed(atime);
+        }
+        if let Some(mtime) = to_system_time(attrs.mtime) {
+            times = times.set_modified(mtime);
+        }
+        file.set_times(times)?;
+    }
+    Ok(())
+}
+
+/// copy-data：在两个已打开的文件之间复制数据，length 为 0 时一直复制到源文件末尾。
+/// 返回实际复制的字节数。
+pub fn copy_range(
+    src: &fs::File,
+    src_offset: u64,
+    length: u64,
+    dst: &fs::File,
+    dst_offset: u64,
+) -> io::Result<u64> {
+    let end = match length {
+        0 => u64::MAX,
+        length => src_offset.saturating_add(length),
+    };
+    let mut buf = vec![0; 64 * 1024];
+    let mut copied = 0;
+    while src_offset + copied < end {
+        let want = (end - src_offset - copied).min(buf.len() as u64) as usize;
+        let bytes_read = src.read_at(&mut buf[..want], src_offset + copied)?;
+        if bytes_read == 0 {
+            break;
+        }
+        dst.write_all_at(&buf[..bytes_read], dst_offset + copied)?;
+        copied += bytes_read as u64;
+    }
+    Ok(copied)
+}
+
+/// 以 O_NOFOLLOW 打开目录，目录本身是符号链接时返回 ENOTDIR
+fn open_dir_nofollow(path: &Path) -> io::Result<fs::File> {
+    fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECTORY | libc::O_NOFOLLOW)
+        .open(path)
+}
+
+/// 已打开目录在 /proc 中的路径，经由它访问的始终是打开时的那个目录
+fn fd_path(dir: &fs::File) -> PathBuf {
+    PathBuf::from(format!("/proc/self/fd/{}", dir.as_raw_fd()))
+}
+
+/// 以 O_NOFOLLOW 打开文件，最后一个分量是符号链接时返回 ELOOP
+pub fn open_nofollow(options: &mut fs::OpenOptions, path: &Path) -> io::Result<fs::File> {
+    options.custom_flags(libc::O_NOFOLLOW).open(path)
+}
+
+/// 以 O_NOFOLLOW 打开目录并遍历它。返回的目录文件必须与遍历器一起保留，
+/// 遍历器经由它的文件描述符读取目录项
+pub fn read_dir_nofollow(path: &Path) -> io::Result<(fs::File, fs::ReadDir)> {
+    let dir = open_dir_nofollow(path)?;
+    let entries = fs::read_dir(fd_path(&dir))?;
+    Ok((dir, entries))
+}
+
+/// 删除文件：以 O_NOFOLLOW 打开所在目录后用 unlinkat 删除，返回被删除条目的属性
+pub fn remove_file_nofollow(path: &Path) -> io::Result<fs::Metadata> {
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Cannot remove the root directory"));
+    };
+    let dir = open_dir_nofollow(parent)?;
+    let metadata = fs::symlink_metadata(fd_path(&dir).join(name))?;
+    let c_name = to_c_path(Path::new(name))?;
+    if unsafe { libc::unlinkat(dir.as_raw_fd(), c_name.as_ptr(), 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(metadata)
+}
+
+/// rename(2) 的不覆盖版本（renameat2 的 RENAME_NOREPLACE）：目标已存在时返回 AlreadyExists
+pub fn rename_noreplace(old_path: &Path, new_path: &Path) -> io::Result<()> {
+    let old_path = to_c_path(old_path)?;
+    let new_path = to_c_path(new_path)?;
+    let ret = unsafe {
+        libc::renameat2(
+            libc::AT_FDCWD,
+            old_path.as_ptr(),
+            libc::AT_FDCWD,
+            new_path.as_ptr(),
+            libc::RENAME_NOREPLACE,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// libc::statvfs 各字段的类型随平台不同，统一转换为 u64
+#[allow(clippy::unnecessary_cast)]
+fn to_statvfs(stat: &libc::statvfs) -> Statvfs {
+    let mut flags = 0;
+    if stat.f_flag & libc::ST_RDONLY != 0 {
+        flags |= SSH_FXE_STATVFS_ST_RDONLY;
+    }
+    if stat.f_flag & libc::ST_NOSUID != 0 {
+        flags |= SSH_FXE_STATVFS_ST_NOSUID;
+    }
+    Statvfs {
+        block_size: stat.f_bsize as u64,
+        fragment_size: stat.f_frsize as u64,
+        blocks: stat.f_blocks as u64,
+        blocks_free: stat.f_bfree as u64,
+        blocks_avail: stat.f_bavail as u64,
+        inodes: stat.f_files as u64,
+        inodes_free: stat.f_ffree as u64,
+        inodes_avail: stat.f_favail as u64,
+        fs_id: stat.f_fsid as u64,
+        flags,
+        name_max: stat.f_namemax as u64,
+    }
+}
+
+/// 查询路径所在文件系统的容量信息（statvfs@openssh.com）
+pub fn statvfs(path: &Path) -> io::Result<Statvfs> {
+    let c_path = to_c_path(path)?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(to_statvfs(&stat))
+}
+
+/// 查询已打开文件所在文件系统的容量信息（fstatvfs@openssh.com）
+pub fn fstatvfs(file: &fs::File) -> io::Result<Statvfs> {
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstatvfs(file.as_raw_fd(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(to_statvfs(&stat))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::env;
     use std::fs::File;
     use std::io::Write;
 
     #[test]
     fn test_virtual_root() {
-        let temp_dir = env::current_dir().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let temp_dir = dir.path().canonicalize().unwrap();
         println!("temp_dir: {:?}", temp_dir);
         let virtual_root = VirtualRoot::new(&temp_dir).unwrap();
         println!("root: {:?}", virtual_root.get_root());
@@ -185,4 +582,118 @@ mod tests {
         let real_file_path = virtual_root.to_real_path(Path::new("/file.txt")).unwrap();
         assert_eq!(real_file_path, temp_dir.join("file.txt"));
     }
+
+    #[test]
+    fn test_normalize_virtual_path() {
+        assert_eq!(normalize_virtual_path(Path::new("/")), Path::new("/"));
+        assert_eq!(
+            normalize_virtual_path(Path::new("/../../etc/passwd")),
+            Path::new("/etc/passwd")
+        );
+        assert_eq!(normalize_virtual_path(Path::new("/a/./b/../c")), Path::new("/a/c"));
+        assert_eq!(normalize_virtual_path(Path::new("/a/../../..")), Path::new("/"));
+    }
+
+    /// 构造一个带有各种符号链接的虚拟根目录：
+    /// root/a/b、root/in -> a、root/out -> 外部目录、root/rel_out -> ../outside、root/loop -> loop
+    fn hostile_root() -> (tempfile::TempDir, VirtualRoot, PathBuf) {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let base = temp_dir.path().canonicalize().unwrap();
+        let root = base.join("root");
+        let outside = base.join("outside");
+        fs::create_dir_all(root.join("a/b")).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(outside.join("passwd"), b"secret").unwrap();
+        std::os::unix::fs::symlink("a", root.join("in")).unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("out")).unwrap();
+        std::os::unix::fs::symlink("../outside", root.join("rel_out")).unwrap();
+        std::os::unix::fs::symlink("../../../outside", root.join("a/b/deep_out")).unwrap();
+        std::os::unix::fs::symlink("loop", root.join("loop")).unwrap();
+        let virtual_root = VirtualRoot::new(&root).unwrap();
+        (temp_dir, virtual_root, root)
+    }
+
+    #[test]
+    fn test_virtual_root_rejects_escaping_symlinks() {
+        let (_temp_dir, virtual_root, root) = hostile_root();
+
+        assert_eq!(
+            virtual_root.to_real_path(Path::new("/../../etc/passwd")).unwrap(),
+            root.join("etc/passwd")
+        );
+        assert_eq!(
+            virtual_root.to_real_path(Path::new("/in/b")).unwrap(),
+            root.join("a/b")
+        );
+        for path in ["/out/passwd", "/rel_out/passwd", "/a/b/deep_out", "/out"] {
+            let err = virtual_root.to_real_path(Path::new(path)).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::PermissionDenied, "{}", path);
+        }
+        // 不跟随最后一个分量时，链接本身仍然可以被操作（lstat、remove）
+        assert_eq!(
+            virtual_root.to_real_path_nofollow(Path::new("/out")).unwrap(),
+            root.join("out")
+        );
+        assert!(virtual_root.to_real_path(Path::new("/loop")).is_err());
+        assert!(virtual_root.to_real_path(Path::new("relative")).is_err());
+    }
+
+    #[test]
+    fn test_nofollow_operations_reject_swapped_symlinks() {
+        // 模拟检查之后最后一个分量被换成符号链接：直接对链接路径操作
+        let (_temp_dir, _virtual_root, root) = hostile_root();
+        fs::write(root.join("a/file.txt"), b"data").unwrap();
+        std::os::unix::fs::symlink(root.join("../outside/passwd"), root.join("a/swapped")).unwrap();
+
+        let err = open_nofollow(fs::OpenOptions::new().read(true), &root.join("a/swapped")).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ELOOP));
+        let err = read_dir_nofollow(&root.join("out")).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOTDIR));
+        let err = remove_file_nofollow(&root.join("out/passwd")).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOTDIR));
+        assert!(root.join("../outside/passwd").exists());
+
+        // 没有符号链接时行为与普通的打开、遍历和删除相同
+        assert!(open_nofollow(fs::OpenOptions::new().read(true), &root.join("a/file.txt")).is_ok());
+        let (_dir, entries) = read_dir_nofollow(&root.join("a")).unwrap();
+        let mut names: Vec<_> = entries.map(|entry| entry.unwrap().file_name()).collect();
+        names.sort();
+        assert_eq!(names, ["b", "file.txt", "swapped"]);
+        assert_eq!(remove_file_nofollow(&root.join("a/file.txt")).unwrap().len(), 4);
+        assert!(!root.join("a/file.txt").exists());
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn test_virtual_root_hostile_paths(
+            components in proptest::collection::vec(
+                proptest::sample::select(vec![
+                    "..", ".", "a", "b", "in", "out", "rel_out", "deep_out", "loop", "etc", "passwd", "",
+                ]),
+                0..10,
+            ),
+            follow in proptest::bool::ANY,
+        ) {
+            let (_temp_dir, virtual_root, root) = hostile_root();
+            let virtual_path = format!("/{}", components.join("/"));
+            let result = if follow {
+                virtual_root.to_real_path(Path::new(&virtual_path))
+            } else {
+                virtual_root.to_real_path_nofollow(Path::new(&virtual_path))
+            };
+            if let Ok(real_path) = result {
+                proptest::prop_assert!(real_path.starts_with(&root));
+                // 解析结果的父目录已经不含符号链接，规范化后仍然在根目录内
+                if let Some(parent) = real_path
+                    .parent()
+                    .filter(|parent| real_path != root && parent.exists())
+                {
+                    proptest::prop_assert!(parent.canonicalize().unwrap().starts_with(&root));
+                }
+                if follow && real_path.exists() {
+                    proptest::prop_assert!(real_path.canonicalize().unwrap().starts_with(&root));
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,166 @@
+//! SFTP 会话中打开的文件和目录句柄
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::info;
+
+/// 未设置 SFTP_MAX_HANDLES 时，每个会话最多同时打开的句柄数量
+const DEFAULT_MAX_HANDLES: usize = 256;
+
+pub enum OpenHandle {
+    File { path: PathBuf, file: fs::File },
+    // 每个目录句柄各自的遍历进度；entries 经由 dir 的文件描述符读取目录项
+    Dir {
+        path: PathBuf,
+        dir: fs::File,
+        entries: fs::ReadDir,
+    },
+}
+
+impl OpenHandle {
+    /// 句柄对应的真实路径
+    pub fn path(&self) -> &Path {
+        match self {
+            Self::File { path, .. } | Self::Dir { path, .. } => path,
+        }
+    }
+
+    /// 直接读取已打开的文件或目录的属性
+    pub fn metadata(&self) -> io::Result<fs::Metadata> {
+        match self {
+            Self::File { file, .. } => file.metadata(),
+            Self::Dir { dir, .. } => dir.metadata(),
+        }
+    }
+}
+
+fn invalid_handle() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "Invalid handle")
+}
+
+/// 句柄表：分配在整个会话中都不会重复的句柄，并限制同时打开的数量
+pub struct HandleTable {
+    next_id: u64,
+    max_handles: usize,
+    handles: HashMap<String, OpenHandle>,
+}
+
+impl HandleTable {
+    pub fn new(max_handles: usize) -> Self {
+        Self {
+            next_id: 0,
+            max_handles,
+            handles: HashMap::new(),
+        }
+    }
+
+    pub fn max_handles(&self) -> usize {
+        self.max_handles
+    }
+
+    /// 句柄数量已达上限时返回错误，用于在打开文件之前检查
+    pub fn check_capacity(&self) -> io::Result<()> {
+        if self.handles.len() >= self.max_handles {
+            return Err(io::Error::other(format!(
+                "Too many open handles (limit {})",
+                self.max_handles
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn insert(&mut self, handle: OpenHandle) -> io::Result<String> {
+        self.check_capacity()?;
+        self.next_id += 1;
+        let id = format!("{:016x}", self.next_id);
+        self.handles.insert(id.clone(), handle);
+        Ok(id)
+    }
+
+    pub fn get(&self, handle: &str) -> io::Result<&OpenHandle> {
+        self.handles.get(handle).ok_or_else(invalid_handle)
+    }
+
+    pub fn file(&self, handle: &str) -> io::Result<&fs::File> {
+        match self.get(handle)? {
+            OpenHandle::File { file, .. } => Ok(file),
+            OpenHandle::Dir { .. } => Err(io::Error::other("Not a file handle")),
+        }
+    }
+
+    pub fn dir_mut(&mut self, handle: &str) -> io::Result<&mut fs::ReadDir> {
+        match self.handles.get_mut(handle).ok_or_else(invalid_handle)? {
+            OpenHandle::Dir { entries, .. } => Ok(entries),
+            OpenHandle::File { .. } => Err(io::Error::other("Not a directory handle")),
+        }
+    }
+
+    /// 关闭句柄；被移除的文件或目录在返回值丢弃时关闭
+    pub fn remove(&mut self, handle: &str) -> io::Result<OpenHandle> {
+        self.handles.remove(handle).ok_or_else(invalid_handle)
+    }
+}
+
+impl Default for HandleTable {
+    fn default() -> Self {
+        let max_handles = env::var("SFTP_MAX_HANDLES")
+            .ok()
+            .and_then(|max| max.parse().ok())
+            .unwrap_or(DEFAULT_MAX_HANDLES);
+        Self::new(max_handles)
+    }
+}
+
+// 通道 EOF 后 russh_sftp 会丢弃整个会话，客户端没有 close 的句柄在这里一并关闭
+impl Drop for HandleTable {
+    fn drop(&mut self) {
+        if !self.handles.is_empty() {
+            info!("closing {} handles left open at end of session", self.handles.len());
+            self.handles.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open_file(dir: &Path) -> OpenHandle {
+        let path = dir.join("file.txt");
+        let file = fs::File::create(&path).unwrap();
+        OpenHandle::File { path, file }
+    }
+
+    #[test]
+    fn test_handle_table() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut table = HandleTable::new(2);
+
+        let first = table.insert(open_file(dir.path())).unwrap();
+        let (opened, entries) = crate::fs::read_dir_nofollow(dir.path()).unwrap();
+        let second = table
+            .insert(OpenHandle::Dir {
+                path: dir.path().to_path_buf(),
+                dir: opened,
+                entries,
+            })
+            .unwrap();
+        assert_ne!(first, second);
+        assert!(table.file(&first).is_ok());
+        assert!(table.file(&second).is_err());
+        assert!(table.dir_mut(&second).is_ok());
+
+        // 达到上限后拒绝新的句柄
+        assert!(table.insert(open_file(dir.path())).is_err());
+
+        // 关闭后句柄不会被再次分配
+        table.remove(&first).unwrap();
+        assert!(table.get(&first).is_err());
+        let third = table.insert(open_file(dir.path())).unwrap();
+        assert_ne!(third, first);
+        assert!(table.remove(&first).is_err());
+    }
+}
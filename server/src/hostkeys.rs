@@ -0,0 +1,454 @@
+//! 持久化的主机密钥：首次启动时生成并保存，之后每次启动都使用同一组密钥
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use russh_keys::key::{KeyPair, SignatureHash};
+
+/// 未设置 HOST_KEY_DIR 时保存主机密钥的目录
+const DEFAULT_HOST_KEY_DIR: &str = "host_keys";
+/// 新生成的 RSA 密钥长度
+const RSA_BITS: usize = 3072;
+const ALL_TYPES: [KeyType; 3] = [KeyType::Ed25519, KeyType::Rsa, KeyType::Ecdsa];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyType {
+    Ed25519,
+    Rsa,
+    Ecdsa,
+}
+
+impl KeyType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            KeyType::Ed25519 => "ed25519",
+            KeyType::Rsa => "rsa",
+            KeyType::Ecdsa => "ecdsa",
+        }
+    }
+
+    /// 与 OpenSSH 相同的文件名，例如 ssh_host_ed25519_key
+    fn file_name(&self) -> String {
+        format!("ssh_host_{}_key", self.name())
+    }
+
+    fn generate(&self) -> Result<KeyPair> {
+        let key = match self {
+            KeyType::Ed25519 => KeyPair::generate_ed25519(),
+            KeyType::Rsa => KeyPair::generate_rsa(RSA_BITS, SignatureHash::SHA2_256),
+            // russh_keys 不能生成 ECDSA 密钥，需要用 ssh-keygen 生成后放到密钥目录中
+            KeyType::Ecdsa => {
+                return Err(anyhow::anyhow!(
+                    "Cannot generate ECDSA host keys, create one with `ssh-keygen -t ecdsa -m PKCS8`"
+                ))
+            }
+        };
+        key.with_context(|| format!("Failed to generate {} host key", self.name()))
+    }
+}
+
+impl FromStr for KeyType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim() {
+            "ed25519" => Ok(KeyType::Ed25519),
+            "rsa" => Ok(KeyType::Rsa),
+            "ecdsa" => Ok(KeyType::Ecdsa),
+            name => Err(anyhow::anyhow!("Unknown host key type: {}", name)),
+        }
+    }
+}
+
+impl fmt::Display for KeyType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// HOST_KEY_TYPES 中逗号分隔的密钥类型，默认只有 ed25519
+pub fn configured_types() -> Result<Vec<KeyType>> {
+    env::var("HOST_KEY_TYPES")
+        .unwrap_or("ed25519".to_string())
+        .split(',')
+        .filter(|name| !name.trim().is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+/// `hostkey list` 显示的一个密钥
+pub struct HostKeyInfo {
+    pub path: PathBuf,
+    pub algorithm: String,
+    pub fingerprint: String,
+    /// 轮换后保留的旧密钥的宽限期截止时间，当前密钥为 None
+    pub retired_until: Option<SystemTime>,
+}
+
+impl HostKeyInfo {
+    /// 旧密钥是否仍在宽限期内
+    pub fn is_active(&self) -> bool {
+        self.retired_until
+            .is_none_or(|until| until > SystemTime::now())
+    }
+}
+
+/// 主机密钥目录。每种类型的当前密钥保存在 ssh_host_<type>_key，
+/// 轮换后旧密钥改名为 ssh_host_<type>_key.old，宽限期截止时间记录在 .old.expires 中
+pub struct HostKeyStore {
+    dir: PathBuf,
+}
+
+impl Default for HostKeyStore {
+    fn default() -> Self {
+        let dir = env::var("HOST_KEY_DIR").unwrap_or(DEFAULT_HOST_KEY_DIR.to_string());
+        Self::new(Path::new(&dir))
+    }
+}
+
+impl HostKeyStore {
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    fn key_path(&self, key_type: KeyType) -> PathBuf {
+        self.dir.join(key_type.file_name())
+    }
+
+    fn retired_path(&self, key_type: KeyType) -> PathBuf {
+        self.dir.join(format!("{}.old", key_type.file_name()))
+    }
+
+    fn expires_path(&self, key_type: KeyType) -> PathBuf {
+        self.dir.join(format!("{}.old.expires", key_type.file_name()))
+    }
+
+    /// 读取服务器出示的主机密钥，缺少的当前密钥会先生成。
+    ///
+    /// 被 replace 换掉的类型只剩下旧密钥：宽限期内和新类型的密钥一起出示，
+    /// 记住旧密钥的客户端会继续使用旧类型；宽限期结束后跳过这个类型，
+    /// 不会为它生成新的密钥，否则记住旧密钥的客户端会看到主机密钥变化的警告
+    pub fn load_or_generate(&self, key_types: &[KeyType]) -> Result<Vec<KeyPair>> {
+        let mut keys = vec![];
+        for &key_type in key_types {
+            let path = self.key_path(key_type);
+            if !path.exists() && self.retired_path(key_type).exists() {
+                if self.in_grace(key_type)? {
+                    keys.push(load_key(&self.retired_path(key_type))?);
+                } else {
+                    warn!("the {} host key was replaced and its grace period is over, remove it from HOST_KEY_TYPES", key_type);
+                }
+                continue;
+            }
+            if !path.exists() {
+                self.generate(key_type)?;
+            }
+            keys.push(load_key(&path)?);
+        }
+        // 被 replace 换掉的类型即使不在 key_types 中，宽限期内也继续出示
+        for key_type in ALL_TYPES {
+            if !key_types.contains(&key_type) && !self.key_path(key_type).exists() && self.in_grace(key_type)? {
+                keys.push(load_key(&self.retired_path(key_type))?);
+            }
+        }
+        if keys.is_empty() {
+            return Err(anyhow::anyhow!("No host keys to serve, check HOST_KEY_TYPES"));
+        }
+        Ok(keys)
+    }
+
+    /// 生成同一种类型的新密钥，下次启动时立即切换，旧密钥不再出示。
+    ///
+    /// russh 对每种算法只使用一个密钥，也不能发送 hostkeys-00@openssh.com 让客户端提前记住新密钥，
+    /// 同一种类型无法新旧密钥同时出示，客户端会看到主机密钥变化的警告。
+    /// 因此需要 force，平滑轮换请用 replace 换成另一种类型
+    pub fn rotate(&self, key_type: KeyType, force: bool) -> Result<HostKeyInfo> {
+        if !force {
+            return Err(anyhow::anyhow!(
+                "Rotating the {} host key switches clients to the new key without an overlap; \
+                 use --replace with another key type to serve both keys during a grace period, \
+                 or --force to switch now",
+                key_type
+            ));
+        }
+        self.retire(key_type, Duration::ZERO, true)?;
+        self.generate(key_type)?;
+        self.describe(&self.key_path(key_type), None)
+    }
+
+    /// 用 new 类型的密钥替换 old 类型的密钥：old 的当前密钥改为旧密钥，在 grace 时间内和新密钥一起出示，
+    /// 之后只出示新密钥。new 类型已有密钥时直接使用
+    pub fn replace(&self, old: KeyType, new: KeyType, grace: Duration, force: bool) -> Result<HostKeyInfo> {
+        if old == new {
+            return Err(anyhow::anyhow!("Cannot replace a {} host key with itself, rotate it instead", old));
+        }
+        if !self.key_path(old).exists() {
+            return Err(anyhow::anyhow!("There is no {} host key to replace", old));
+        }
+        // new 类型的旧密钥还在出示时，为它生成的当前密钥会和旧密钥争用同一种算法
+        if !self.key_path(new).exists() && self.in_grace(new)? {
+            return Err(anyhow::anyhow!(
+                "The replaced {} host key is still served, wait for its grace period to end",
+                new
+            ));
+        }
+        self.check_retirable(old, force)?;
+        let path = self.key_path(new);
+        if !path.exists() {
+            self.generate(new)?;
+        }
+        self.retire(old, grace, force)?;
+        self.describe(&path, None)
+    }
+
+    /// 宽限期内的旧密钥正在出示，再退役一个密钥会把它覆盖掉，需要 force
+    fn check_retirable(&self, key_type: KeyType, force: bool) -> Result<()> {
+        if let Some(until) = self.retired_until(key_type)?.filter(|until| *until > SystemTime::now()) {
+            if !force {
+                return Err(anyhow::anyhow!(
+                    "The previous {} host key is served until {}, retiring another key would discard it; use --force to do so anyway",
+                    key_type,
+                    chrono::DateTime::<chrono::Utc>::from(until).format("%Y-%m-%d %H:%M:%S UTC")
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// 把当前密钥改名为旧密钥，在 grace 时间内继续出示
+    fn retire(&self, key_type: KeyType, grace: Duration, force: bool) -> Result<()> {
+        self.check_retirable(key_type, force)?;
+        let path = self.key_path(key_type);
+        if path.exists() {
+            // 先写好截止时间再改名，否则中途失败会留下没有截止时间、视为已经过期的旧密钥
+            let until = (SystemTime::now() + grace).duration_since(UNIX_EPOCH)?.as_secs();
+            let expires = self.expires_path(key_type);
+            let tmp = expires.with_extension("expires.tmp");
+            fs::write(&tmp, until.to_string())?;
+            fs::rename(&tmp, &expires)?;
+            fs::rename(&path, self.retired_path(key_type))?;
+            let _ = fs::remove_file(path.with_extension("pub"));
+        }
+        Ok(())
+    }
+
+    /// 目录中全部密钥的信息，包括已经过了宽限期的旧密钥
+    pub fn list(&self) -> Result<Vec<HostKeyInfo>> {
+        let mut infos = vec![];
+        for key_type in ALL_TYPES {
+            let path = self.key_path(key_type);
+            if path.exists() {
+                infos.push(self.describe(&path, None)?);
+            }
+            let retired = self.retired_path(key_type);
+            if retired.exists() {
+                let until = self.retired_until(key_type)?.unwrap_or(UNIX_EPOCH);
+                infos.push(self.describe(&retired, Some(until))?);
+            }
+        }
+        Ok(infos)
+    }
+
+    fn describe(&self, path: &Path, retired_until: Option<SystemTime>) -> Result<HostKeyInfo> {
+        let public_key = load_key(path)?.clone_public_key()?;
+        Ok(HostKeyInfo {
+            path: path.to_path_buf(),
+            algorithm: public_key.name().to_string(),
+            fingerprint: format!("SHA256:{}", public_key.fingerprint()),
+            retired_until,
+        })
+    }
+
+    /// 旧密钥是否仍在宽限期内
+    fn in_grace(&self, key_type: KeyType) -> Result<bool> {
+        Ok(self
+            .retired_until(key_type)?
+            .is_some_and(|until| until > SystemTime::now()))
+    }
+
+    fn retired_until(&self, key_type: KeyType) -> Result<Option<SystemTime>> {
+        if !self.retired_path(key_type).exists() {
+            return Ok(None);
+        }
+        // 没有截止时间的旧密钥视为已经过期
+        let secs = fs::read_to_string(self.expires_path(key_type))
+            .ok()
+            .and_then(|secs| secs.trim().parse().ok())
+            .unwrap_or(0);
+        Ok(Some(UNIX_EPOCH + Duration::from_secs(secs)))
+    }
+
+    /// 生成密钥并以 0600 权限写入，同时写出 OpenSSH 格式的公钥 .pub 文件
+    fn generate(&self, key_type: KeyType) -> Result<()> {
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Failed to create {}", self.dir.display()))?;
+        let key = key_type.generate()?;
+        let path = self.key_path(key_type);
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)
+            .with_context(|| format!("Failed to create {}", path.display()))?;
+        russh_keys::encode_pkcs8_pem(&key, &mut file)?;
+        file.flush()?;
+
+        let mut public = fs::File::create(path.with_extension("pub"))?;
+        russh_keys::write_public_key_base64(&mut public, &key.clone_public_key()?)?;
+        info!("generated {} host key {}", key_type, path.display());
+        Ok(())
+    }
+}
+
+/// 读取私钥文件；和 sshd 一样拒绝其他用户可以读取的私钥
+fn load_key(path: &Path) -> Result<KeyPair> {
+    let mode = fs::metadata(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?
+        .permissions()
+        .mode();
+    if mode & 0o077 != 0 {
+        return Err(anyhow::anyhow!(
+            "Permissions {:o} of host key {} are too open, expected 0600",
+            mode & 0o777,
+            path.display()
+        ));
+    }
+    russh_keys::load_secret_key(path, None)
+        .with_context(|| format!("Failed to load host key {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_type() {
+        assert_eq!("rsa".parse::<KeyType>().unwrap(), KeyType::Rsa);
+        assert!("dsa".parse::<KeyType>().is_err());
+        assert_eq!(KeyType::Ed25519.file_name(), "ssh_host_ed25519_key");
+    }
+
+    #[test]
+    fn test_load_or_generate() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = HostKeyStore::new(&dir.path().join("keys"));
+        let keys = store.load_or_generate(&[KeyType::Ed25519]).unwrap();
+        assert_eq!(keys.len(), 1);
+
+        let path = dir.path().join("keys/ssh_host_ed25519_key");
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(path.with_extension("pub").exists());
+
+        // 再次启动时使用同一个密钥
+        let again = store.load_or_generate(&[KeyType::Ed25519]).unwrap();
+        assert_eq!(
+            again[0].clone_public_key().unwrap(),
+            keys[0].clone_public_key().unwrap()
+        );
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(store.load_or_generate(&[KeyType::Ed25519]).is_err());
+        assert!(store.load_or_generate(&[KeyType::Ecdsa]).is_err());
+    }
+
+    #[test]
+    fn test_rotate() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = HostKeyStore::new(dir.path());
+        let old = store.load_or_generate(&[KeyType::Ed25519]).unwrap()[0]
+            .clone_public_key()
+            .unwrap();
+
+        // 同一种类型不能平滑轮换，没有 force 时拒绝，密钥保持不变
+        assert!(store.rotate(KeyType::Ed25519, false).is_err());
+        let keys = store.load_or_generate(&[KeyType::Ed25519]).unwrap();
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].clone_public_key().unwrap(), old);
+
+        // force 时立即切换到新密钥，旧密钥不再出示
+        let new = store.rotate(KeyType::Ed25519, true).unwrap();
+        assert_ne!(new.fingerprint, format!("SHA256:{}", old.fingerprint()));
+        let keys = store.load_or_generate(&[KeyType::Ed25519]).unwrap();
+        assert_eq!(keys.len(), 1);
+        assert_eq!(
+            format!("SHA256:{}", keys[0].clone_public_key().unwrap().fingerprint()),
+            new.fingerprint
+        );
+        let infos = store.list().unwrap();
+        assert_eq!(infos.len(), 2);
+        assert!(!infos[1].is_active());
+    }
+
+    #[test]
+    fn test_replace() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = HostKeyStore::new(dir.path());
+        let old = store.load_or_generate(&[KeyType::Ed25519]).unwrap()[0]
+            .clone_public_key()
+            .unwrap();
+        assert!(store
+            .replace(KeyType::Ed25519, KeyType::Ed25519, Duration::from_secs(3600), false)
+            .is_err());
+        assert!(store
+            .replace(KeyType::Rsa, KeyType::Ed25519, Duration::from_secs(3600), false)
+            .is_err());
+
+        let new = store
+            .replace(KeyType::Ed25519, KeyType::Rsa, Duration::from_secs(3600), false)
+            .unwrap();
+        // 宽限期内新旧两种类型的密钥一起出示，旧类型已经不在配置中也一样
+        let keys = store.load_or_generate(&[KeyType::Rsa]).unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(
+            format!("SHA256:{}", keys[0].clone_public_key().unwrap().fingerprint()),
+            new.fingerprint
+        );
+        assert_eq!(keys[1].clone_public_key().unwrap(), old);
+        // 旧类型仍在配置中时不会为它生成新的密钥
+        let keys = store.load_or_generate(&[KeyType::Ed25519, KeyType::Rsa]).unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0].clone_public_key().unwrap(), old);
+        assert!(!dir.path().join("ssh_host_ed25519_key").exists());
+
+        // 宽限期结束后只出示新密钥
+        fs::write(dir.path().join("ssh_host_ed25519_key.old.expires"), "0").unwrap();
+        let keys = store.load_or_generate(&[KeyType::Rsa]).unwrap();
+        assert_eq!(keys.len(), 1);
+
+        // 宽限期内不能再换回旧类型，否则同一种算法会有新旧两个密钥
+        fs::write(dir.path().join("ssh_host_ed25519_key.old.expires"), "4102444800").unwrap();
+        assert!(store
+            .replace(KeyType::Rsa, KeyType::Ed25519, Duration::from_secs(3600), false)
+            .is_err());
+    }
+
+    #[test]
+    fn test_replaced_type_is_not_regenerated() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = HostKeyStore::new(dir.path());
+        store.load_or_generate(&[KeyType::Ed25519]).unwrap();
+        let new = store
+            .replace(KeyType::Ed25519, KeyType::Rsa, Duration::ZERO, false)
+            .unwrap();
+
+        // 宽限期已经结束而配置中仍有 ed25519：跳过它，不生成客户端不认识的新密钥
+        let keys = store.load_or_generate(&[KeyType::Ed25519, KeyType::Rsa]).unwrap();
+        assert_eq!(keys.len(), 1);
+        assert_eq!(
+            format!("SHA256:{}", keys[0].clone_public_key().unwrap().fingerprint()),
+            new.fingerprint
+        );
+        assert!(!dir.path().join("ssh_host_ed25519_key").exists());
+        assert!(store.load_or_generate(&[KeyType::Ed25519]).is_err());
+    }
+}
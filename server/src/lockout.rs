@@ -0,0 +1,368 @@
+//! 防暴力破解：按账号和来源地址统计登录失败次数，超过阈值后临时封禁，
+//! 重复封禁的时长按指数增长
+use std::env;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::Result;
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{params, Connection, OptionalExtension};
+
+/// 封禁的对象：某个账号或某个来源地址
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BanKind {
+    User,
+    Address,
+}
+
+impl BanKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            BanKind::User => "user",
+            BanKind::Address => "addr",
+        }
+    }
+}
+
+impl FromStr for BanKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "user" => Ok(BanKind::User),
+            "addr" => Ok(BanKind::Address),
+            kind => Err(anyhow::anyhow!("Unknown ban kind: {}", kind)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Ban {
+    pub kind: BanKind,
+    pub subject: String,
+    /// 连续被封禁的次数，决定下一次封禁的时长
+    pub level: u32,
+    /// UTC 时间，格式同 CURRENT_TIMESTAMP
+    pub banned_until: String,
+}
+
+impl fmt::Display for Ban {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} banned until {} UTC",
+            self.kind.name(),
+            self.subject,
+            self.banned_until
+        )
+    }
+}
+
+/// 阈值和封禁时长，时间单位均为秒
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockoutPolicy {
+    /// 一个账号在统计窗口内允许的失败次数
+    pub max_user_failures: u32,
+    /// 一个来源地址在统计窗口内允许的失败次数（可能对应多个账号）
+    pub max_addr_failures: u32,
+    pub window: u64,
+    /// 第一次封禁的时长，之后每次翻倍
+    pub base_lockout: u64,
+    pub max_lockout: u64,
+}
+
+impl Default for LockoutPolicy {
+    fn default() -> Self {
+        fn var(name: &str, default: u64) -> u64 {
+            env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        }
+        Self {
+            max_user_failures: var("LOGIN_MAX_FAILURES", 5) as u32,
+            max_addr_failures: var("LOGIN_MAX_FAILURES_PER_ADDR", 20) as u32,
+            window: var("LOGIN_FAILURE_WINDOW", 15 * 60),
+            base_lockout: var("LOGIN_LOCKOUT", 60),
+            max_lockout: var("LOGIN_LOCKOUT_MAX", 24 * 60 * 60),
+        }
+    }
+}
+
+impl LockoutPolicy {
+    /// 第 level 次（从 0 开始）封禁的时长
+    fn lockout(&self, level: u32) -> u64 {
+        self.base_lockout
+            .saturating_mul(1u64.checked_shl(level).unwrap_or(u64::MAX))
+            .min(self.max_lockout)
+    }
+}
+
+pub struct LoginGuard {
+    policy: LockoutPolicy,
+    pool: Arc<Pool<SqliteConnectionManager>>,
+}
+
+impl LoginGuard {
+    pub fn new(pool: Arc<Pool<SqliteConnectionManager>>, policy: LockoutPolicy) -> Self {
+        Self { policy, pool }
+    }
+
+    /// 账号或来源地址当前是否被封禁，被封禁时返回其中一条
+    pub fn banned(&self, username: &str, addr: Option<IpAddr>) -> Result<Option<Ban>> {
+        let conn = self.pool.get()?;
+        if let Some(ban) = active_ban(&conn, BanKind::User, username)? {
+            return Ok(Some(ban));
+        }
+        match addr {
+            Some(addr) => active_ban(&conn, BanKind::Address, &addr.to_string()),
+            None => Ok(None),
+        }
+    }
+
+    /// 记录一次失败；超过阈值时封禁账号或地址并返回新的封禁
+    pub fn record_failure(
+        &self,
+        username: &str,
+        addr: Option<IpAddr>,
+        method: &str,
+    ) -> Result<Vec<Ban>> {
+        let conn = self.pool.get()?;
+        let addr = addr.map(|addr| addr.to_string());
+        conn.execute(
+            "INSERT INTO LoginAttempts (username, client_addr, method, success) VALUES (?, ?, ?, 0)",
+            params![username, addr, method],
+        )?;
+        let mut bans = vec![];
+        let user_failures = count_failures(&conn, BanKind::User, username, self.policy.window)?;
+        if user_failures >= self.policy.max_user_failures {
+            bans.push(self.ban(&conn, BanKind::User, username)?);
+        }
+        if let Some(addr) = addr {
+            let addr_failures =
+                count_failures(&conn, BanKind::Address, &addr, self.policy.window)?;
+            if addr_failures >= self.policy.max_addr_failures {
+                bans.push(self.ban(&conn, BanKind::Address, &addr)?);
+            }
+        }
+        Ok(bans)
+    }
+
+    /// 登录成功后这个账号之前的失败不再计数，账号的封禁时长也恢复到初始值。
+    /// 来源地址的计数和封禁等级不变：攻击者可以用自己的账号成功登录来清掉对其他账号的尝试，
+    /// 地址的失败次数只随统计窗口过期
+    pub fn record_success(&self, username: &str, addr: Option<IpAddr>, method: &str) -> Result<()> {
+        let conn = self.pool.get()?;
+        let addr = addr.map(|addr| addr.to_string());
+        conn.execute(
+            "INSERT INTO LoginAttempts (username, client_addr, method, success) VALUES (?, ?, ?, 1)",
+            params![username, addr, method],
+        )?;
+        conn.execute(
+            "UPDATE Bans SET level = 0 WHERE kind = 'user' AND subject = ?",
+            params![username],
+        )?;
+        Ok(())
+    }
+
+    fn ban(&self, conn: &Connection, kind: BanKind, subject: &str) -> Result<Ban> {
+        let level: u32 = conn
+            .query_row(
+                "SELECT level FROM Bans WHERE kind = ? AND subject = ?",
+                params![kind.name(), subject],
+                |row| row.get(0),
+            )
+            .optional()?
+            .unwrap_or(0);
+        let seconds = self.policy.lockout(level) as i64;
+        // last_attempt_id 之前的失败已经导致过这次封禁，之后不再计数
+        conn.execute(
+            "INSERT INTO Bans (kind, subject, level, banned_until, last_attempt_id)
+                VALUES (?1, ?2, ?3, datetime('now', ?4 || ' seconds'), (SELECT MAX(attempt_id) FROM LoginAttempts))
+             ON CONFLICT (kind, subject) DO UPDATE SET
+                level = excluded.level, banned_until = excluded.banned_until,
+                last_attempt_id = excluded.last_attempt_id, created_at = CURRENT_TIMESTAMP",
+            params![kind.name(), subject, level + 1, seconds],
+        )?;
+        Ok(active_ban(conn, kind, subject)?.expect("ban was just created"))
+    }
+}
+
+/// 统计窗口内、最近一次封禁之后的失败次数；账号的计数还从最近一次成功登录之后开始
+fn count_failures(conn: &Connection, kind: BanKind, subject: &str, window: u64) -> Result<u32> {
+    let (column, since_success) = match kind {
+        BanKind::User => (
+            "username",
+            "AND attempt_id > COALESCE((SELECT MAX(attempt_id) FROM LoginAttempts WHERE username = ?1 AND success = 1), 0)",
+        ),
+        BanKind::Address => ("client_addr", ""),
+    };
+    let count = conn.query_row(
+        &format!(
+            "SELECT COUNT(*) FROM LoginAttempts
+                WHERE {column} = ?1 AND success = 0
+                AND created_at > datetime('now', ?2 || ' seconds')
+                {since_success}
+                AND attempt_id > COALESCE((SELECT last_attempt_id FROM Bans WHERE kind = ?3 AND subject = ?1), 0)",
+            column = column,
+            since_success = since_success
+        ),
+        params![subject, -(window as i64), kind.name()],
+        |row| row.get(0),
+    )?;
+    Ok(count)
+}
+
+fn active_ban(conn: &Connection, kind: BanKind, subject: &str) -> Result<Option<Ban>> {
+    let ban = conn
+        .query_row(
+            "SELECT level, banned_until FROM Bans
+                WHERE kind = ? AND subject = ? AND banned_until > CURRENT_TIMESTAMP",
+            params![kind.name(), subject],
+            |row| {
+                Ok(Ban {
+                    kind,
+                    subject: subject.to_string(),
+                    level: row.get(0)?,
+                    banned_until: row.get(1)?,
+                })
+            },
+        )
+        .optional()?;
+    Ok(ban)
+}
+
+/// 当前仍然有效的封禁
+pub fn list_bans(conn: &Connection) -> Result<Vec<Ban>> {
+    let mut stmt = conn.prepare(
+        "SELECT kind, subject, level, banned_until FROM Bans
+            WHERE banned_until > CURRENT_TIMESTAMP ORDER BY banned_until",
+    )?;
+    let rows = stmt.query_map(params![], |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, u32>(2)?,
+            row.get::<_, String>(3)?,
+        ))
+    })?;
+    let mut bans = vec![];
+    for row in rows {
+        let (kind, subject, level, banned_until) = row?;
+        bans.push(Ban {
+            kind: kind.parse()?,
+            subject,
+            level,
+            banned_until,
+        });
+    }
+    Ok(bans)
+}
+
+/// 解除封禁并把封禁时长恢复到初始值，返回是否存在有效的封禁
+pub fn clear_ban(conn: &Connection, kind: BanKind, subject: &str) -> Result<bool> {
+    let active = active_ban(conn, kind, subject)?.is_some();
+    conn.execute(
+        "UPDATE Bans SET level = 0, banned_until = CURRENT_TIMESTAMP,
+            last_attempt_id = (SELECT MAX(attempt_id) FROM LoginAttempts)
+         WHERE kind = ? AND subject = ?",
+        params![kind.name(), subject],
+    )?;
+    Ok(active)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{DatabasePool, MockDatabasePool};
+
+    fn policy() -> LockoutPolicy {
+        LockoutPolicy {
+            max_user_failures: 3,
+            max_addr_failures: 5,
+            window: 600,
+            base_lockout: 60,
+            max_lockout: 200,
+        }
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = policy();
+        assert_eq!(policy.lockout(0), 60);
+        assert_eq!(policy.lockout(1), 120);
+        assert_eq!(policy.lockout(2), 200);
+        assert_eq!(policy.lockout(100), 200);
+    }
+
+    #[test]
+    fn test_user_lockout() {
+        let pool = MockDatabasePool::get_pool();
+        let guard = LoginGuard::new(pool.clone(), policy());
+        let addr: IpAddr = "192.0.2.1".parse().unwrap();
+
+        assert!(guard.record_failure("admin", Some(addr), "password").unwrap().is_empty());
+        // 成功登录后重新计数
+        guard.record_success("admin", Some(addr), "password").unwrap();
+        assert!(guard.record_failure("admin", Some(addr), "password").unwrap().is_empty());
+        assert!(guard.record_failure("admin", Some(addr), "password").unwrap().is_empty());
+        assert!(guard.banned("admin", Some(addr)).unwrap().is_none());
+
+        let bans = guard.record_failure("admin", Some(addr), "password").unwrap();
+        assert_eq!(bans.len(), 1);
+        assert_eq!(bans[0].kind, BanKind::User);
+        assert_eq!(bans[0].level, 1);
+        let ban = guard.banned("admin", None).unwrap().unwrap();
+        assert_eq!(ban.subject, "admin");
+        assert!(guard.banned("other", None).unwrap().is_none());
+
+        let conn = pool.get().unwrap();
+        assert_eq!(list_bans(&conn).unwrap().len(), 1);
+        assert!(clear_ban(&conn, BanKind::User, "admin").unwrap());
+        assert!(!clear_ban(&conn, BanKind::User, "admin").unwrap());
+        assert!(guard.banned("admin", None).unwrap().is_none());
+        // 解除封禁后之前的失败不再计数
+        assert!(guard.record_failure("admin", None, "password").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_address_ban_and_backoff() {
+        let pool = MockDatabasePool::get_pool();
+        let guard = LoginGuard::new(pool.clone(), policy());
+        let addr: IpAddr = "192.0.2.7".parse().unwrap();
+
+        // 同一地址尝试不同的账号，中间用自己的账号成功登录也不会清掉地址的计数
+        for i in 0..4 {
+            let bans = guard.record_failure(&format!("user{}", i), Some(addr), "password").unwrap();
+            assert!(bans.is_empty());
+            guard.record_success("mallory", Some(addr), "password").unwrap();
+        }
+        let bans = guard.record_failure("user4", Some(addr), "password").unwrap();
+        assert_eq!(bans.len(), 1);
+        assert_eq!(bans[0].kind, BanKind::Address);
+        assert!(guard.banned("someone", Some(addr)).unwrap().is_some());
+
+        // 再次被封禁时等级增加，时长翻倍
+        let conn = pool.get().unwrap();
+        clear_ban(&conn, BanKind::Address, &addr.to_string()).unwrap();
+        conn.execute("UPDATE Bans SET level = 1", params![]).unwrap();
+        for i in 0..4 {
+            guard.record_failure(&format!("other{}", i), Some(addr), "password").unwrap();
+        }
+        // 成功登录也不会降低地址的封禁等级
+        guard.record_success("mallory", Some(addr), "password").unwrap();
+        let bans = guard.record_failure("other4", Some(addr), "password").unwrap();
+        assert_eq!(bans[0].level, 2);
+        let seconds: i64 = conn
+            .query_row(
+                "SELECT CAST(strftime('%s', banned_until) AS INTEGER) - CAST(strftime('%s', 'now') AS INTEGER) FROM Bans",
+                params![],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert!((115..=120).contains(&seconds), "{}", seconds);
+    }
+}
@@ -0,0 +1,141 @@
+//! 日志：target 为 audit 的审计事件只写入审计日志，其他诊断信息输出到终端和日志文件
+use std::env;
+
+use anyhow::Result;
+use tracing::field::Field;
+use tracing::{Metadata, Subscriber};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_subscriber::field::MakeExt;
+use tracing_subscriber::filter::{filter_fn, EnvFilter, FilterExt};
+use tracing_subscriber::fmt::format::{self, Writer};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, Layer};
+
+use crate::audit::{DatabaseLogger, AUDIT_TARGET};
+
+/// 值不会出现在诊断日志中的字段名
+const SECRET_FIELDS: &[&str] = &["password", "old_password", "passphrase", "secret", "code", "token"];
+
+/// 未设置 RUST_LOG 时的诊断日志级别
+const DEFAULT_FILTER: &str = "info";
+
+fn is_audit(metadata: &Metadata<'_>) -> bool {
+    metadata.target() == AUDIT_TARGET
+}
+
+/// 审计日志只接收审计事件，不受 RUST_LOG 影响
+pub fn audit_layer<S>(logger: DatabaseLogger) -> impl Layer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    logger.with_filter(filter_fn(is_audit))
+}
+
+/// 输出到 writer 的诊断日志：按 RUST_LOG 过滤，不包括审计事件，敏感字段的值被替换
+pub fn diagnostic_layer<S, W>(writer: W, ansi: bool) -> impl Layer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    let env_filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_FILTER));
+    let fields = format::debug_fn(|writer: &mut Writer<'_>, field: &Field, value| {
+        if field.name() == "message" {
+            write!(writer, "{:?}", value)
+        } else if SECRET_FIELDS.contains(&field.name()) {
+            write!(writer, "{}=[REDACTED]", field)
+        } else {
+            write!(writer, "{}={:?}", field, value)
+        }
+    })
+    .delimited(" ");
+    fmt::layer()
+        .with_writer(writer)
+        .with_ansi(ansi)
+        .fmt_fields(fields)
+        .with_filter(filter_fn(|metadata| !is_audit(metadata)).and(env_filter))
+}
+
+/// 安装全局的日志：审计事件写入数据库，诊断信息输出到终端；
+/// 设置了 LOG_DIR 时同时按天写入 LOG_DIR/sshfs-rs.log.YYYY-MM-DD。
+/// 返回的 guard 在退出前不能丢弃，否则日志文件中最后的内容可能丢失
+pub fn init(audit: DatabaseLogger) -> Result<Option<WorkerGuard>> {
+    let (file_layer, guard) = match env::var("LOG_DIR") {
+        Ok(dir) => {
+            let appender = tracing_appender::rolling::daily(dir, "sshfs-rs.log");
+            let (writer, guard) = tracing_appender::non_blocking(appender);
+            (Some(diagnostic_layer(writer, false)), Some(guard))
+        }
+        Err(_) => (None, None),
+    };
+    tracing_subscriber::registry()
+        .with(audit_layer(audit))
+        .with(diagnostic_layer(std::io::stderr, true))
+        .with(file_layer)
+        .try_init()?;
+    Ok(guard)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::audit::{query_audit_logs, AuditFilter};
+    use crate::database::{DatabasePool, MockDatabasePool};
+
+    /// 把诊断日志收集到内存中
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for Buffer {
+        type Writer = Buffer;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    #[test]
+    fn test_audit_and_diagnostics_are_separated() {
+        let pool = MockDatabasePool::get_pool();
+        let logger = DatabaseLogger::new(pool.clone());
+        let buffer = Buffer::default();
+        let subscriber = tracing_subscriber::registry()
+            .with(audit_layer(logger.clone()))
+            .with(diagnostic_layer(buffer.clone(), false));
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(target: AUDIT_TARGET, username = "admin", action = "Remove", target = "a.txt", "User action logged");
+            tracing::info!(user = "admin", password = "hunter2", "login attempt");
+            tracing::info!("subsystem: sftp");
+        });
+        logger.flush();
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        assert!(output.contains("login attempt user=\"admin\" password=[REDACTED]"), "{}", output);
+        assert!(output.contains("subsystem: sftp"), "{}", output);
+        assert!(!output.contains("hunter2"), "{}", output);
+        assert!(!output.contains("User action logged"), "{}", output);
+
+        // 诊断信息没有写入审计日志
+        let conn = pool.get().unwrap();
+        let records = query_audit_logs(&conn, &AuditFilter::default()).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].action, "Remove");
+    }
+}
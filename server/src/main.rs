@@ -1,20 +1,33 @@
+mod acl;
 mod audit;
 mod auth;
+mod authorized_keys;
+mod checksum;
 mod database;
+mod extensions;
 mod fs;
+mod handles;
+mod hostkeys;
+mod lockout;
+mod logging;
+mod password;
+mod quota;
+mod retention;
 mod sftp_server;
+mod sink;
+mod totp;
 
 use auth::Auther;
-use clap::{Arg, Command};
-use log::LevelFilter;
+use clap::{Arg, ArgAction, Command};
 use russh::server::Server;
-use russh_keys::key::KeyPair;
+use std::net::IpAddr;
+use std::path::Path;
 use std::sync::Arc;
 use std::time::Duration;
 use std::env;
-use tracing_subscriber::layer::SubscriberExt;
+use std::io::Write;
 
-use crate::audit::DatabaseLogger;
+use crate::audit::{AuditConfig, DatabaseLogger};
 use crate::auth::User;
 use crate::database::{DatabasePool, GlobalDatabasePool};
 
@@ -62,6 +75,19 @@ async fn main() {
                                 .required(true)
                                 .index(2)
                                 .help("Password for the new user"),
+                        )
+                        .arg(
+                            Arg::new("root")
+                                .long("root")
+                                .value_name("DIR")
+                                .help("Home directory of the user, defaults to VIRTUAL_ROOT_PATH"),
+                        )
+                        .arg(
+                            Arg::new("create-root")
+                                .long("create-root")
+                                .action(ArgAction::SetTrue)
+                                .requires("root")
+                                .help("Create the home directory if it does not exist"),
                         ),
                 )
                 .subcommand(
@@ -85,6 +111,426 @@ async fn main() {
                                 .index(3)
                                 .help("Old password for the user"),
                         ),
+                )
+                .subcommand(
+                    Command::new("set-root")
+                        .about("Move a user's home directory")
+                        .arg(
+                            Arg::new("username")
+                                .required(true)
+                                .index(1)
+                                .help("Username of the user to update"),
+                        )
+                        .arg(
+                            Arg::new("root")
+                                .required(true)
+                                .index(2)
+                                .help("New home directory of the user"),
+                        )
+                        .arg(
+                            Arg::new("create")
+                                .long("create")
+                                .action(ArgAction::SetTrue)
+                                .help("Create the directory if it does not exist"),
+                        ),
+                )
+                .subcommand(
+                    Command::new("list")
+                        .about("List users with their role, creation time and last login"),
+                )
+                .subcommand(
+                    Command::new("delete")
+                        .about("Delete a user together with their keys, rules and quotas")
+                        .arg(
+                            Arg::new("username")
+                                .required(true)
+                                .index(1)
+                                .help("Username of the user to delete"),
+                        ),
+                )
+                .subcommand(
+                    Command::new("disable")
+                        .about("Prevent a user from logging in")
+                        .arg(
+                            Arg::new("username")
+                                .required(true)
+                                .index(1)
+                                .help("Username of the user to disable"),
+                        ),
+                )
+                .subcommand(
+                    Command::new("enable")
+                        .about("Allow a disabled user to log in again")
+                        .arg(
+                            Arg::new("username")
+                                .required(true)
+                                .index(1)
+                                .help("Username of the user to enable"),
+                        ),
+                )
+                .subcommand(
+                    Command::new("set-role")
+                        .about("Change a user's role")
+                        .arg(
+                            Arg::new("username")
+                                .required(true)
+                                .index(1)
+                                .help("Username of the user to update"),
+                        )
+                        .arg(
+                            Arg::new("role")
+                                .required(true)
+                                .index(2)
+                                .help("New role of the user, e.g. admin or user"),
+                        ),
+                )
+                .subcommand(
+                    Command::new("reset-password")
+                        .about("Set a new password without knowing the old one")
+                        .arg(
+                            Arg::new("username")
+                                .required(true)
+                                .index(1)
+                                .help("Username of the user to update"),
+                        )
+                        .arg(
+                            Arg::new("password")
+                                .required(true)
+                                .index(2)
+                                .help("New password for the user"),
+                        ),
+                )
+                .subcommand(
+                    Command::new("key")
+                        .about("Manage a user's public keys")
+                        .subcommand(
+                            Command::new("add")
+                                .about("Authorize a public key for a user")
+                                .arg(Arg::new("username").required(true).index(1))
+                                .arg(
+                                    Arg::new("key")
+                                        .required(true)
+                                        .index(2)
+                                        .help("OpenSSH public key line, or path to a .pub file"),
+                                )
+                                .arg(
+                                    Arg::new("comment")
+                                        .long("comment")
+                                        .value_name("TEXT")
+                                        .help("Comment for the key, defaults to the comment in the key line"),
+                                )
+                                .arg(
+                                    Arg::new("expires")
+                                        .long("expires")
+                                        .value_name("TIME")
+                                        .help("Expiry time in UTC, `YYYY-MM-DD` or `YYYY-MM-DD HH:MM:SS`"),
+                                ),
+                        )
+                        .subcommand(
+                            Command::new("list")
+                                .about("List the public keys of a user")
+                                .arg(Arg::new("username").required(true).index(1)),
+                        )
+                        .subcommand(
+                            Command::new("revoke")
+                                .about("Revoke a public key of a user")
+                                .arg(Arg::new("username").required(true).index(1))
+                                .arg(
+                                    Arg::new("id")
+                                        .required(true)
+                                        .index(2)
+                                        .value_parser(clap::value_parser!(i64))
+                                        .help("Id of the key, as shown by `auth key list`"),
+                                ),
+                        ),
+                )
+                .subcommand(
+                    Command::new("totp")
+                        .about("Manage TOTP two-factor authentication")
+                        .subcommand(
+                            Command::new("enroll")
+                                .about("Enroll a user, replacing any previous secret and recovery codes")
+                                .arg(Arg::new("username").required(true).index(1))
+                                .arg(
+                                    Arg::new("qr")
+                                        .long("qr")
+                                        .action(ArgAction::SetTrue)
+                                        .help("Print the otpauth URI as a QR code in the terminal"),
+                                ),
+                        )
+                        .subcommand(
+                            Command::new("disable")
+                                .about("Disable two-factor authentication for a user")
+                                .arg(Arg::new("username").required(true).index(1)),
+                        )
+                        .subcommand(
+                            Command::new("recovery-codes")
+                                .about("Generate new recovery codes, invalidating the old ones")
+                                .arg(Arg::new("username").required(true).index(1)),
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("hostkey")
+                .about("Manage the server's host keys")
+                .subcommand(Command::new("list").about("Print the fingerprints of the host keys"))
+                .subcommand(
+                    Command::new("rotate")
+                        .about(
+                            "Replace a host key with one of another type, serving both during a grace period. \
+                             A key can only be rotated to a new key of the same type with --force, which switches \
+                             clients to the new key without an overlap",
+                        )
+                        .arg(
+                            Arg::new("type")
+                                .long("type")
+                                .value_name("TYPE")
+                                .default_value("ed25519")
+                                .help("Key type: ed25519 or rsa"),
+                        )
+                        .arg(
+                            Arg::new("replace")
+                                .long("replace")
+                                .value_name("TYPE")
+                                .help("Retire the key of this other type, serving both keys during the grace period"),
+                        )
+                        .arg(
+                            Arg::new("grace-days")
+                                .long("grace-days")
+                                .value_name("DAYS")
+                                .default_value("7")
+                                .value_parser(clap::value_parser!(u64))
+                                .help("Days the replaced key keeps being served, 0 to stop serving it at the next start"),
+                        )
+                        .arg(
+                            Arg::new("force")
+                                .long("force")
+                                .action(ArgAction::SetTrue)
+                                .help(
+                                    "Rotate to a new key of the same type, or retire a key while a previous one is still served",
+                                ),
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("ban")
+                .about("Manage login lockouts and address bans")
+                .subcommand(Command::new("list").about("List active bans"))
+                .subcommand(
+                    Command::new("clear")
+                        .about("Lift the ban of a user or an address")
+                        .arg(
+                            Arg::new("user")
+                                .long("user")
+                                .value_name("USERNAME")
+                                .conflicts_with("addr")
+                                .required_unless_present("addr")
+                                .help("Locked out user"),
+                        )
+                        .arg(
+                            Arg::new("addr")
+                                .long("addr")
+                                .value_name("IP")
+                                .value_parser(clap::value_parser!(IpAddr))
+                                .help("Banned client address"),
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("audit")
+                .about("Query the audit log")
+                .subcommand(
+                    Command::new("query")
+                        .about("Print or export audit records, oldest first")
+                        .arg(
+                            Arg::new("user")
+                                .long("user")
+                                .value_name("USERNAME")
+                                .help("Only records of this user"),
+                        )
+                        .arg(
+                            Arg::new("action")
+                                .long("action")
+                                .action(ArgAction::Append)
+                                .help("Only these actions, e.g. Remove; can be repeated"),
+                        )
+                        .arg(
+                            Arg::new("path")
+                                .long("path")
+                                .help("Only targets under this path prefix"),
+                        )
+                        .arg(
+                            Arg::new("since")
+                                .long("since")
+                                .value_name("TIME")
+                                .help("Only records at or after this UTC time, YYYY-MM-DD [HH:MM:SS]"),
+                        )
+                        .arg(
+                            Arg::new("until")
+                                .long("until")
+                                .value_name("TIME")
+                                .help("Only records before this UTC time, YYYY-MM-DD [HH:MM:SS]"),
+                        )
+                        .arg(
+                            Arg::new("limit")
+                                .long("limit")
+                                .value_parser(clap::value_parser!(u64))
+                                .help("Records per page, defaults to 50 for table output and all records for exports"),
+                        )
+                        .arg(
+                            Arg::new("page")
+                                .long("page")
+                                .value_parser(clap::value_parser!(u64).range(1..))
+                                .default_value("1")
+                                .help("Page number, starting at 1"),
+                        )
+                        .arg(
+                            Arg::new("format")
+                                .long("format")
+                                .value_parser(["table", "csv", "jsonl"])
+                                .default_value("table")
+                                .help("Output format"),
+                        )
+                        .arg(
+                            Arg::new("output")
+                                .long("output")
+                                .short('o')
+                                .value_name("FILE")
+                                .help("Write to this file instead of standard output"),
+                        ),
+                )
+                .subcommand(
+                    Command::new("verify")
+                        .about("Check the hash chain of the audit log and report the first inconsistent record"),
+                )
+                .subcommand(
+                    Command::new("prune")
+                        .about("Archive and delete audit records older than the retention period")
+                        .arg(
+                            Arg::new("days")
+                                .long("days")
+                                .value_parser(clap::value_parser!(u32))
+                                .help("Days of records to keep, defaults to AUDIT_RETENTION_DAYS or 90"),
+                        )
+                        .arg(
+                            Arg::new("archive-dir")
+                                .long("archive-dir")
+                                .value_name("DIR")
+                                .help("Directory of the archive files, defaults to AUDIT_ARCHIVE_DIR or audit_archive"),
+                        )
+                        .arg(
+                            Arg::new("no-archive")
+                                .long("no-archive")
+                                .action(ArgAction::SetTrue)
+                                .conflicts_with("archive-dir")
+                                .help("Delete the records without archiving them"),
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("acl")
+                .about("Manage access rules")
+                .subcommand(
+                    Command::new("add")
+                        .about("Add an access rule for a user or a role")
+                        .arg(
+                            Arg::new("user")
+                                .long("user")
+                                .value_name("USERNAME")
+                                .conflicts_with("role")
+                                .required_unless_present("role")
+                                .help("User the rule applies to"),
+                        )
+                        .arg(
+                            Arg::new("role")
+                                .long("role")
+                                .value_name("ROLE")
+                                .help("Role the rule applies to"),
+                        )
+                        .arg(
+                            Arg::new("path")
+                                .required(true)
+                                .index(1)
+                                .help("Virtual path prefix, e.g. /shared"),
+                        )
+                        .arg(
+                            Arg::new("rights")
+                                .required(true)
+                                .index(2)
+                                .help("Comma separated rights: read,write,list,delete,mkdir, or all/none"),
+                        ),
+                )
+                .subcommand(Command::new("list").about("List access rules"))
+                .subcommand(
+                    Command::new("remove")
+                        .about("Remove an access rule")
+                        .arg(
+                            Arg::new("id")
+                                .required(true)
+                                .index(1)
+                                .value_parser(clap::value_parser!(i64))
+                                .help("Id of the rule, as shown by `acl list`"),
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("quota")
+                .about("Manage disk quotas")
+                .subcommand(
+                    Command::new("set")
+                        .about("Set the quota of a user or a role")
+                        .arg(
+                            Arg::new("user")
+                                .long("user")
+                                .value_name("USERNAME")
+                                .conflicts_with("role")
+                                .required_unless_present("role")
+                                .help("User the quota applies to"),
+                        )
+                        .arg(
+                            Arg::new("role")
+                                .long("role")
+                                .value_name("ROLE")
+                                .help("Role the quota applies to"),
+                        )
+                        .arg(
+                            Arg::new("max-bytes")
+                                .long("max-bytes")
+                                .value_name("BYTES")
+                                .value_parser(clap::value_parser!(u64))
+                                .help("Maximum number of bytes, unlimited if omitted"),
+                        )
+                        .arg(
+                            Arg::new("max-files")
+                                .long("max-files")
+                                .value_name("COUNT")
+                                .value_parser(clap::value_parser!(u64))
+                                .help("Maximum number of files and directories, unlimited if omitted"),
+                        ),
+                )
+                .subcommand(
+                    Command::new("show")
+                        .about("Show the quota and usage of a user")
+                        .arg(Arg::new("username").required(true).index(1)),
+                )
+                .subcommand(
+                    Command::new("recompute")
+                        .about("Recompute the usage of a user from the files in their root")
+                        .arg(Arg::new("username").required(true).index(1)),
+                ),
+        )
+        .subcommand(
+            Command::new("db")
+                .about("Manage the database")
+                .subcommand(
+                    Command::new("migrate")
+                        .about("Apply pending schema migrations")
+                        .arg(
+                            Arg::new("status")
+                                .long("status")
+                                .action(ArgAction::SetTrue)
+                                .help("Only list the migrations and whether they have been applied"),
+                        ),
                 ),
         )
         .get_matches();
@@ -94,40 +540,51 @@ async fn main() {
             let default_port = env::var("PORT").unwrap_or("22".to_string());
             let port = run_matches.get_one::<String>("port").unwrap_or(&default_port);
             let port = port.parse::<u16>().unwrap();
-            env_logger::builder()
-                .filter_level(LevelFilter::Debug)
-                .init();
-
             let config = russh::server::Config {
                 auth_rejection_time: Duration::from_secs(3),
                 auth_rejection_time_initial: Some(Duration::from_secs(0)),
-                keys: vec![KeyPair::generate_ed25519().unwrap()],
+                keys: hostkeys::HostKeyStore::default()
+                    .load_or_generate(&hostkeys::configured_types().unwrap())
+                    .unwrap(),
                 ..Default::default()
             };
 
-            let logger = DatabaseLogger::new(GlobalDatabasePool::get_pool());
-            let subscriber = tracing_subscriber::Registry::default().with(logger);
-            tracing::subscriber::set_global_default(subscriber).expect("Failed to set subscriber");
+            let logger = DatabaseLogger::with_config(
+                GlobalDatabasePool::get_pool(),
+                AuditConfig::from_env().unwrap(),
+            )
+            .unwrap();
+            retention::spawn(GlobalDatabasePool::get_pool(), retention::RetentionPolicy::from_env().unwrap());
+            // 退出时用它等待队列中的审计记录写完
+            let audit_logger = logger.clone();
+            let _log_guard = logging::init(logger).expect("Failed to set subscriber");
 
             let mut server = crate::sftp_server::Server::<GlobalDatabasePool> {
                 // pool: GlobalDatabasePool::get_pool(),
                 _marker: std::marker::PhantomData,
             };
 
-            server
-                .run_on_address(Arc::new(config), ("0.0.0.0", port))
-                .await
-                .unwrap();
+            tokio::select! {
+                result = server.run_on_address(Arc::new(config), ("0.0.0.0", port)) => result.unwrap(),
+                _ = tokio::signal::ctrl_c() => log::info!("shutting down"),
+            }
+            audit_logger.flush();
+            if audit_logger.dropped() > 0 {
+                log::warn!("{} audit events were dropped because the queue was full", audit_logger.dropped());
+            }
         }
         Some(("auth", auth_matches)) => {
+            let auth = User::<GlobalDatabasePool>::new().unwrap();
             let pool = GlobalDatabasePool::get_pool();
-            let auth = User::<GlobalDatabasePool>::new_with_pool(pool);
             match auth_matches.subcommand() {
                 Some(("register", register_matches)) => {
                     let username = register_matches.get_one::<String>("username").unwrap();
                     let password = register_matches.get_one::<String>("password").unwrap();
                     auth.register(username, password).unwrap();
-                    
+                    if let Some(root) = register_matches.get_one::<String>("root") {
+                        let create = register_matches.get_flag("create-root");
+                        auth.set_root(username, Path::new(root), create).unwrap();
+                    }
                 }
                 Some(("update-password", update_password_matches)) => {
                     let username = update_password_matches
@@ -143,9 +600,394 @@ async fn main() {
                         .unwrap();
                     
                 }
+                Some(("set-root", set_root_matches)) => {
+                    let username = set_root_matches.get_one::<String>("username").unwrap();
+                    let root = set_root_matches.get_one::<String>("root").unwrap();
+                    let create = set_root_matches.get_flag("create");
+                    auth.set_root(username, Path::new(root), create).unwrap();
+                }
+                Some(("list", _)) => {
+                    for user in auth.list_users().unwrap() {
+                        println!(
+                            "{}\t{}\t{}\t{}\t{}",
+                            user.username,
+                            user.role,
+                            if user.disabled { "disabled" } else { "enabled" },
+                            user.created_at,
+                            user.last_login.as_deref().unwrap_or("never"),
+                        );
+                    }
+                }
+                Some(("delete", delete_matches)) => {
+                    let username = delete_matches.get_one::<String>("username").unwrap();
+                    auth.delete_user(username).unwrap();
+                }
+                Some(("disable", disable_matches)) => {
+                    let username = disable_matches.get_one::<String>("username").unwrap();
+                    auth.set_disabled(username, true).unwrap();
+                }
+                Some(("enable", enable_matches)) => {
+                    let username = enable_matches.get_one::<String>("username").unwrap();
+                    auth.set_disabled(username, false).unwrap();
+                }
+                Some(("set-role", set_role_matches)) => {
+                    let username = set_role_matches.get_one::<String>("username").unwrap();
+                    let role = set_role_matches.get_one::<String>("role").unwrap();
+                    auth.set_role(username, role).unwrap();
+                }
+                Some(("reset-password", reset_password_matches)) => {
+                    let username = reset_password_matches
+                        .get_one::<String>("username")
+                        .unwrap();
+                    let password = reset_password_matches
+                        .get_one::<String>("password")
+                        .unwrap();
+                    auth.reset_password(username, password).unwrap();
+                }
+                Some(("key", key_matches)) => {
+                    let conn = pool.get().unwrap();
+                    match key_matches.subcommand() {
+                        Some(("add", add_matches)) => {
+                            let username = add_matches.get_one::<String>("username").unwrap();
+                            let key = add_matches.get_one::<String>("key").unwrap();
+                            // 参数是已存在的文件时读取其中的公钥
+                            let line = match std::fs::read_to_string(key) {
+                                Ok(content) => content.trim().to_string(),
+                                Err(_) => key.clone(),
+                            };
+                            let (key, comment) = authorized_keys::parse_key_line(&line).unwrap();
+                            let comment = add_matches.get_one::<String>("comment").cloned().or(comment);
+                            let expires = add_matches
+                                .get_one::<String>("expires")
+                                .map(|expires| authorized_keys::parse_expiry(expires).unwrap());
+                            let id = authorized_keys::add_key(&conn, username, &key, comment.as_deref(), expires)
+                                .unwrap();
+                            println!("Added key {} ({})", id, key.fingerprint());
+                        }
+                        Some(("list", list_matches)) => {
+                            let username = list_matches.get_one::<String>("username").unwrap();
+                            for key in authorized_keys::list_keys(&conn, username).unwrap() {
+                                println!(
+                                    "{}\t{}\t{}\t{}\tadded: {}\texpires: {}",
+                                    key.id,
+                                    key.algorithm,
+                                    key.fingerprint,
+                                    key.comment.as_deref().unwrap_or("-"),
+                                    key.created_at,
+                                    key.expires_at.as_deref().unwrap_or("never")
+                                );
+                            }
+                        }
+                        Some(("revoke", revoke_matches)) => {
+                            let username = revoke_matches.get_one::<String>("username").unwrap();
+                            let id = revoke_matches.get_one::<i64>("id").unwrap();
+                            authorized_keys::revoke_key(&conn, username, *id).unwrap();
+                        }
+                        _ => {}
+                    }
+                }
+                Some(("totp", totp_matches)) => {
+                    let conn = pool.get().unwrap();
+                    match totp_matches.subcommand() {
+                        Some(("enroll", enroll_matches)) => {
+                            let username = enroll_matches.get_one::<String>("username").unwrap();
+                            let enrollment = totp::enroll(&conn, username).unwrap();
+                            println!("secret: {}", enrollment.secret);
+                            println!("uri: {}", enrollment.uri);
+                            if enroll_matches.get_flag("qr") {
+                                println!("{}", totp::render_qr(&enrollment.uri).unwrap());
+                            }
+                            println!("recovery codes (each can be used once):");
+                            for code in enrollment.recovery_codes {
+                                println!("  {}", code);
+                            }
+                        }
+                        Some(("disable", disable_matches)) => {
+                            let username = disable_matches.get_one::<String>("username").unwrap();
+                            totp::disable(&conn, username).unwrap();
+                        }
+                        Some(("recovery-codes", codes_matches)) => {
+                            let username = codes_matches.get_one::<String>("username").unwrap();
+                            match totp::regenerate_recovery_codes(&conn, username) {
+                                Ok(codes) => {
+                                    for code in codes {
+                                        println!("{}", code);
+                                    }
+                                }
+                                Err(err) => {
+                                    eprintln!("{}", err);
+                                    std::process::exit(1);
+                                }
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                _ => {}
+            }
+        }
+        Some(("hostkey", hostkey_matches)) => {
+            let store = hostkeys::HostKeyStore::default();
+            match hostkey_matches.subcommand() {
+                Some(("list", _)) => {
+                    let keys = store.list().unwrap();
+                    for key in &keys {
+                        let state = match key.retired_until {
+                            None => "current".to_string(),
+                            Some(until) if key.is_active() => format!(
+                                "retired, served until {}",
+                                chrono::DateTime::<chrono::Utc>::from(until).format("%Y-%m-%d %H:%M:%S UTC")
+                            ),
+                            Some(_) => "retired, expired".to_string(),
+                        };
+                        println!("{}\t{}\t{}\t{}", key.algorithm, key.fingerprint, key.path.display(), state);
+                    }
+                }
+                Some(("rotate", rotate_matches)) => {
+                    let key_type = rotate_matches
+                        .get_one::<String>("type")
+                        .unwrap()
+                        .parse()
+                        .unwrap();
+                    let days = rotate_matches.get_one::<u64>("grace-days").unwrap();
+                    let grace = Duration::from_secs(days * 24 * 60 * 60);
+                    let force = rotate_matches.get_flag("force");
+                    let result = match rotate_matches.get_one::<String>("replace") {
+                        Some(old) => {
+                            let old: hostkeys::KeyType = old.parse().unwrap();
+                            store.replace(old, key_type, grace, force).map(|key| {
+                                println!("New {} host key: {}", key.algorithm, key.fingerprint);
+                                println!("Set HOST_KEY_TYPES to include {} and no longer {} before restarting", key_type, old);
+                            })
+                        }
+                        None => store.rotate(key_type, force).map(|key| {
+                            println!("New {} host key: {}", key.algorithm, key.fingerprint);
+                            println!("Clients that pinned the old key will see a host key change warning");
+                        }),
+                    };
+                    if let Err(err) = result {
+                        eprintln!("{}", err);
+                        std::process::exit(1);
+                    }
+                }
                 _ => {}
             }
         }
+        Some(("ban", ban_matches)) => {
+            let pool = GlobalDatabasePool::get_pool();
+            let conn = pool.get().unwrap();
+            match ban_matches.subcommand() {
+                Some(("list", _)) => {
+                    for ban in lockout::list_bans(&conn).unwrap() {
+                        println!("{}\t{}\tuntil {} UTC\tlevel {}", ban.kind.name(), ban.subject, ban.banned_until, ban.level);
+                    }
+                }
+                Some(("clear", clear_matches)) => {
+                    let (kind, subject) = match clear_matches.get_one::<String>("user") {
+                        Some(username) => (lockout::BanKind::User, username.clone()),
+                        None => (
+                            lockout::BanKind::Address,
+                            clear_matches.get_one::<IpAddr>("addr").unwrap().to_string(),
+                        ),
+                    };
+                    if !lockout::clear_ban(&conn, kind, &subject).unwrap() {
+                        println!("{} {} is not banned", kind.name(), subject);
+                    }
+                }
+                _ => {}
+            }
+        }
+        Some(("audit", audit_matches)) => {
+            let pool = GlobalDatabasePool::get_pool();
+            let conn = pool.get().unwrap();
+            match audit_matches.subcommand() {
+                Some(("query", query_matches)) => {
+                    let format = query_matches.get_one::<String>("format").unwrap().as_str();
+                    let limit = query_matches
+                        .get_one::<u64>("limit")
+                        .copied()
+                        .or((format == "table").then_some(50));
+                    let page = query_matches.get_one::<u64>("page").unwrap();
+                    let time = |name: &str| {
+                        query_matches
+                            .get_one::<String>(name)
+                            .map(|time| audit::parse_time(time).unwrap())
+                    };
+                    let filter = audit::AuditFilter {
+                        username: query_matches.get_one::<String>("user").cloned(),
+                        actions: query_matches
+                            .get_many::<String>("action")
+                            .unwrap_or_default()
+                            .cloned()
+                            .collect(),
+                        path: query_matches.get_one::<String>("path").cloned(),
+                        since: time("since"),
+                        until: time("until"),
+                        limit,
+                        offset: limit.unwrap_or(0) * (page - 1),
+                    };
+                    let records = audit::query_audit_logs(&conn, &filter).unwrap();
+                    let mut output: Box<dyn Write> = match query_matches.get_one::<String>("output") {
+                        Some(path) => Box::new(std::fs::File::create(path).unwrap()),
+                        None => Box::new(std::io::stdout().lock()),
+                    };
+                    match format {
+                        "csv" => audit::write_csv(output, &records).unwrap(),
+                        "jsonl" => audit::write_jsonl(output, &records).unwrap(),
+                        _ => {
+                            for record in &records {
+                                let target = match &record.secondary_target {
+                                    Some(secondary) => format!("{} -> {}", record.target, secondary),
+                                    None => record.target.clone(),
+                                };
+                                let optional = |value: Option<i64>| {
+                                    value.map_or("-".to_string(), |value| value.to_string())
+                                };
+                                writeln!(
+                                    output,
+                                    "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                                    record.id,
+                                    record.created_at,
+                                    record.username,
+                                    record.client_addr.as_deref().unwrap_or("-"),
+                                    record.session_id.as_deref().unwrap_or("-"),
+                                    record.action,
+                                    record.result,
+                                    optional(record.bytes),
+                                    optional(record.duration_us),
+                                    target,
+                                )
+                                .unwrap();
+                            }
+                        }
+                    }
+                }
+                Some(("verify", _)) => {
+                    let report = audit::verify_chain(&conn).unwrap();
+                    if report.legacy > 0 {
+                        println!("{} records written before hash chaining cannot be verified", report.legacy);
+                    }
+                    if let Some((id, false)) = report.first {
+                        println!("chain starts at record {}, earlier records were pruned", id);
+                    }
+                    if let Some((id, reason)) = report.broken {
+                        println!("record {} is inconsistent: {}", id, reason);
+                        println!("{} records verified before it", report.verified);
+                        std::process::exit(1);
+                    }
+                    println!("{} records verified", report.verified);
+                    if let Some((id, hash)) = report.last {
+                        println!("last record {}: {}", id, hash);
+                    }
+                }
+                Some(("prune", prune_matches)) => {
+                    let mut policy = retention::RetentionPolicy::from_env().unwrap();
+                    if let Some(days) = prune_matches.get_one::<u32>("days") {
+                        policy.days = *days;
+                    }
+                    if let Some(dir) = prune_matches.get_one::<String>("archive-dir") {
+                        policy.archive_dir = Some(dir.into());
+                    }
+                    if prune_matches.get_flag("no-archive") {
+                        policy.archive_dir = None;
+                    }
+                    let report = retention::prune(&conn, &policy, chrono::Utc::now().naive_utc()).unwrap();
+                    for file in &report.files {
+                        println!("archived to {}", file.display());
+                    }
+                    println!("{} records older than {} days deleted", report.deleted, policy.days);
+                }
+                _ => {}
+            }
+        }
+        Some(("acl", acl_matches)) => {
+            let pool = GlobalDatabasePool::get_pool();
+            let conn = pool.get().unwrap();
+            match acl_matches.subcommand() {
+                Some(("add", add_matches)) => {
+                    let user = add_matches.get_one::<String>("user").map(String::as_str);
+                    let role = add_matches.get_one::<String>("role").map(String::as_str);
+                    let path = add_matches.get_one::<String>("path").unwrap();
+                    let rights = add_matches
+                        .get_one::<String>("rights")
+                        .unwrap()
+                        .parse()
+                        .unwrap();
+                    let id = acl::add_rule(&conn, user, role, Path::new(path), rights).unwrap();
+                    println!("Added access rule {}", id);
+                }
+                Some(("list", _)) => {
+                    for rule in acl::list_rules(&conn).unwrap() {
+                        let subject = match (&rule.username, &rule.role) {
+                            (Some(username), _) => format!("user:{}", username),
+                            (_, Some(role)) => format!("role:{}", role),
+                            _ => "-".to_string(),
+                        };
+                        println!("{}\t{}\t{}\t{}", rule.id, subject, rule.path.display(), rule.rights);
+                    }
+                }
+                Some(("remove", remove_matches)) => {
+                    let id = remove_matches.get_one::<i64>("id").unwrap();
+                    acl::remove_rule(&conn, *id).unwrap();
+                }
+                _ => {}
+            }
+        }
+        Some(("quota", quota_matches)) => {
+            let pool = GlobalDatabasePool::get_pool();
+            let conn = pool.get().unwrap();
+            let auth = User::<GlobalDatabasePool>::new_with_pool(pool.clone());
+            match quota_matches.subcommand() {
+                Some(("set", set_matches)) => {
+                    let user = set_matches.get_one::<String>("user").map(String::as_str);
+                    let role = set_matches.get_one::<String>("role").map(String::as_str);
+                    let limits = quota::QuotaLimits {
+                        max_bytes: set_matches.get_one::<u64>("max-bytes").copied(),
+                        max_files: set_matches.get_one::<u64>("max-files").copied(),
+                    };
+                    quota::set_quota(&conn, user, role, limits).unwrap();
+                }
+                Some(("show", show_matches)) => {
+                    let username = show_matches.get_one::<String>("username").unwrap();
+                    let limits = quota::limits_for(&conn, username).unwrap();
+                    let usage = quota::get_usage(&conn, username).unwrap().unwrap_or_default();
+                    let limit = |max: Option<u64>| max.map_or("unlimited".to_string(), |max| max.to_string());
+                    println!("bytes: {} / {}", usage.bytes, limit(limits.max_bytes));
+                    println!("files: {} / {}", usage.files, limit(limits.max_files));
+                }
+                Some(("recompute", recompute_matches)) => {
+                    let username = recompute_matches.get_one::<String>("username").unwrap();
+                    let root = auth.get_root(username).unwrap();
+                    let usage = quota::recompute(&conn, username, root.as_deref()).unwrap();
+                    println!("bytes: {}, files: {}", usage.bytes, usage.files);
+                }
+                _ => {}
+            }
+        }
+        Some(("db", db_matches)) => {
+            // 不使用 GlobalDatabasePool，它在创建时就会执行迁移
+            let mut conn = rusqlite::Connection::open(database::database_path()).unwrap();
+            if let Some(("migrate", migrate_matches)) = db_matches.subcommand() {
+                if migrate_matches.get_flag("status") {
+                    for (migration, applied_at) in database::migration_status(&conn).unwrap() {
+                        println!(
+                            "{}\t{}\t{}",
+                            migration.version,
+                            applied_at.as_deref().unwrap_or("pending"),
+                            migration.description
+                        );
+                    }
+                } else {
+                    let applied = database::migrate(&mut conn).unwrap();
+                    for migration in &applied {
+                        println!("applied {}: {}", migration.version, migration.description);
+                    }
+                    if applied.is_empty() {
+                        println!("database is up to date");
+                    }
+                }
+            }
+        }
         _ => {}
     }
 }
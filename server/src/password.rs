@@ -0,0 +1,229 @@
+//! 密码哈希（argon2id，兼容旧的 bcrypt 哈希）和密码强度策略
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+
+use anyhow::{Context, Result};
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+
+/// 内置的常见弱密码，PASSWORD_DENYLIST 指定的文件会追加到这里
+const COMMON_PASSWORDS: &[&str] = &[
+    "123456", "12345678", "123456789", "1234567890", "password", "password1", "password123",
+    "qwerty", "qwerty123", "qwertyuiop", "abc123", "111111", "000000", "iloveyou", "admin",
+    "admin123", "admin_password", "welcome", "welcome1", "letmein", "monkey", "dragon",
+    "football", "baseball", "sunshine", "princess", "master", "123123", "1q2w3e4r", "passw0rd",
+    "p@ssw0rd", "changeme", "student", "teacher", "root", "toor",
+];
+
+/// 新密码使用的哈希算法，由 PASSWORD_HASH 设置
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashScheme {
+    Argon2id,
+    Bcrypt,
+}
+
+impl HashScheme {
+    pub fn from_env() -> Result<Self> {
+        match env::var("PASSWORD_HASH").as_deref() {
+            Err(_) | Ok("argon2id") => Ok(HashScheme::Argon2id),
+            Ok("bcrypt") => Ok(HashScheme::Bcrypt),
+            Ok(name) => Err(anyhow::anyhow!("Unknown password hash: {}", name)),
+        }
+    }
+
+    /// 哈希字符串使用的算法，无法识别时返回 None
+    fn of(hash: &str) -> Option<Self> {
+        if hash.starts_with("$argon2id$") {
+            Some(HashScheme::Argon2id)
+        } else if hash.starts_with("$2") {
+            Some(HashScheme::Bcrypt)
+        } else {
+            None
+        }
+    }
+}
+
+fn argon2() -> Argon2<'static> {
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, Params::default())
+}
+
+pub fn hash_password(password: &str) -> Result<String> {
+    match HashScheme::from_env()? {
+        HashScheme::Argon2id => {
+            let salt = SaltString::generate(&mut OsRng);
+            let hash = argon2()
+                .hash_password(password.as_bytes(), &salt)
+                .map_err(|err| anyhow::anyhow!("Failed to hash password: {}", err))?;
+            Ok(hash.to_string())
+        }
+        HashScheme::Bcrypt => Ok(bcrypt::hash(password, bcrypt::DEFAULT_COST)?),
+    }
+}
+
+pub fn verify_password(password: &str, hash: &str) -> Result<bool> {
+    match HashScheme::of(hash) {
+        Some(HashScheme::Argon2id) => {
+            let hash = PasswordHash::new(hash)
+                .map_err(|err| anyhow::anyhow!("Invalid argon2 hash: {}", err))?;
+            Ok(argon2().verify_password(password.as_bytes(), &hash).is_ok())
+        }
+        Some(HashScheme::Bcrypt) => Ok(bcrypt::verify(password, hash)?),
+        None => Err(anyhow::anyhow!("Unknown password hash format")),
+    }
+}
+
+/// 哈希是否应该用当前的算法和参数重新计算，用于登录时透明升级
+pub fn needs_rehash(hash: &str) -> bool {
+    let Ok(scheme) = HashScheme::from_env() else {
+        return false;
+    };
+    match (HashScheme::of(hash), scheme) {
+        (Some(HashScheme::Argon2id), HashScheme::Argon2id) => PasswordHash::new(hash)
+            .ok()
+            .and_then(|hash| Params::try_from(&hash).ok())
+            .is_none_or(|params| {
+                let current = Params::default();
+                (params.m_cost(), params.t_cost(), params.p_cost())
+                    != (current.m_cost(), current.t_cost(), current.p_cost())
+            }),
+        (Some(current), scheme) => current != scheme,
+        (None, _) => true,
+    }
+}
+
+/// 密码强度要求
+#[derive(Debug, Clone)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    /// 小写字母、大写字母、数字、符号四类中至少包含几类
+    pub min_classes: usize,
+    pub denylist: HashSet<String>,
+}
+
+impl PasswordPolicy {
+    /// 从环境变量读取：PASSWORD_MIN_LENGTH、PASSWORD_MIN_CLASSES、PASSWORD_DENYLIST（每行一个密码的文件）
+    pub fn from_env() -> Result<Self> {
+        let var = |name: &str, default: usize| -> Result<usize> {
+            match env::var(name) {
+                Ok(value) => value
+                    .parse()
+                    .with_context(|| format!("Invalid {}: {}", name, value)),
+                Err(_) => Ok(default),
+            }
+        };
+        let mut policy = Self {
+            min_length: var("PASSWORD_MIN_LENGTH", 8)?,
+            min_classes: var("PASSWORD_MIN_CLASSES", 2)?,
+            ..Default::default()
+        };
+        if let Ok(path) = env::var("PASSWORD_DENYLIST") {
+            let content = fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read password denylist {}", path))?;
+            policy.denylist.extend(
+                content
+                    .lines()
+                    .map(|line| line.trim().to_lowercase())
+                    .filter(|line| !line.is_empty()),
+            );
+        }
+        Ok(policy)
+    }
+
+    /// 检查密码是否满足要求，不满足时返回的错误说明了全部原因
+    pub fn check(&self, username: &str, password: &str) -> Result<()> {
+        let mut problems = vec![];
+        if password.chars().count() < self.min_length {
+            problems.push(format!("must be at least {} characters long", self.min_length));
+        }
+        let classes = [
+            password.chars().any(|c| c.is_lowercase()),
+            password.chars().any(|c| c.is_uppercase()),
+            password.chars().any(|c| c.is_ascii_digit()),
+            password.chars().any(|c| !c.is_alphanumeric()),
+        ]
+        .iter()
+        .filter(|present| **present)
+        .count();
+        if classes < self.min_classes {
+            problems.push(format!(
+                "must contain at least {} of: lowercase letters, uppercase letters, digits, symbols",
+                self.min_classes
+            ));
+        }
+        let lowercase = password.to_lowercase();
+        if self.denylist.contains(&lowercase) {
+            problems.push("is too common".to_string());
+        }
+        if !username.is_empty() && lowercase.contains(&username.to_lowercase()) {
+            problems.push("must not contain the username".to_string());
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!("Password {}", problems.join(", ")))
+        }
+    }
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        Self {
+            min_length: 8,
+            min_classes: 2,
+            denylist: COMMON_PASSWORDS.iter().map(|password| password.to_string()).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policy() {
+        let policy = PasswordPolicy::default();
+        assert!(policy.check("alice", "Correct-Horse-9").is_ok());
+        assert!(policy.check("alice", "").is_err());
+        assert!(policy.check("alice", "short1").is_err());
+        assert!(policy.check("alice", "alllowercase").is_err());
+        assert!(policy.check("alice", "P@ssw0rd").is_err());
+        assert!(policy.check("alice", "Alice-2024!").is_err());
+
+        let err = policy.check("alice", "abc").unwrap_err().to_string();
+        assert!(err.contains("at least 8 characters"), "{}", err);
+        assert!(err.contains("lowercase letters"), "{}", err);
+
+        let lenient = PasswordPolicy {
+            min_length: 1,
+            min_classes: 0,
+            denylist: HashSet::new(),
+        };
+        assert!(lenient.check("alice", "x").is_ok());
+    }
+
+    #[test]
+    fn test_hash_and_verify() {
+        let hash = hash_password("Correct-Horse-9").unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+        assert!(verify_password("Correct-Horse-9", &hash).unwrap());
+        assert!(!verify_password("wrong", &hash).unwrap());
+        assert!(!needs_rehash(&hash));
+
+        // 旧的 bcrypt 哈希仍然可以验证，但需要升级
+        let bcrypt_hash = bcrypt::hash("Correct-Horse-9", 4).unwrap();
+        assert!(verify_password("Correct-Horse-9", &bcrypt_hash).unwrap());
+        assert!(needs_rehash(&bcrypt_hash));
+
+        // 参数与当前默认值不同的 argon2 哈希也需要升级
+        let weak = Argon2::new(Algorithm::Argon2id, Version::V0x13, Params::new(8, 1, 1, None).unwrap())
+            .hash_password(b"Correct-Horse-9", &SaltString::generate(&mut OsRng))
+            .unwrap()
+            .to_string();
+        assert!(verify_password("Correct-Horse-9", &weak).unwrap());
+        assert!(needs_rehash(&weak));
+
+        assert!(verify_password("x", "plaintext").is_err());
+    }
+}
@@ -0,0 +1,337 @@
+//! 按用户或角色限制磁盘用量（字节数和文件数）
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Result;
+use log::warn;
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{params, Connection, OptionalExtension};
+
+/// 配额上限，None 表示不限制
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QuotaLimits {
+    pub max_bytes: Option<u64>,
+    pub max_files: Option<u64>,
+}
+
+/// 用户根目录下已经使用的字节数和文件数（目录和符号链接也算作文件）
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub bytes: u64,
+    pub files: u64,
+}
+
+/// 一次 SFTP 会话中的配额检查。用量保存在数据库中，同一用户的多个会话共享
+#[derive(Default)]
+pub struct Quota {
+    username: String,
+    limits: QuotaLimits,
+    // 没有数据库时（例如测试中，或用户共用根目录时）不限制也不记录用量
+    pool: Option<Arc<Pool<SqliteConnectionManager>>>,
+}
+
+impl Quota {
+    /// 读取用户的配额；数据库中还没有用量记录时先统计一次根目录。
+    /// root 为 None 表示用户没有自己的根目录、和其他用户共用 VIRTUAL_ROOT_PATH。
+    /// 共用目录中的文件无法确定算在谁的用量中（删除别人的文件会归还到自己头上），
+    /// 因此不记录用量；对这样的用户设置了配额时返回错误，而不是错误地统计
+    pub fn load(
+        pool: Arc<Pool<SqliteConnectionManager>>,
+        username: &str,
+        root: Option<&Path>,
+    ) -> Result<Self> {
+        let conn = pool.get()?;
+        let limits = limits_for(&conn, username)?;
+        let Some(root) = root else {
+            if limits != QuotaLimits::default() {
+                return Err(anyhow::anyhow!(
+                    "{} shares VIRTUAL_ROOT_PATH with other users, a quota can only be enforced in a root of their own (auth set-root)",
+                    username
+                ));
+            }
+            return Ok(Self {
+                username: username.to_string(),
+                limits,
+                pool: None,
+            });
+        };
+        if get_usage(&conn, username)?.is_none() {
+            recompute(&conn, username, Some(root))?;
+        }
+        drop(conn);
+        Ok(Self {
+            username: username.to_string(),
+            limits,
+            pool: Some(pool),
+        })
+    }
+
+    /// 在修改文件系统之前占用 bytes 字节和 files 个文件，超出配额时返回 EDQUOT。
+    /// 检查和增加用量在同一条 UPDATE 中完成，同一用户的多个会话不会一起超出配额；
+    /// 操作失败时用 release 归还
+    pub fn reserve(&self, bytes: u64, files: u64) -> io::Result<()> {
+        let Some(pool) = &self.pool else {
+            return Ok(());
+        };
+        if bytes == 0 && files == 0 {
+            return Ok(());
+        }
+        // 超出 i64 的值写入数据库后会变成负数，反而减少用量
+        let too_large = |_| io::Error::new(io::ErrorKind::InvalidInput, "File would be too large");
+        let bytes = i64::try_from(bytes).map_err(too_large)?;
+        let files = i64::try_from(files).map_err(too_large)?;
+        let conn = pool.get().map_err(io::Error::other)?;
+        // 只检查这次会增加的那一项，已经超出的另一项不影响
+        let changed = conn
+            .execute(
+                "UPDATE QuotaUsage SET bytes = bytes + ?1, files = files + ?2, updated_at = CURRENT_TIMESTAMP
+                    WHERE username = ?3
+                        AND (?1 = 0 OR ?4 IS NULL OR bytes + ?1 <= ?4)
+                        AND (?2 = 0 OR ?5 IS NULL OR files + ?2 <= ?5)",
+                params![
+                    bytes,
+                    files,
+                    self.username,
+                    self.limits.max_bytes.map(|max| max.min(i64::MAX as u64) as i64),
+                    self.limits.max_files.map(|max| max.min(i64::MAX as u64) as i64),
+                ],
+            )
+            .map_err(io::Error::other)?;
+        // 没有用量记录（例如用户已被删除）且不限制时照常进行
+        if changed == 0 && self.limits != QuotaLimits::default() {
+            return Err(io::Error::from_raw_os_error(libc::EDQUOT));
+        }
+        Ok(())
+    }
+
+    /// 归还 reserve 占用但没有用到的配额
+    pub fn release(&self, bytes: u64, files: u64) {
+        let negate = |value: u64| -i64::try_from(value).unwrap_or(i64::MAX);
+        self.record(negate(bytes), negate(files));
+    }
+
+    /// 操作成功后更新用量，参数可以为负
+    pub fn record(&self, bytes: i64, files: i64) {
+        let Some(pool) = &self.pool else {
+            return;
+        };
+        if bytes == 0 && files == 0 {
+            return;
+        }
+        let result = pool.get().map_err(anyhow::Error::from).and_then(|conn| {
+            conn.execute(
+                "UPDATE QuotaUsage SET bytes = MAX(bytes + ?1, 0), files = MAX(files + ?2, 0),
+                    updated_at = CURRENT_TIMESTAMP WHERE username = ?3",
+                params![bytes, files, self.username],
+            )?;
+            Ok(())
+        });
+        if let Err(err) = result {
+            warn!("failed to update quota usage of {}: {}", self.username, err);
+        }
+    }
+}
+
+/// 用户的配额：用户自己的配额优先，其次是其角色的配额
+pub fn limits_for(conn: &Connection, username: &str) -> Result<QuotaLimits> {
+    let limits = conn
+        .query_row(
+            "SELECT q.max_bytes, q.max_files FROM Quotas q JOIN Users u
+                ON q.username = u.username OR q.role = u.role
+             WHERE u.username = ?
+             ORDER BY q.username IS NULL
+             LIMIT 1",
+            params![username],
+            |row| {
+                Ok(QuotaLimits {
+                    max_bytes: row.get::<_, Option<i64>>(0)?.map(|max| max as u64),
+                    max_files: row.get::<_, Option<i64>>(1)?.map(|max| max as u64),
+                })
+            },
+        )
+        .optional()?;
+    Ok(limits.unwrap_or_default())
+}
+
+/// 设置用户或角色的配额，username 和 role 必须且只能给出一个
+pub fn set_quota(
+    conn: &Connection,
+    username: Option<&str>,
+    role: Option<&str>,
+    limits: QuotaLimits,
+) -> Result<()> {
+    if username.is_some() == role.is_some() {
+        return Err(anyhow::anyhow!("A quota applies to either a user or a role"));
+    }
+    conn.execute(
+        "DELETE FROM Quotas WHERE username IS ?1 AND role IS ?2",
+        params![username, role],
+    )?;
+    conn.execute(
+        "INSERT INTO Quotas (username, role, max_bytes, max_files) VALUES (?, ?, ?, ?)",
+        params![
+            username,
+            role,
+            limits.max_bytes.map(|max| max as i64),
+            limits.max_files.map(|max| max as i64)
+        ],
+    )?;
+    Ok(())
+}
+
+pub fn get_usage(conn: &Connection, username: &str) -> Result<Option<Usage>> {
+    let usage = conn
+        .query_row(
+            "SELECT bytes, files FROM QuotaUsage WHERE username = ?",
+            params![username],
+            |row| {
+                Ok(Usage {
+                    bytes: row.get::<_, i64>(0)? as u64,
+                    files: row.get::<_, i64>(1)? as u64,
+                })
+            },
+        )
+        .optional()?;
+    Ok(usage)
+}
+
+/// 重新统计用户根目录的用量并保存，用于修正增量记录的偏差。
+/// 没有自己的根目录的用户无法统计，否则整个共用的目录都会算到这个用户头上
+pub fn recompute(conn: &Connection, username: &str, root: Option<&Path>) -> Result<Usage> {
+    let Some(root) = root else {
+        return Err(anyhow::anyhow!(
+            "{} has no root directory of their own, usage in the shared VIRTUAL_ROOT_PATH cannot be recomputed",
+            username
+        ));
+    };
+    let usage = measure(root)?;
+    conn.execute(
+        "INSERT OR REPLACE INTO QuotaUsage (username, bytes, files, updated_at)
+            VALUES (?, ?, ?, CURRENT_TIMESTAMP)",
+        params![username, usage.bytes as i64, usage.files as i64],
+    )?;
+    Ok(usage)
+}
+
+/// 统计目录下所有条目的数量和普通文件的总大小，不跟随符号链接。
+/// 每个硬链接都算作一个文件，但同一个文件的字节只计算一次
+pub fn measure(root: &Path) -> io::Result<Usage> {
+    let mut usage = Usage::default();
+    let mut linked = HashSet::new();
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            usage.files += 1;
+            if metadata.is_dir() {
+                pending.push(entry.path());
+            } else if metadata.is_file()
+                && (metadata.nlink() == 1 || linked.insert((metadata.dev(), metadata.ino())))
+            {
+                usage.bytes += metadata.len();
+            }
+        }
+    }
+    Ok(usage)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{DatabasePool, MockDatabasePool};
+
+    #[test]
+    fn test_measure() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("a.txt"), b"12345").unwrap();
+        fs::write(dir.path().join("sub/b.txt"), b"123").unwrap();
+        std::os::unix::fs::symlink("/etc", dir.path().join("link")).unwrap();
+        fs::hard_link(dir.path().join("a.txt"), dir.path().join("sub/a.txt")).unwrap();
+        assert_eq!(measure(dir.path()).unwrap(), Usage { bytes: 8, files: 5 });
+    }
+
+    #[test]
+    fn test_quota() {
+        let pool = MockDatabasePool::get_pool();
+        let conn = pool.get().unwrap();
+        conn.execute(
+            "INSERT INTO Users (username, password, role) VALUES ('student', '', 'user')",
+            params![],
+        )
+        .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), b"12345").unwrap();
+
+        let role_limits = QuotaLimits {
+            max_bytes: Some(100),
+            max_files: None,
+        };
+        set_quota(&conn, None, Some("user"), role_limits).unwrap();
+        assert_eq!(limits_for(&conn, "student").unwrap(), role_limits);
+        let user_limits = QuotaLimits {
+            max_bytes: Some(10),
+            max_files: Some(2),
+        };
+        set_quota(&conn, Some("student"), None, user_limits).unwrap();
+        assert_eq!(limits_for(&conn, "student").unwrap(), user_limits);
+        assert_eq!(limits_for(&conn, "admin").unwrap(), QuotaLimits::default());
+        assert!(set_quota(&conn, None, None, user_limits).is_err());
+
+        let quota = Quota::load(pool.clone(), "student", Some(dir.path())).unwrap();
+        assert_eq!(get_usage(&conn, "student").unwrap(), Some(Usage { bytes: 5, files: 1 }));
+        let err = quota.reserve(6, 0).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EDQUOT));
+        assert!(quota.reserve(0, 2).is_err());
+        assert!(quota.reserve(u64::MAX, 0).is_err());
+        // 失败的 reserve 不占用配额
+        assert_eq!(get_usage(&conn, "student").unwrap(), Some(Usage { bytes: 5, files: 1 }));
+        // 只减少用量的操作不受限制
+        assert!(quota.reserve(0, 0).is_ok());
+
+        quota.reserve(5, 1).unwrap();
+        assert_eq!(get_usage(&conn, "student").unwrap(), Some(Usage { bytes: 10, files: 2 }));
+        assert!(quota.reserve(1, 0).is_err());
+        quota.release(2, 0);
+        assert_eq!(get_usage(&conn, "student").unwrap(), Some(Usage { bytes: 8, files: 2 }));
+        quota.record(-20, -1);
+        assert_eq!(get_usage(&conn, "student").unwrap(), Some(Usage { bytes: 0, files: 1 }));
+
+        assert_eq!(
+            recompute(&conn, "student", Some(dir.path())).unwrap(),
+            Usage { bytes: 5, files: 1 }
+        );
+    }
+
+    #[test]
+    fn test_shared_root() {
+        let pool = MockDatabasePool::get_pool();
+        let conn = pool.get().unwrap();
+        conn.execute(
+            "INSERT INTO Users (username, password, role) VALUES ('student', '', 'user')",
+            params![],
+        )
+        .unwrap();
+
+        // 共用根目录且没有配额时不记录用量，删除其他用户的文件也不会改变自己的用量
+        let quota = Quota::load(pool.clone(), "student", None).unwrap();
+        quota.reserve(3, 1).unwrap();
+        quota.release(10, 1);
+        assert_eq!(get_usage(&conn, "student").unwrap(), None);
+        assert!(recompute(&conn, "student", None).is_err());
+
+        // 有配额时拒绝，而不是错误地统计
+        let limits = QuotaLimits {
+            max_bytes: Some(100),
+            max_files: None,
+        };
+        set_quota(&conn, None, Some("user"), limits).unwrap();
+        assert!(Quota::load(pool.clone(), "student", None).is_err());
+    }
+}
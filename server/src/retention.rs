@@ -0,0 +1,298 @@
+//! 审计日志的保留期限：超过期限的记录先归档到按天分开的压缩 JSON Lines 文件，再从数据库中删除
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{params, Connection};
+use serde::Serialize;
+
+use crate::audit::{AuditRecord, TIMESTAMP_FORMAT};
+
+/// 每次归档和删除的记录数，删除在各自的语句中完成，不会长时间占用数据库
+const BATCH_SIZE: i64 = 1000;
+
+/// 审计日志的保留策略
+#[derive(Debug, Clone)]
+pub struct RetentionPolicy {
+    /// 数据库中保留最近多少天的记录
+    pub days: u32,
+    /// 归档目录，为 None 时直接删除
+    pub archive_dir: Option<PathBuf>,
+    /// 后台清理的间隔，为 None 时只能用 `audit prune` 手动清理
+    pub interval: Option<Duration>,
+}
+
+impl RetentionPolicy {
+    /// 从环境变量读取：AUDIT_RETENTION_DAYS、AUDIT_ARCHIVE_DIR（为空时不归档）
+    /// 和 AUDIT_PRUNE_INTERVAL（小时）
+    pub fn from_env() -> Result<Self> {
+        let var = |name: &str| -> Result<Option<u32>> {
+            match env::var(name) {
+                Ok(value) => value
+                    .parse()
+                    .map(Some)
+                    .with_context(|| format!("Invalid {}: {}", name, value)),
+                Err(_) => Ok(None),
+            }
+        };
+        let defaults = Self::default();
+        let archive_dir = match env::var("AUDIT_ARCHIVE_DIR") {
+            Ok(dir) if dir.is_empty() => None,
+            Ok(dir) => Some(PathBuf::from(dir)),
+            Err(_) => defaults.archive_dir,
+        };
+        Ok(Self {
+            days: var("AUDIT_RETENTION_DAYS")?.unwrap_or(defaults.days),
+            archive_dir,
+            interval: var("AUDIT_PRUNE_INTERVAL")?
+                .filter(|hours| *hours > 0)
+                .map(|hours| Duration::from_secs(u64::from(hours) * 3600)),
+        })
+    }
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        Self {
+            days: 90,
+            archive_dir: Some(PathBuf::from("audit_archive")),
+            interval: None,
+        }
+    }
+}
+
+/// `audit prune` 的结果
+#[derive(Debug, Default)]
+pub struct PruneReport {
+    pub deleted: u64,
+    /// 写入过的归档文件
+    pub files: Vec<PathBuf>,
+}
+
+/// 归档文件中的一条记录，带上哈希，归档后仍然可以检查
+#[derive(Serialize)]
+struct ArchivedRecord {
+    #[serde(flatten)]
+    record: AuditRecord,
+    prev_hash: Option<String>,
+    entry_hash: Option<String>,
+}
+
+/// 删除 now 之前 policy.days 天以前的记录，归档时追加到 archive_dir/audit-YYYY-MM-DD.jsonl.gz。
+/// 只删除开头连续的一段旧记录，第一条未过期记录之后的记录都保留；最后一条记录也总是保留，
+/// 新记录会接在它后面，剩下的哈希链仍然是完整的。
+/// 归档文件先写完再删除记录，中途失败时下次会重新归档，文件中可能出现 id 相同的重复记录
+pub fn prune(conn: &Connection, policy: &RetentionPolicy, now: NaiveDateTime) -> Result<PruneReport> {
+    let mut report = PruneReport::default();
+    // 保留天数超出日期范围时没有记录会过期
+    let Some(cutoff) = now.checked_sub_signed(TimeDelta::days(i64::from(policy.days))) else {
+        return Ok(report);
+    };
+    let cutoff = cutoff.format(TIMESTAMP_FORMAT).to_string();
+    let boundary: Option<i64> = conn.query_row(
+        "SELECT COALESCE((SELECT MIN(log_id) FROM AuditLogs WHERE created_at >= ?1),
+            (SELECT MAX(log_id) FROM AuditLogs))",
+        params![cutoff],
+        |row| row.get(0),
+    )?;
+    let Some(boundary) = boundary else {
+        return Ok(report);
+    };
+    if let Some(dir) = &policy.archive_dir {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create archive directory {}", dir.display()))?;
+    }
+
+    let mut stmt = conn.prepare(
+        "SELECT log_id, username, action, target, result, session_id, client_addr,
+            secondary_target, bytes, duration_us, created_at, prev_hash, entry_hash
+        FROM AuditLogs WHERE log_id < ?1 ORDER BY log_id LIMIT ?2",
+    )?;
+    loop {
+        let records = stmt
+            .query_map(params![boundary, BATCH_SIZE], |row| {
+                Ok(ArchivedRecord {
+                    record: AuditRecord {
+                        id: row.get(0)?,
+                        username: row.get(1)?,
+                        action: row.get(2)?,
+                        target: row.get(3)?,
+                        result: row.get(4)?,
+                        session_id: row.get(5)?,
+                        client_addr: row.get(6)?,
+                        secondary_target: row.get(7)?,
+                        bytes: row.get(8)?,
+                        duration_us: row.get(9)?,
+                        created_at: row.get(10)?,
+                    },
+                    prev_hash: row.get(11)?,
+                    entry_hash: row.get(12)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        let Some(last) = records.last().map(|archived| archived.record.id) else {
+            break;
+        };
+        if let Some(dir) = &policy.archive_dir {
+            // 按记录的日期分组，created_at 的前 10 个字符是 YYYY-MM-DD
+            let mut days: BTreeMap<&str, Vec<&ArchivedRecord>> = BTreeMap::new();
+            for archived in &records {
+                let day = archived.record.created_at.get(..10).unwrap_or("unknown");
+                days.entry(day).or_default().push(archived);
+            }
+            for (day, records) in days {
+                let path = dir.join(format!("audit-{}.jsonl.gz", day));
+                append_gzip_jsonl(&path, &records)
+                    .with_context(|| format!("Failed to archive to {}", path.display()))?;
+                if !report.files.contains(&path) {
+                    report.files.push(path);
+                }
+            }
+        }
+        report.deleted += conn.execute("DELETE FROM AuditLogs WHERE log_id <= ?1", params![last])? as u64;
+    }
+    Ok(report)
+}
+
+/// 在文件末尾追加一个 gzip 成员，zcat 和 flate2 的 MultiGzDecoder 会读出全部成员
+fn append_gzip_jsonl(path: &Path, records: &[&ArchivedRecord]) -> Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let mut encoder = GzEncoder::new(file, Compression::default());
+    for record in records {
+        serde_json::to_writer(&mut encoder, record)?;
+        encoder.write_all(b"\n")?;
+    }
+    let file = encoder.finish()?;
+    // 删除记录之前确认归档已经写到磁盘上
+    file.sync_all()?;
+    Ok(())
+}
+
+/// 启动后台线程，每隔 policy.interval 清理一次；没有设置间隔时不启动
+pub fn spawn(pool: Arc<Pool<SqliteConnectionManager>>, policy: RetentionPolicy) {
+    let Some(interval) = policy.interval else {
+        return;
+    };
+    thread::Builder::new()
+        .name("audit-prune".to_string())
+        .spawn(move || loop {
+            let result = pool
+                .get()
+                .context("Failed to get connection from pool")
+                .and_then(|conn| prune(&conn, &policy, Utc::now().naive_utc()));
+            match result {
+                Ok(report) if report.deleted > 0 => {
+                    log::info!("pruned {} audit records older than {} days", report.deleted, policy.days)
+                }
+                Ok(_) => {}
+                Err(err) => log::warn!("Failed to prune audit logs: {:#}", err),
+            }
+            thread::sleep(interval);
+        })
+        .expect("Failed to start audit prune thread");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader};
+
+    use flate2::read::MultiGzDecoder;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+    use crate::audit::{verify_chain, DatabaseLogger};
+    use crate::database::{DatabasePool, MockDatabasePool};
+
+    fn archived_ids(path: &Path) -> Vec<i64> {
+        let reader = BufReader::new(MultiGzDecoder::new(fs::File::open(path).unwrap()));
+        reader
+            .lines()
+            .map(|line| {
+                let value: serde_json::Value = serde_json::from_str(&line.unwrap()).unwrap();
+                value["id"].as_i64().unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_prune() {
+        let pool = MockDatabasePool::get_pool();
+        let conn = pool.get().expect("Failed to get connection from pool");
+        // 4 是新记录，之后的 5 虽然过期了也要保留，保证留下的记录是连续的
+        for created_at in ["2024-01-01 10:00:00", "2024-01-01 11:00:00", "2024-01-02 09:00:00"] {
+            conn.execute(
+                "INSERT INTO AuditLogs (username, action, target, created_at) VALUES ('admin', 'Read', 'old.txt', ?1)",
+                params![created_at],
+            )
+            .unwrap();
+        }
+        conn.execute("INSERT INTO AuditLogs (username, action, target) VALUES ('admin', 'Read', 'new.txt')", params![])
+            .unwrap();
+        conn.execute(
+            "INSERT INTO AuditLogs (username, action, target, created_at) VALUES ('admin', 'Read', 'old.txt', '2024-01-01 12:00:00')",
+            params![],
+        )
+        .unwrap();
+        drop(conn);
+
+        let logger = DatabaseLogger::new(pool.clone());
+        let subscriber = tracing_subscriber::Registry::default().with(logger.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            for target in ["a.txt", "b.txt"] {
+                tracing::info!(username = "admin", action = "Write", target = target, "test log");
+            }
+        });
+        logger.flush();
+
+        let dir = tempfile::tempdir().unwrap();
+        let policy = RetentionPolicy {
+            days: 30,
+            archive_dir: Some(dir.path().join("archive")),
+            interval: None,
+        };
+        let conn = pool.get().expect("Failed to get connection from pool");
+        let now = Utc::now().naive_utc();
+        let report = prune(&conn, &policy, now).unwrap();
+        assert_eq!(report.deleted, 3);
+        let first_day = dir.path().join("archive/audit-2024-01-01.jsonl.gz");
+        let second_day = dir.path().join("archive/audit-2024-01-02.jsonl.gz");
+        assert_eq!(report.files, [first_day.clone(), second_day.clone()]);
+        assert_eq!(archived_ids(&first_day), [1, 2]);
+        assert_eq!(archived_ids(&second_day), [3]);
+        assert_eq!(prune(&conn, &policy, now).unwrap().deleted, 0);
+        let forever = RetentionPolicy {
+            days: u32::MAX,
+            ..policy.clone()
+        };
+        assert_eq!(prune(&conn, &forever, now).unwrap().deleted, 0);
+
+        // 全部过期后，5 追加到已有的归档文件中，最后一条记录 7 保留
+        let report = prune(&conn, &policy, now + TimeDelta::days(31)).unwrap();
+        assert_eq!(report.deleted, 3);
+        assert_eq!(archived_ids(&first_day), [1, 2, 5]);
+        drop(conn);
+
+        // 新记录接在 7 后面，哈希链仍然可以检查
+        let subscriber = tracing_subscriber::Registry::default().with(logger.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(username = "admin", action = "Write", target = "c.txt", "test log");
+        });
+        logger.flush();
+        let conn = pool.get().expect("Failed to get connection from pool");
+        let report = verify_chain(&conn).unwrap();
+        assert_eq!((report.verified, report.legacy), (2, 0));
+        assert_eq!(report.first, Some((7, false)));
+        assert!(report.broken.is_none());
+    }
+}
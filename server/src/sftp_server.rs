@@ -1,23 +1,45 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
-use std::io::{Read, Seek, SeekFrom, Write};
-use std::net::SocketAddr;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::fs;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::time::Instant;
 
 use async_trait::async_trait;
-use log::error;
-use russh::server::{Auth, Msg, Session};
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use log::{error, warn};
+use russh::server::{Auth, Msg, Response, Session};
 use russh::{Channel, ChannelId, MethodSet};
+use russh_keys::key::PublicKey;
 use russh_sftp::protocol::{
-    Attrs, Data, File, FileAttributes, Handle, Name, OpenFlags, Status, StatusCode, Version
+    Attrs, Data, File, FileAttributes, Handle, Name, OpenFlags, Packet, Status, StatusCode,
+    Version,
 };
 use tokio::sync::Mutex;
 use tracing::info;
 
+use crate::acl::{Acl, Right};
+use crate::audit::AUDIT_TARGET;
 use crate::auth::{Auther, User};
 use crate::database::DatabasePool;
-use crate::fs::{format_file_info, get_file_file_attributes, VirtualRoot};
+use crate::checksum::{hash_range, HashAlgorithm};
+use crate::extensions::{
+    self, CheckFileHandleExtension, CheckFileNameExtension, CheckFileReply, CopyDataExtension,
+    FstatvfsExtension, FsyncExtension, HardlinkExtension, LimitsExtension, LsetstatExtension,
+    PosixRenameExtension, StatvfsExtension,
+};
+use crate::handles::{HandleTable, OpenHandle};
+use crate::lockout::{LockoutPolicy, LoginGuard};
+use crate::quota::Quota;
+use crate::fs::{
+    copy_range, format_file_info, fstatvfs, get_file_file_attributes, open_nofollow,
+    read_dir_nofollow, remove_file_nofollow, rename_noreplace, set_file_attributes,
+    set_path_attributes, set_symlink_attributes, statvfs, VirtualRoot,
+};
 
 #[derive(Clone)]
 pub struct Server<P: DatabasePool + 'static> {
@@ -28,21 +50,37 @@ pub struct Server<P: DatabasePool + 'static> {
 impl<P: DatabasePool> russh::server::Server for Server<P> {
     type Handler = SshSession<P>;
 
-    fn new_client(&mut self, _: Option<SocketAddr>) -> Self::Handler {
-        SshSession::<P>::default()
+    fn new_client(&mut self, addr: Option<SocketAddr>) -> Self::Handler {
+        SshSession::<P> {
+            client_addr: addr,
+            ..Default::default()
+        }
     }
 }
 
 pub struct SshSession<P: DatabasePool> {
     clients: Arc<Mutex<HashMap<ChannelId, Channel<Msg>>>>,
     auther: User<P>,
+    pool: Arc<Pool<SqliteConnectionManager>>,
+    // 密码或公钥已经通过、还在等待 TOTP 验证码的用户，以及第一步的认证方式
+    pending_second_factor: Option<(String, &'static str)>,
+    client_addr: Option<SocketAddr>,
+    // 每个 SSH 连接一个随机 id，登录和文件操作的审计记录都带有它
+    session_id: String,
+    guard: LoginGuard,
 }
 
 impl<P: DatabasePool> Default for SshSession<P> {
     fn default() -> Self {
+        let pool = P::get_pool();
         Self {
             clients: Arc::new(Mutex::new(HashMap::new())),
-            auther: User::new_with_pool(P::get_pool()),
+            auther: User::new_with_pool(pool.clone()),
+            guard: LoginGuard::new(pool.clone(), LockoutPolicy::default()),
+            pool,
+            pending_second_factor: None,
+            client_addr: None,
+            session_id: format!("{:016x}", rand::random::<u64>()),
         }
     }
 }
@@ -52,6 +90,83 @@ impl<P: DatabasePool> SshSession<P> {
         let mut clients = self.clients.lock().await;
         clients.remove(&channel_id).unwrap()
     }
+
+    fn client_ip(&self) -> Option<IpAddr> {
+        self.client_addr.map(|addr| addr.ip())
+    }
+
+    /// 登录结果写入审计日志，target 为认证方式
+    fn audit_login(&self, user: &str, method: &str, result: &str) {
+        let client_addr = self.client_addr.map(|addr| addr.to_string());
+        info!(target: AUDIT_TARGET, username = user, action = "Login", target = method, result = result, session_id = self.session_id.as_str(), client_addr = client_addr.as_deref(), "Login attempt");
+    }
+
+    /// 账号或来源地址被封禁时拒绝登录，不再检查凭据。无法查询封禁记录时同样拒绝
+    fn is_banned(&self, user: &str, method: &str) -> bool {
+        match self.guard.banned(user, self.client_ip()) {
+            Ok(Some(ban)) => {
+                warn!("login of {} from {:?} rejected: {}", user, self.client_addr, ban);
+                self.audit_login(user, method, "Banned");
+                true
+            }
+            Ok(None) => false,
+            Err(err) => {
+                error!("cannot check bans of {}, rejecting the login: {}", user, err);
+                self.audit_login(user, method, "Error");
+                true
+            }
+        }
+    }
+
+    fn login_failed(&self, user: &str, method: &str) {
+        self.audit_login(user, method, "InvalidCredentials");
+        match self.guard.record_failure(user, self.client_ip(), method) {
+            Ok(bans) => bans.iter().for_each(|ban| warn!("too many failed logins: {}", ban)),
+            Err(err) => error!("cannot record failed login of {}: {}", user, err),
+        }
+    }
+
+    fn login_succeeded(&self, user: &str, method: &str) {
+        self.audit_login(user, method, "Ok");
+        if let Err(err) = self.guard.record_success(user, self.client_ip(), method) {
+            error!("cannot record login of {}: {}", user, err);
+        }
+    }
+
+    /// 第一步认证通过后，登记了两步验证的用户还需要通过 keyboard-interactive 输入验证码
+    fn first_factor_passed(&mut self, user: &str, method: &'static str) -> Auth {
+        match self.auther.is_totp_enrolled(user) {
+            Ok(false) => {
+                self.login_succeeded(user, method);
+                Auth::Accept
+            }
+            Ok(true) => {
+                self.pending_second_factor = Some((user.to_string(), method));
+                Auth::Reject {
+                    proceed_with_methods: Some(MethodSet::KEYBOARD_INTERACTIVE),
+                }
+            }
+            Err(err) => {
+                error!("cannot check two-factor enrollment of {}: {}", user, err);
+                reject()
+            }
+        }
+    }
+
+    /// 用户自己的根目录；没有设置时使用全局的 VIRTUAL_ROOT_PATH，第二项为 false
+    fn user_root(&self, username: &str) -> anyhow::Result<(VirtualRoot, bool)> {
+        match self.auther.get_root(username)? {
+            Some(root) => Ok((VirtualRoot::new(&root)?, true)),
+            None => Ok((VirtualRoot::default(), false)),
+        }
+    }
+}
+
+/// 认证失败后告诉客户端还可以尝试的方式
+fn reject() -> Auth {
+    Auth::Reject {
+        proceed_with_methods: Some(MethodSet::PASSWORD | MethodSet::PUBLICKEY),
+    }
 }
 
 #[async_trait]
@@ -59,12 +174,103 @@ impl<P: DatabasePool> russh::server::Handler for SshSession<P> {
     type Error = anyhow::Error;
 
     async fn auth_password(&mut self, user: &str, password: &str) -> Result<Auth, Self::Error> {
-        info!("credentials: {}, {}", user, password);
-        match self.auther.authenticate(user, password).is_ok() {
-            true => Ok(Auth::Accept),
-            false => Ok(Auth::Reject {
-                proceed_with_methods: Some(MethodSet::PASSWORD),
-            }),
+        if self.is_banned(user, "password") {
+            return Ok(reject());
+        }
+        if self.auther.authenticate(user, password).is_err() {
+            self.login_failed(user, "password");
+            return Ok(reject());
+        }
+        Ok(self.first_factor_passed(user, "password"))
+    }
+
+    /// 两步验证的第二步：第一次调用时发出提示，第二次调用时检查验证码或恢复码
+    async fn auth_keyboard_interactive(
+        &mut self,
+        user: &str,
+        _submethods: &str,
+        response: Option<Response<'async_trait>>,
+    ) -> Result<Auth, Self::Error> {
+        let Some(method) = self
+            .pending_second_factor
+            .as_ref()
+            .filter(|(pending, _)| pending == user)
+            .map(|(_, method)| *method)
+        else {
+            return Ok(reject());
+        };
+        let Some(mut response) = response else {
+            return Ok(Auth::Partial {
+                name: Cow::Borrowed("Two-factor authentication"),
+                instructions: Cow::Borrowed(
+                    "Enter the code from your authenticator app, or a recovery code.",
+                ),
+                prompts: Cow::Owned(vec![(Cow::Borrowed("Verification code: "), false)]),
+            });
+        };
+        // 无论成功与否，验证码只能尝试一次，失败后需要重新完成第一步认证
+        self.pending_second_factor = None;
+        let code = response
+            .next()
+            .map(|code| String::from_utf8_lossy(code).to_string())
+            .unwrap_or_default();
+        match self.auther.verify_second_factor(user, &code) {
+            Ok(()) => {
+                self.login_succeeded(user, &format!("{}+totp", method));
+                Ok(Auth::Accept)
+            }
+            Err(err) => {
+                warn!("second factor of {} rejected: {}", user, err);
+                self.login_failed(user, "totp");
+                Ok(reject())
+            }
+        }
+    }
+
+    /// 客户端询问某个公钥是否可用，此时还没有签名
+    async fn auth_publickey_offered(
+        &mut self,
+        user: &str,
+        public_key: &PublicKey,
+    ) -> Result<Auth, Self::Error> {
+        // 客户端通常会依次询问多个公钥，这里不计入失败次数
+        match self.guard.banned(user, self.client_ip()) {
+            Ok(None) => {}
+            Ok(Some(_)) => return Ok(reject()),
+            Err(err) => {
+                error!("cannot check bans of {}, rejecting the key: {}", user, err);
+                return Ok(reject());
+            }
+        }
+        match self.auther.is_authorized_key(user, public_key) {
+            Ok(true) => Ok(Auth::Accept),
+            Ok(false) => Ok(reject()),
+            Err(err) => {
+                error!("cannot check public key of {}: {}", user, err);
+                Ok(reject())
+            }
+        }
+    }
+
+    /// 签名已经由 russh 验证，这里再次确认公钥仍然有效。公钥不算第二步认证，
+    /// 登记了两步验证的用户同样需要输入验证码
+    async fn auth_publickey(
+        &mut self,
+        user: &str,
+        public_key: &PublicKey,
+    ) -> Result<Auth, Self::Error> {
+        if self.is_banned(user, "publickey") {
+            return Ok(reject());
+        }
+        match self.auther.authenticate_publickey(user, public_key) {
+            Ok(()) => {
+                info!("public key login: {}, {}", user, public_key.fingerprint());
+                Ok(self.first_factor_passed(user, "publickey"))
+            }
+            Err(_) => {
+                self.login_failed(user, "publickey");
+                Ok(reject())
+            }
         }
     }
 
@@ -91,7 +297,30 @@ impl<P: DatabasePool> russh::server::Handler for SshSession<P> {
         if name == "sftp" {
             let channel = self.get_channel(channel_id).await;
             let username = self.auther.username.clone();
-            let sftp = SftpSession::new_with_username(username);
+            let setup = self.user_root(&username).and_then(|(virtual_root, own_root)| {
+                let conn = self.pool.get()?;
+                let acl = Acl::load(&conn, &username)?;
+                let root = own_root.then(|| virtual_root.get_root());
+                let quota = Quota::load(self.pool.clone(), &username, root)?;
+                Ok((virtual_root, acl, quota))
+            });
+            let (virtual_root, acl, quota) = match setup {
+                Ok(setup) => setup,
+                Err(err) => {
+                    error!("cannot start sftp session for {}: {}", username, err);
+                    session.channel_failure(channel_id);
+                    return Ok(());
+                }
+            };
+            let client_addr = self.client_addr.map(|addr| addr.to_string());
+            let sftp = SftpSession::new(
+                username,
+                self.session_id.clone(),
+                client_addr,
+                virtual_root,
+                acl,
+                quota,
+            );
             session.channel_success(channel_id);
             russh_sftp::server::run(channel.into_stream(), sftp).await;
         } else {
@@ -102,43 +331,284 @@ impl<P: DatabasePool> russh::server::Handler for SshSession<P> {
     }
 }
 
+/// 每个 SSH_FXP_NAME 应答中最多返回的目录项数量
+const READDIR_BATCH_SIZE: usize = 100;
+
 #[derive(Default)]
 struct SftpSession {
     version: Option<u32>,
-    root_dir_read_done: bool,
     virtual_root: VirtualRoot,
     cwd_offset: PathBuf,
-    handles: HashMap<String, String>,
-    file_handles: HashMap<String, fs::File>,
-    req_done: HashMap<u32, bool>,
+    handles: HandleTable,
+    acl: Acl,
+    quota: Quota,
     user: String,
+    // 审计日志中用来关联同一连接的操作
+    session_id: String,
+    client_addr: Option<String>,
 }
 
 impl SftpSession {
-    fn new_with_username(username: String) -> Self {
+    fn new(
+        username: String,
+        session_id: String,
+        client_addr: Option<String>,
+        virtual_root: VirtualRoot,
+        acl: Acl,
+        quota: Quota,
+    ) -> Self {
         Self {
             version: None,
-            root_dir_read_done: false,
-            virtual_root: VirtualRoot::default(),
+            virtual_root,
             cwd_offset: PathBuf::from("/"),
-            handles: HashMap::new(),
-            file_handles: HashMap::new(),
-            req_done: HashMap::new(),
+            handles: HandleTable::default(),
+            acl,
+            quota,
             user: username,
+            session_id,
+            client_addr,
+        }
+    }
+
+    /// 将客户端路径解析为虚拟根目录下的真实路径（跟随符号链接），并检查访问规则
+    fn real_path(&self, path: impl AsRef<Path>, right: Right) -> io::Result<PathBuf> {
+        let real_path = self.virtual_root.to_real_path(&self.cwd_offset.join(path))?;
+        self.authorize(&real_path, right)?;
+        Ok(real_path)
+    }
+
+    /// 同 real_path，但不跟随最后一个分量的符号链接
+    fn real_path_nofollow(&self, path: impl AsRef<Path>, right: Right) -> io::Result<PathBuf> {
+        let real_path = self
+            .virtual_root
+            .to_real_path_nofollow(&self.cwd_offset.join(path))?;
+        self.authorize(&real_path, right)?;
+        Ok(real_path)
+    }
+
+    /// 按解析后的路径检查访问规则，避免通过符号链接绕过
+    fn authorize(&self, real_path: &Path, right: Right) -> io::Result<()> {
+        let virtual_path = self.virtual_root.to_virtual_path(real_path)?;
+        self.acl.check(&virtual_path, right)
+    }
+
+    /// 移动目录时检查其下没有访问规则，否则移走后规则不再作用于其中的文件
+    fn authorize_move(&self, real_path: &Path) -> io::Result<()> {
+        if !fs::symlink_metadata(real_path)?.is_dir() {
+            return Ok(());
+        }
+        let virtual_path = self.virtual_root.to_virtual_path(real_path)?;
+        self.acl.check_subtree(&virtual_path)
+    }
+
+    /// 写入一条审计记录
+    fn audit(&self, op: &AuditOp, target: &str, result: StatusCode) {
+        info!(
+            target: AUDIT_TARGET,
+            username = self.user.as_str(),
+            action = op.action,
+            target = target,
+            result = ?result,
+            session_id = self.session_id.as_str(),
+            client_addr = self.client_addr.as_deref(),
+            secondary_target = op.secondary_target.as_deref(),
+            bytes = op.bytes,
+            duration_us = op.started.elapsed().as_micros() as u64,
+            "User action logged"
+        );
+    }
+
+    /// 记录失败的操作，并返回对应的状态码
+    fn fail(&self, op: &AuditOp, target: &str, err: io::Error) -> StatusCode {
+        let status_code = io_error_to_status(&err);
+        warn!("{} {} failed: {}", op.action, target, err);
+        self.audit(op, target, status_code);
+        status_code
+    }
+
+    /// 只需要返回 Status 的请求：失败时把错误说明一并返回给客户端
+    fn reply_status(
+        &self,
+        id: u32,
+        op: &AuditOp,
+        path: &str,
+        result: io::Result<PathBuf>,
+    ) -> Result<Status, StatusCode> {
+        match result {
+            Ok(real_path) => {
+                self.audit(op, &real_path.to_string_lossy(), StatusCode::Ok);
+                Ok(Status {
+                    id,
+                    status_code: StatusCode::Ok,
+                    error_message: "Ok".to_string(),
+                    language_tag: "en-US".to_string(),
+                })
+            }
+            Err(err) => {
+                let error_message = err.to_string();
+                Ok(Status {
+                    id,
+                    status_code: self.fail(op, path, err),
+                    error_message,
+                    language_tag: "en-US".to_string(),
+                })
+            }
+        }
+    }
+
+    /// 取得句柄对应的真实路径，句柄不存在时返回错误
+    fn handle_path(&self, handle: &str) -> io::Result<String> {
+        let path = self.handles.get(handle)?.path();
+        Ok(path.to_string_lossy().to_string())
+    }
+
+    /// 删除或被覆盖的条目归还一个文件的配额；普通文件只有删除的是最后一个硬链接时才归还字节
+    fn release_entry(&self, metadata: &fs::Metadata) {
+        let bytes = if metadata.is_file() && metadata.nlink() == 1 {
+            metadata.len()
+        } else {
+            0
+        };
+        self.quota.release(bytes, 1);
+    }
+
+    /// rename 和 posix-rename：overwrite 为 false 时目标已存在则失败（SFTP v3 的 rename），
+    /// 否则原子地覆盖目标（rename(2)），并归还被覆盖条目占用的配额
+    fn rename_entry(&self, old_path: &Path, new_path: &Path, overwrite: bool) -> io::Result<()> {
+        if !overwrite {
+            return rename_noreplace(old_path, new_path);
+        }
+        let moved = fs::symlink_metadata(old_path)?;
+        let replaced = fs::symlink_metadata(new_path).ok();
+        fs::rename(old_path, new_path)?;
+        // 两个路径是同一个文件的硬链接时 rename 什么也不做
+        if let Some(replaced) = replaced
+            .filter(|replaced| (replaced.dev(), replaced.ino()) != (moved.dev(), moved.ino()))
+        {
+            self.release_entry(&replaced);
+        }
+        Ok(())
+    }
+
+    /// setstat/fsetstat 修改文件大小时，按增长的部分占用配额，缩小时归还
+    fn resize_with_quota(
+        &self,
+        len: impl FnOnce() -> io::Result<u64>,
+        attrs: &FileAttributes,
+        apply: impl FnOnce() -> io::Result<()>,
+    ) -> io::Result<()> {
+        let Some(size) = attrs.size else {
+            return apply();
+        };
+        let len = len()?;
+        let growth = size.saturating_sub(len);
+        self.quota.reserve(growth, 0)?;
+        apply().inspect_err(|_| self.quota.release(growth, 0))?;
+        self.quota.release(len.saturating_sub(size), 0);
+        Ok(())
+    }
+}
+
+/// 一次请求的审计信息，在处理请求之前创建，写入审计日志时计算耗时
+struct AuditOp {
+    action: &'static str,
+    started: Instant,
+    /// 重命名、链接、复制等操作涉及的第二个路径
+    secondary_target: Option<String>,
+    /// 读写或复制的字节数
+    bytes: Option<u64>,
+}
+
+impl AuditOp {
+    fn new(action: &'static str) -> Self {
+        Self {
+            action,
+            started: Instant::now(),
+            secondary_target: None,
+            bytes: None,
+        }
+    }
+}
+
+/// 将 std::io::Error 统一转换为 SFTP 状态码
+fn io_error_to_status(err: &io::Error) -> StatusCode {
+    match err.kind() {
+        io::ErrorKind::NotFound => StatusCode::NoSuchFile,
+        io::ErrorKind::PermissionDenied | io::ErrorKind::ReadOnlyFilesystem => {
+            StatusCode::PermissionDenied
         }
+        io::ErrorKind::UnexpectedEof => StatusCode::Eof,
+        io::ErrorKind::InvalidInput | io::ErrorKind::InvalidData => StatusCode::BadMessage,
+        io::ErrorKind::Unsupported => StatusCode::OpUnsupported,
+        _ => StatusCode::Failure,
+    }
+}
+
+/// 从指定偏移量读取至多 len 字节，到达文件末尾时返回空数据
+fn read_at(file: &fs::File, offset: u64, len: u32) -> io::Result<Vec<u8>> {
+    let file_size = file.metadata()?.len();
+    if offset >= file_size {
+        return Ok(Vec::new());
     }
+    let mut buf = vec![0; len as usize];
+    // 按偏移量读写，不改变文件指针
+    let bytes_read = file.read_at(&mut buf, offset)?;
+    buf.truncate(bytes_read);
+    Ok(buf)
+}
+
+fn write_at(file: &fs::File, offset: u64, data: &[u8]) -> io::Result<()> {
+    file.write_all_at(data, offset)
+}
+
+/// offset + length，客户端给出的数值溢出时返回 InvalidInput
+fn end_offset(offset: u64, length: u64) -> io::Result<u64> {
+    offset
+        .checked_add(length)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Offset is too large"))
+}
+
+/// check-file：按客户端的候选列表选择算法并计算哈希
+fn check_file(
+    file: &fs::File,
+    hash_algorithms: &str,
+    start_offset: u64,
+    length: u64,
+    block_size: u32,
+) -> io::Result<CheckFileReply> {
+    let algorithm = HashAlgorithm::negotiate(hash_algorithms).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::Unsupported, "No supported hash algorithm")
+    })?;
+    let hash = hash_range(file, algorithm, start_offset, length, block_size)?;
+    Ok(CheckFileReply {
+        extension: extensions::CHECK_FILE.to_string(),
+        hash_algorithm: algorithm.name().to_string(),
+        hash,
+    })
+}
 
-    fn check_req_done(&mut self, _id: u32) -> bool {
-        // match self.req_done.get(&id) {
-        //     | Some(v) => *v,
-        //     | None => {
-        //         self.req_done.insert(id,false).unwrap();
-        //         false
-        //     }
-        // }
-        self.root_dir_read_done = !self.root_dir_read_done;
-        !self.root_dir_read_done
+/// 从目录迭代器中取出至多 max 个目录项；
+/// 无法读取属性的条目（例如悬空的符号链接）退回到 lstat 的结果
+fn read_dir_batch(entries: &mut fs::ReadDir, max: usize) -> io::Result<Vec<File>> {
+    let mut files = vec![];
+    for entry in entries.take(max) {
+        let path = entry?.path();
+        let filename = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let attrs = match get_file_file_attributes(&path) {
+            Ok(attrs) => attrs,
+            Err(_) => FileAttributes::from(&fs::symlink_metadata(&path)?),
+        };
+        let longname = format_file_info(&path).unwrap_or_else(|_| filename.clone());
+        files.push(File {
+            filename,
+            longname,
+            attrs,
+        });
     }
+    Ok(files)
 }
 
 #[async_trait]
@@ -163,18 +633,19 @@ impl russh_sftp::server::Handler for SftpSession {
 
         self.version = Some(version);
         info!("version: {:?}, extensions: {:?}", self.version, extensions);
-        Ok(Version::new())
+        let mut version = Version::new();
+        version.extensions = extensions::advertised();
+        Ok(version)
     }
 
-    async fn close(&mut self, id: u32, _handle: String) -> Result<Status, Self::Error> {
-        self.handles.remove(&_handle);
-
-        Ok(Status {
-            id,
-            status_code: StatusCode::Ok,
-            error_message: "Ok".to_string(),
-            language_tag: "en-US".to_string(),
-        })
+    async fn close(&mut self, id: u32, handle: String) -> Result<Status, Self::Error> {
+        let op = AuditOp::new("Close");
+        // 移除的文件或目录在这里被丢弃，对应的文件描述符随之关闭
+        let result = self
+            .handles
+            .remove(&handle)
+            .map(|closed| closed.path().to_path_buf());
+        self.reply_status(id, &op, &handle, result)
     }
 
     async fn open(
@@ -184,19 +655,49 @@ impl russh_sftp::server::Handler for SftpSession {
         pflags: OpenFlags,
         _attrs: FileAttributes,
     ) -> Result<Handle, Self::Error> {
+        let op = AuditOp::new("Open");
         let mut open_options: fs::OpenOptions = pflags.into();
         if pflags.contains(OpenFlags::CREATE) {
             open_options.write(true);
         }
-        let path = self.cwd_offset.join(filename);
-        let path = self.virtual_root.to_real_path(&path).unwrap();
-        let handle_str = format!("handle_{}", id);
-        let file = open_options.open(path.clone()).unwrap();
-        self.handles
-            .insert(handle_str.clone(), path.to_str().unwrap().to_string());
-        self.file_handles.insert(handle_str.clone(), file);
-        // log example:     tracing::info!(username = "admin", action = "Open", target = "Connection", "User action logged");
-        info!(username = self.user.clone(), action = "Open", target = path.to_str().unwrap(), "User action logged");
+        let path = self
+            .handles
+            .check_capacity()
+            .and_then(|_| {
+                let writing = pflags.intersects(
+                    OpenFlags::WRITE | OpenFlags::APPEND | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+                );
+                if !writing {
+                    return self.real_path(&filename, Right::Read);
+                }
+                let path = self.real_path(&filename, Right::Write)?;
+                if pflags.contains(OpenFlags::READ) {
+                    self.authorize(&path, Right::Read)?;
+                }
+                Ok(path)
+            })
+            .map_err(|err| self.fail(&op, &filename, err))?;
+        let target = path.to_string_lossy().to_string();
+        // 新建文件占用一个文件配额，截断已有文件则释放它占用的字节
+        let existing = fs::metadata(&path).ok();
+        let created = pflags.contains(OpenFlags::CREATE) && existing.is_none();
+        let truncated = match &existing {
+            Some(metadata) if pflags.contains(OpenFlags::TRUNCATE) => metadata.len(),
+            _ => 0,
+        };
+        let handle_str = self
+            .quota
+            .reserve(0, created as u64)
+            .and_then(|_| {
+                open_nofollow(&mut open_options, &path)
+                    .inspect_err(|_| self.quota.release(0, created as u64))
+            })
+            .and_then(|file| {
+                self.quota.release(truncated, 0);
+                self.handles.insert(OpenHandle::File { path, file })
+            })
+            .map_err(|err| self.fail(&op, &target, err))?;
+        self.audit(&op, &target, StatusCode::Ok);
         Ok(Handle {
             id,
             handle: handle_str,
@@ -204,14 +705,15 @@ impl russh_sftp::server::Handler for SftpSession {
     }
 
     async fn lstat(&mut self, id: u32, path: String) -> Result<Attrs, Self::Error> {
+        let op = AuditOp::new("Lstat");
         let real_path = self
-            .virtual_root
-            .to_real_path(&self.cwd_offset.join(path))
-            .unwrap();
-        let target = real_path.clone().to_str().unwrap().to_string();
-        let metadata = fs::symlink_metadata(real_path).unwrap();
+            .real_path_nofollow(&path, Right::List)
+            .map_err(|err| self.fail(&op, &path, err))?;
+        let target = real_path.to_string_lossy().to_string();
+        let metadata =
+            fs::symlink_metadata(&real_path).map_err(|err| self.fail(&op, &target, err))?;
         let attrs = FileAttributes::from(&metadata);
-        info!(username = self.user.clone(), action = "Lstat", target = target, "User action logged");
+        self.audit(&op, &target, StatusCode::Ok);
         Ok(Attrs {
             id,
             attrs,
@@ -219,36 +721,62 @@ impl russh_sftp::server::Handler for SftpSession {
     }
 
     async fn fstat(&mut self, id: u32, handle: String) -> Result<Attrs, Self::Error> {
-    match self.handles.get(&handle) {
-        Some(vpath) => {
-            let real_path = self
-            .virtual_root
-            .to_real_path(&self.cwd_offset.join(vpath))
-            .unwrap();
-            let metadata = fs::symlink_metadata(real_path.clone()).unwrap();
-            let attrs = FileAttributes::from(&metadata);
-            info!(username = self.user.clone(), action = "Fstat", target = real_path.to_str().unwrap(), "User action logged");
-            Ok(Attrs {
-                id,
-                attrs,
-            })
-        }
-        None => {
-            Err(StatusCode::NoSuchFile)
-        }
+        let op = AuditOp::new("Fstat");
+        let target = self
+            .handle_path(&handle)
+            .map_err(|err| self.fail(&op, &handle, err))?;
+        let metadata = self
+            .handles
+            .get(&handle)
+            .and_then(|open_handle| open_handle.metadata())
+            .map_err(|err| self.fail(&op, &target, err))?;
+        let attrs = FileAttributes::from(&metadata);
+        self.audit(&op, &target, StatusCode::Ok);
+        Ok(Attrs {
+            id,
+            attrs,
+        })
     }
 
+    async fn setstat(
+        &mut self,
+        id: u32,
+        path: String,
+        attrs: FileAttributes,
+    ) -> Result<Status, Self::Error> {
+        let op = AuditOp::new("SetStat");
+        let result = self.real_path(&path, Right::Write).and_then(|real_path| {
+            self.resize_with_quota(
+                || Ok(fs::metadata(&real_path)?.len()),
+                &attrs,
+                || set_path_attributes(&real_path, &attrs),
+            )?;
+            Ok(real_path)
+        });
+        self.reply_status(id, &op, &path, result)
     }
 
-
-    async fn setstat(
+    async fn fsetstat(
         &mut self,
-        _id: u32,
-        _path: String,
-        _attrs: FileAttributes,
+        id: u32,
+        handle: String,
+        attrs: FileAttributes,
     ) -> Result<Status, Self::Error> {
-        // 状态相关的暂时不写了
-        Err(self.unimplemented())
+        let op = AuditOp::new("FSetStat");
+        let result = self.handles.get(&handle).and_then(|open_handle| {
+            // 与 setstat 相同需要写权限：只读打开的文件和目录句柄也能 fchmod/fchown
+            self.authorize(open_handle.path(), Right::Write)?;
+            self.resize_with_quota(
+                || Ok(open_handle.metadata()?.len()),
+                &attrs,
+                || match open_handle {
+                    OpenHandle::File { file, .. } => set_file_attributes(file, &attrs),
+                    OpenHandle::Dir { path, .. } => set_path_attributes(path, &attrs),
+                },
+            )?;
+            Ok(open_handle.path().to_path_buf())
+        });
+        self.reply_status(id, &op, &handle, result)
     }
 
     async fn read(
@@ -258,21 +786,26 @@ impl russh_sftp::server::Handler for SftpSession {
         offset: u64,
         len: u32,
     ) -> Result<Data, Self::Error> {
-        let file = self.file_handles.get_mut(&handle).unwrap();
-        let file_size = file.metadata().unwrap().len();
-        if offset >= file_size {
+        let op = AuditOp::new("Read");
+        let path = self
+            .handle_path(&handle)
+            .map_err(|err| self.fail(&op, &handle, err))?;
+        // 与 limits@openssh.com 中公布的上限保持一致
+        let len = len.min(extensions::MAX_READ_LEN as u32);
+        let buf = self
+            .handles
+            .file(&handle)
+            .and_then(|file| read_at(file, offset, len))
+            .map_err(|err| self.fail(&op, &path, err))?;
+        let op = AuditOp {
+            bytes: Some(buf.len() as u64),
+            ..op
+        };
+        if buf.is_empty() && len > 0 {
+            self.audit(&op, &path, StatusCode::Eof);
             return Err(StatusCode::Eof);
         }
-        let mut buf = vec![0; len as usize];
-        file.seek(SeekFrom::Start(offset)).unwrap();
-
-        let bytes_read = file.read(&mut buf).unwrap();
-
-        if bytes_read < len as usize {
-            buf.truncate(bytes_read);
-        }
-        let path = self.handles.get(&handle).unwrap();
-        info!(username = self.user.clone(), action = "Read", target = path, "User action logged");
+        self.audit(&op, &path, StatusCode::Ok);
         Ok(Data { id, data: buf })
     }
 
@@ -283,57 +816,57 @@ impl russh_sftp::server::Handler for SftpSession {
         offset: u64,
         data: Vec<u8>,
     ) -> Result<Status, Self::Error> {
-        let file = match self.file_handles.get_mut(&handle) {
-            Some(file) => file,
-            None => return Err(StatusCode::Eof),
+        let op = AuditOp {
+            bytes: Some(data.len() as u64),
+            ..AuditOp::new("Write")
         };
-
-        // 将文件指针移动到指定的偏移量
-        file.seek(SeekFrom::Start(offset)).unwrap();
-
-        // 写入数据
-        let bytes_written = file.write(&data).unwrap();
-
-        // 检查是否所有数据都已写入
-        if bytes_written < data.len() {
-            return Err(StatusCode::Eof);
-        }
-        let path = self.handles.get(&handle).unwrap();
-        info!(username = self.user.clone(), action = "Write", target = path, "User action logged");
+        let result = self.handles.get(&handle).and_then(|open_handle| {
+            let OpenHandle::File { path, file } = open_handle else {
+                return Err(io::Error::other("Not a file handle"));
+            };
+            // 只有超出当前文件末尾的部分才占用新的配额
+            let end = end_offset(offset, data.len() as u64)?;
+            let growth = end.saturating_sub(file.metadata()?.len());
+            self.quota.reserve(growth, 0)?;
+            write_at(file, offset, &data).inspect_err(|_| self.quota.release(growth, 0))?;
+            Ok(path.clone())
+        });
         // 返回写入操作的状态
-        Ok(Status {
-            id,
-            status_code: StatusCode::Ok,
-            error_message: "Ok".to_string(),
-            language_tag: "en-US".to_string(),
-        })
+        self.reply_status(id, &op, &handle, result)
     }
 
     async fn remove(&mut self, id: u32, filename: String) -> Result<Status, Self::Error> {
-        let vpath = self.cwd_offset.join(filename);
-        let real_path = self.virtual_root.to_real_path(&vpath).unwrap();
-        fs::remove_file(real_path.clone()).unwrap();
-        info!(username = self.user.clone(), action = "Remove", target = real_path.to_str().unwrap(), "User action logged");
-        Ok(Status {
-            id,
-            status_code: StatusCode::Ok,
-            error_message: "Ok".to_string(),
-            language_tag: "en-US".to_string(),
-        })
+        let op = AuditOp::new("Remove");
+        let result = self.real_path_nofollow(&filename, Right::Delete).and_then(|real_path| {
+            let metadata = remove_file_nofollow(&real_path)?;
+            self.release_entry(&metadata);
+            Ok(real_path)
+        });
+        self.reply_status(id, &op, &filename, result)
     }
 
     async fn opendir(&mut self, id: u32, path: String) -> Result<Handle, Self::Error> {
+        let op = AuditOp::new("OpenDir");
         // 使用winscp打开空文件夹会出错显示返回空表 很奇怪 本来就是空的啊
         info!("opendir: {}", path);
-        self.root_dir_read_done = false;
         let path = self.cwd_offset.join(path);
-        let handle_str = format!("handle_{}", id);
-        self.handles.insert(
-            handle_str.clone(),
-            path.clone().to_str().unwrap().to_string(),
-        );
-        let real_path = self.virtual_root.to_real_path(&path).unwrap().to_str().unwrap().to_string();
-        info!(username = self.user.clone(), action = "OpenDir", target = real_path, "User action logged");
+        let vpath = path.to_string_lossy().to_string();
+        let real_path = self
+            .handles
+            .check_capacity()
+            .and_then(|_| self.real_path(&path, Right::List))
+            .map_err(|err| self.fail(&op, &vpath, err))?;
+        let target = real_path.to_string_lossy().to_string();
+        let handle_str = read_dir_nofollow(&real_path)
+            .and_then(|(dir, entries)| {
+                self.handles.insert(OpenHandle::Dir {
+                    path: real_path,
+                    dir,
+                    entries,
+                })
+            })
+            .map_err(|err| self.fail(&op, &target, err))?;
+        self.audit(&op, &target, StatusCode::Ok);
         Ok(Handle {
             id,
             handle: handle_str,
@@ -341,34 +874,22 @@ impl russh_sftp::server::Handler for SftpSession {
     }
 
     async fn readdir(&mut self, id: u32, handle: String) -> Result<Name, Self::Error> {
+        let op = AuditOp::new("ReadDir");
         info!("readdir handle: {}", handle);
-        let done = self.check_req_done(id);
-        match done {
-            false => {
-                let vpath = self.handles.get(&handle).unwrap();
-                let vpath = Path::new(vpath);
-                let real_path = self.virtual_root.to_real_path(vpath).unwrap();
-                let real_path = real_path.canonicalize().unwrap();
-                // 读取目录
-                let entries = fs::read_dir(real_path.clone()).unwrap();
-                let mut files = vec![];
-                for entry in entries {
-                    let entry = entry.unwrap();
-                    let path = entry.path();
-                    let filename = path.file_name().unwrap().to_str().unwrap().to_string();
-                    let longname = format_file_info(&path).unwrap();
-                    let attrs = get_file_file_attributes(&path).unwrap();
-                    files.push(File {
-                        filename,
-                        longname,
-                        attrs,
-                    });
-                }
-                info!(username = self.user.clone(), action = "ReadDir", target = real_path.to_str().unwrap(), "User action logged");
-                Ok(Name { id, files })
-            }
-            true => Err(StatusCode::Eof),
+        let target = self
+            .handle_path(&handle)
+            .map_err(|err| self.fail(&op, &handle, err))?;
+        let files = self
+            .handles
+            .dir_mut(&handle)
+            .and_then(|entries| read_dir_batch(entries, READDIR_BATCH_SIZE))
+            .map_err(|err| self.fail(&op, &target, err))?;
+        // 目录已经遍历完毕
+        if files.is_empty() {
+            return Err(StatusCode::Eof);
         }
+        self.audit(&op, &target, StatusCode::Ok);
+        Ok(Name { id, files })
     }
 
     async fn mkdir(
@@ -377,52 +898,60 @@ impl russh_sftp::server::Handler for SftpSession {
         path: String,
         _attrs: FileAttributes,
     ) -> Result<Status, Self::Error> {
-        let real_path = self
-            .virtual_root
-            .to_real_path(&self.cwd_offset.join(path))
-            .unwrap();
-        fs::create_dir(real_path.clone()).unwrap();
-        info!(username = self.user.clone(), action = "MakeDir", target = real_path.to_str().unwrap(), "User action logged");
-        Ok(Status {
-            id,
-            status_code: StatusCode::Ok,
-            error_message: "Ok".to_string(),
-            language_tag: "en-US".to_string(),
-        })
+        let op = AuditOp::new("MakeDir");
+        let result = self.real_path_nofollow(&path, Right::MakeDir).and_then(|real_path| {
+            self.quota.reserve(0, 1)?;
+            fs::create_dir(&real_path).inspect_err(|_| self.quota.release(0, 1))?;
+            Ok(real_path)
+        });
+        self.reply_status(id, &op, &path, result)
     }
 
     async fn rmdir(&mut self, id: u32, path: String) -> Result<Status, Self::Error> {
-        let real_path = self
-            .virtual_root
-            .to_real_path(&self.cwd_offset.join(path))
-            .unwrap();
-        fs::remove_dir(real_path.clone()).unwrap();
-        info!(username = self.user.clone(), action = "RemoveDir", target = real_path.to_str().unwrap(), "User action logged");
-        Ok(Status {
-            id,
-            status_code: StatusCode::Ok,
-            error_message: "Ok".to_string(),
-            language_tag: "en-US".to_string(),
-        })
+        let op = AuditOp::new("RemoveDir");
+        let result = self.real_path_nofollow(&path, Right::Delete).and_then(|real_path| {
+            fs::remove_dir(&real_path)?;
+            self.quota.release(0, 1);
+            Ok(real_path)
+        });
+        self.reply_status(id, &op, &path, result)
     }
 
     async fn realpath(&mut self, id: u32, path: String) -> Result<Name, Self::Error> {
+        let op = AuditOp::new("RealPath");
         info!("realpath: {}", path);
+        // realpath 只解析路径，不受访问规则限制，否则客户端可能无法确定初始目录
         let real_path = self
             .virtual_root
-            .to_real_path(&self.cwd_offset.join(path))
-            .unwrap();
-        let ans = self.virtual_root.to_virtual_path(&real_path).unwrap();
-        if !real_path.exists() {
-            return Err(StatusCode::NoSuchFile);
-        }
-        let longname = format_file_info(&real_path).unwrap();
-        let attrs = get_file_file_attributes(&real_path).unwrap();
-        info!(username = self.user.clone(), action = "RealPath", target = real_path.to_str().unwrap(), "User action logged");
+            .to_real_path(&self.cwd_offset.join(&path))
+            .map_err(|err| self.fail(&op, &path, err))?;
+        let target = real_path.to_string_lossy().to_string();
+        let result = self.virtual_root.to_virtual_path(&real_path).and_then(|ans| {
+            // 没有 list 权限时只返回解析后的路径，不读取文件的属性
+            if self.authorize(&real_path, Right::List).is_err() {
+                let longname = ans.to_string_lossy().to_string();
+                let attrs = FileAttributes {
+                    size: None,
+                    uid: None,
+                    user: None,
+                    gid: None,
+                    group: None,
+                    permissions: None,
+                    atime: None,
+                    mtime: None,
+                };
+                return Ok((ans, longname, attrs));
+            }
+            let longname = format_file_info(&real_path)?;
+            let attrs = get_file_file_attributes(&real_path)?;
+            Ok((ans, longname, attrs))
+        });
+        let (ans, longname, attrs) = result.map_err(|err| self.fail(&op, &target, err))?;
+        self.audit(&op, &target, StatusCode::Ok);
         Ok(Name {
             id,
             files: vec![File {
-                filename: ans.to_str().unwrap().to_string(),
+                filename: ans.to_string_lossy().to_string(),
                 longname,
                 attrs,
             }],
@@ -430,21 +959,18 @@ impl russh_sftp::server::Handler for SftpSession {
     }
 
     async fn stat(&mut self, id: u32, path: String) -> Result<Attrs, Self::Error> {
+        let op = AuditOp::new("Stat");
         let real_path = self
-            .virtual_root
-            .to_real_path(&self.cwd_offset.join(path))
-            .unwrap();
-        match fs::metadata(real_path.clone()) {
-            Ok(metadata) => {
-                let attrs = FileAttributes::from(&metadata);
-                info!(username = self.user.clone(), action = "Stat", target = real_path.to_str().unwrap(), "User action logged");
-                Ok(Attrs {
-                    id,
-                    attrs,
-                })
-            }
-            Err(_) => Err(StatusCode::NoSuchFile),
-        }
+            .real_path(&path, Right::List)
+            .map_err(|err| self.fail(&op, &path, err))?;
+        let target = real_path.to_string_lossy().to_string();
+        let metadata = fs::metadata(&real_path).map_err(|err| self.fail(&op, &target, err))?;
+        let attrs = FileAttributes::from(&metadata);
+        self.audit(&op, &target, StatusCode::Ok);
+        Ok(Attrs {
+            id,
+            attrs,
+        })
     }
 
     async fn rename(
@@ -453,37 +979,332 @@ impl russh_sftp::server::Handler for SftpSession {
         oldpath: String,
         newpath: String,
     ) -> Result<Status, Self::Error> {
-        let oldpath = self
-            .virtual_root
-            .to_real_path(&self.cwd_offset.join(oldpath))
-            .unwrap();
-        let newpath = self
-            .virtual_root
-            .to_real_path(&self.cwd_offset.join(newpath))
-            .unwrap();
-        fs::rename(oldpath.clone(), newpath).unwrap();
-        info!(username = self.user.clone(), action = "Rename", target = oldpath.to_str().unwrap(), "User action logged");
-        Ok(Status {
+        let mut op = AuditOp {
+            secondary_target: Some(newpath.clone()),
+            ..AuditOp::new("Rename")
+        };
+        // SFTP v3 的 rename 不覆盖已存在的目标（与 OpenSSH 相同），覆盖需要 posix-rename
+        let result = self.real_path_nofollow(&oldpath, Right::Delete).and_then(|old_real_path| {
+            let new_real_path = self.real_path_nofollow(&newpath, Right::Write)?;
+            op.secondary_target = Some(new_real_path.to_string_lossy().to_string());
+            self.authorize_move(&old_real_path)?;
+            self.rename_entry(&old_real_path, &new_real_path, false)?;
+            Ok(old_real_path)
+        });
+        self.reply_status(id, &op, &oldpath, result)
+    }
+
+    async fn readlink(&mut self, id: u32, path: String) -> Result<Name, Self::Error> {
+        let op = AuditOp::new("ReadLink");
+        let real_path = self
+            .real_path_nofollow(&path, Right::List)
+            .map_err(|err| self.fail(&op, &path, err))?;
+        let target_path = real_path.to_string_lossy().to_string();
+        let target = fs::read_link(&real_path)
+            .and_then(|target| self.virtual_root.to_virtual_link_target(&target))
+            .map_err(|err| self.fail(&op, &target_path, err))?;
+        let target = target.to_string_lossy().to_string();
+        self.audit(&op, &target_path, StatusCode::Ok);
+        Ok(Name {
             id,
-            status_code: StatusCode::Ok,
-            error_message: "Ok".to_string(),
-            language_tag: "en-US".to_string(),
+            files: vec![File {
+                filename: target.clone(),
+                longname: target,
+                attrs: FileAttributes::default(),
+            }],
         })
     }
+
+    // OpenSSH 的 SSH_FXP_SYMLINK 与草案中的参数顺序相反：先是链接目标，再是链接路径。
+    // 这里按 OpenSSH 的约定处理，以兼容 sftp/sshfs 客户端。
+    async fn symlink(
+        &mut self,
+        id: u32,
+        targetpath: String,
+        linkpath: String,
+    ) -> Result<Status, Self::Error> {
+        let op = AuditOp {
+            secondary_target: Some(targetpath.clone()),
+            ..AuditOp::new("Symlink")
+        };
+        let link_vpath = self.cwd_offset.join(&linkpath);
+        let result = self.real_path_nofollow(&link_vpath, Right::Write).and_then(|link_path| {
+            let target = self
+                .virtual_root
+                .to_link_target(&link_vpath, Path::new(&targetpath))?;
+            self.quota.reserve(0, 1)?;
+            std::os::unix::fs::symlink(&target, &link_path)
+                .inspect_err(|_| self.quota.release(0, 1))?;
+            Ok(link_path)
+        });
+        self.reply_status(id, &op, &linkpath, result)
+    }
+
+    async fn extended(
+        &mut self,
+        id: u32,
+        request: String,
+        data: Vec<u8>,
+    ) -> Result<Packet, Self::Error> {
+        match request.as_str() {
+            extensions::HARDLINK => {
+                let request: HardlinkExtension = extensions::parse_request(data)?;
+                self.hardlink(id, request.oldpath, request.newpath)
+                    .map(Packet::Status)
+            }
+            extensions::POSIX_RENAME => {
+                let request: PosixRenameExtension = extensions::parse_request(data)?;
+                self.posix_rename(id, request.oldpath, request.newpath)
+                    .map(Packet::Status)
+            }
+            extensions::STATVFS => {
+                let request: StatvfsExtension = extensions::parse_request(data)?;
+                self.statvfs(id, request.path)
+            }
+            extensions::FSTATVFS => {
+                let request: FstatvfsExtension = extensions::parse_request(data)?;
+                self.fstatvfs(id, request.handle)
+            }
+            extensions::FSYNC => {
+                let request: FsyncExtension = extensions::parse_request(data)?;
+                self.fsync(id, request.handle).map(Packet::Status)
+            }
+            extensions::LSETSTAT => {
+                let request: LsetstatExtension = extensions::parse_request(data)?;
+                self.lsetstat(id, request.path, request.attrs)
+                    .map(Packet::Status)
+            }
+            extensions::COPY_DATA => {
+                let request: CopyDataExtension = extensions::parse_request(data)?;
+                self.copy_data(id, request).map(Packet::Status)
+            }
+            extensions::CHECK_FILE_HANDLE => {
+                let request: CheckFileHandleExtension = extensions::parse_request(data)?;
+                self.check_file_handle(id, request)
+            }
+            extensions::CHECK_FILE_NAME => {
+                let request: CheckFileNameExtension = extensions::parse_request(data)?;
+                self.check_file_name(id, request)
+            }
+            extensions::LIMITS => extensions::reply(
+                id,
+                &LimitsExtension {
+                    max_packet_len: extensions::MAX_PACKET_LEN,
+                    max_read_len: extensions::MAX_READ_LEN,
+                    max_write_len: extensions::MAX_WRITE_LEN,
+                    max_open_handles: self.handles.max_handles() as u64,
+                },
+            ),
+            _ => Err(self.unimplemented()),
+        }
+    }
 }
 
-// 测试
-#[cfg(test)]
-mod tests {
-    use crate::database::MockDatabasePool;
+impl SftpSession {
+    fn hardlink(&mut self, id: u32, oldpath: String, newpath: String) -> Result<Status, StatusCode> {
+        let mut op = AuditOp {
+            secondary_target: Some(oldpath.clone()),
+            ..AuditOp::new("Hardlink")
+        };
+        let result = self.real_path_nofollow(&oldpath, Right::Read).and_then(|old_real_path| {
+            op.secondary_target = Some(old_real_path.to_string_lossy().to_string());
+            // 硬链接和原文件是同一个 inode，通过可写的链接就能修改原文件，所以原文件也需要写权限
+            self.authorize(&old_real_path, Right::Write)?;
+            let new_real_path = self.real_path_nofollow(&newpath, Right::Write)?;
+            // 硬链接占用一个文件，字节已经由原文件占用
+            self.quota.reserve(0, 1)?;
+            fs::hard_link(&old_real_path, &new_real_path)
+                .inspect_err(|_| self.quota.release(0, 1))?;
+            Ok(new_real_path)
+        });
+        self.reply_status(id, &op, &newpath, result)
+    }
 
-    use super::*;
-    use std::time::Duration;
-    use russh_keys::key::KeyPair;
-    use log::LevelFilter;
-    use russh::server::Server as _;
-    
-    
+    /// 与 rename 不同，目标已存在时直接原子地覆盖（rename(2) 的语义）
+    fn posix_rename(
+        &mut self,
+        id: u32,
+        oldpath: String,
+        newpath: String,
+    ) -> Result<Status, StatusCode> {
+        let mut op = AuditOp {
+            secondary_target: Some(newpath.clone()),
+            ..AuditOp::new("PosixRename")
+        };
+        let result = self.real_path_nofollow(&oldpath, Right::Delete).and_then(|old_real_path| {
+            let new_real_path = self.real_path_nofollow(&newpath, Right::Write)?;
+            op.secondary_target = Some(new_real_path.to_string_lossy().to_string());
+            self.authorize_move(&old_real_path)?;
+            self.rename_entry(&old_real_path, &new_real_path, true)?;
+            Ok(old_real_path)
+        });
+        self.reply_status(id, &op, &oldpath, result)
+    }
+
+    fn statvfs(&mut self, id: u32, path: String) -> Result<Packet, StatusCode> {
+        let op = AuditOp::new("StatVfs");
+        let real_path = self
+            .real_path(&path, Right::List)
+            .map_err(|err| self.fail(&op, &path, err))?;
+        let target = real_path.to_string_lossy().to_string();
+        let stat = statvfs(&real_path).map_err(|err| self.fail(&op, &target, err))?;
+        self.audit(&op, &target, StatusCode::Ok);
+        extensions::reply(id, &stat)
+    }
+
+    fn fstatvfs(&mut self, id: u32, handle: String) -> Result<Packet, StatusCode> {
+        let op = AuditOp::new("FStatVfs");
+        let path = self
+            .handle_path(&handle)
+            .map_err(|err| self.fail(&op, &handle, err))?;
+        let stat = self
+            .handles
+            .get(&handle)
+            .and_then(|open_handle| match open_handle {
+                OpenHandle::File { file, .. } => fstatvfs(file),
+                OpenHandle::Dir { path, .. } => statvfs(path),
+            })
+            .map_err(|err| self.fail(&op, &path, err))?;
+        self.audit(&op, &path, StatusCode::Ok);
+        extensions::reply(id, &stat)
+    }
+
+    fn fsync(&mut self, id: u32, handle: String) -> Result<Status, StatusCode> {
+        let op = AuditOp::new("Fsync");
+        let result = self.handles.file(&handle).and_then(|file| {
+            file.sync_all()?;
+            self.handles.get(&handle).map(|open_handle| open_handle.path().to_path_buf())
+        });
+        self.reply_status(id, &op, &handle, result)
+    }
+
+    fn lsetstat(
+        &mut self,
+        id: u32,
+        path: String,
+        attrs: FileAttributes,
+    ) -> Result<Status, StatusCode> {
+        let op = AuditOp::new("LSetStat");
+        let result = self.real_path_nofollow(&path, Right::Write).and_then(|real_path| {
+            set_symlink_attributes(&real_path, &attrs)?;
+            Ok(real_path)
+        });
+        self.reply_status(id, &op, &path, result)
+    }
+
+    /// 在服务器端把一个句柄中的数据复制到另一个句柄，不经过客户端
+    fn copy_data(&mut self, id: u32, request: CopyDataExtension) -> Result<Status, StatusCode> {
+        let mut op = AuditOp::new("CopyData");
+        let result = self.handle_path(&request.write_to_handle).and_then(|target| {
+            let source = self.handle_path(&request.read_from_handle)?;
+            op.secondary_target = Some(source.clone());
+            let src = self.handles.file(&request.read_from_handle)?;
+            let dst = self.handles.file(&request.write_to_handle)?;
+            // 不超过源文件剩余的长度，配额和审计记录都按实际能复制的字节数计算
+            let available = src.metadata()?.len().saturating_sub(request.read_from_offset);
+            let length = match request.read_data_length {
+                0 => available,
+                length => length.min(available),
+            };
+            // 同一个文件内复制时，读写范围不能重叠
+            if source == target
+                && request.read_from_offset < request.write_to_offset.saturating_add(length)
+                && request.write_to_offset < request.read_from_offset.saturating_add(length)
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Source and destination ranges overlap",
+                ));
+            }
+            let old_len = dst.metadata()?.len();
+            let end = end_offset(request.write_to_offset, length)?;
+            let growth = end.saturating_sub(old_len);
+            self.quota.reserve(growth, 0)?;
+            // length 为 0 时 copy_range 会一直复制到源文件末尾，这里已经没有可复制的数据
+            let copied = match length {
+                0 => 0,
+                length => copy_range(
+                    src,
+                    request.read_from_offset,
+                    length,
+                    dst,
+                    request.write_to_offset,
+                )
+                .inspect_err(|_| self.quota.release(growth, 0))?,
+            };
+            op.bytes = Some(copied);
+            // 按实际复制的字节数计算，归还多占用的部分；copied 不超过 length，不会溢出
+            let grown = (request.write_to_offset + copied).max(old_len) - old_len;
+            self.quota.release(growth - grown, 0);
+            Ok(PathBuf::from(target))
+        });
+        self.reply_status(id, &op, &request.write_to_handle, result)
+    }
+
+    fn check_file_handle(
+        &mut self,
+        id: u32,
+        request: CheckFileHandleExtension,
+    ) -> Result<Packet, StatusCode> {
+        let op = AuditOp::new("CheckFile");
+        let path = self
+            .handle_path(&request.handle)
+            .map_err(|err| self.fail(&op, &request.handle, err))?;
+        let reply = self
+            .handles
+            .file(&request.handle)
+            .and_then(|file| {
+                check_file(
+                    file,
+                    &request.hash_algorithms,
+                    request.start_offset,
+                    request.length,
+                    request.block_size,
+                )
+            })
+            .map_err(|err| self.fail(&op, &path, err))?;
+        self.audit(&op, &path, StatusCode::Ok);
+        extensions::reply(id, &reply)
+    }
+
+    fn check_file_name(
+        &mut self,
+        id: u32,
+        request: CheckFileNameExtension,
+    ) -> Result<Packet, StatusCode> {
+        let op = AuditOp::new("CheckFile");
+        let real_path = self
+            .real_path(&request.filename, Right::Read)
+            .map_err(|err| self.fail(&op, &request.filename, err))?;
+        let target = real_path.to_string_lossy().to_string();
+        let reply = fs::File::open(&real_path)
+            .and_then(|file| {
+                check_file(
+                    &file,
+                    &request.hash_algorithms,
+                    request.start_offset,
+                    request.length,
+                    request.block_size,
+                )
+            })
+            .map_err(|err| self.fail(&op, &target, err))?;
+        self.audit(&op, &target, StatusCode::Ok);
+        extensions::reply(id, &reply)
+    }
+}
+
+// 测试
+#[cfg(test)]
+mod tests {
+    use crate::database::MockDatabasePool;
+
+    use super::*;
+    use std::time::Duration;
+    use russh_keys::key::KeyPair;
+    use log::LevelFilter;
+    use russh::server::Server as _;
+    
+    
 
 
 
@@ -513,4 +1334,1124 @@ mod tests {
             .await
             .unwrap();
     }
+
+    fn test_session(root: &Path) -> SftpSession {
+        SftpSession {
+            virtual_root: VirtualRoot::new(root).unwrap(),
+            cwd_offset: PathBuf::from("/"),
+            user: "test".to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// 没有设置任何属性；FileAttributes::default() 会设置大小、属主、权限和时间
+    fn no_attrs() -> FileAttributes {
+        FileAttributes {
+            size: None,
+            uid: None,
+            user: None,
+            gid: None,
+            group: None,
+            permissions: None,
+            atime: None,
+            mtime: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_setstat() {
+        use russh_sftp::server::Handler;
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("file.txt"), b"Hello, world!").unwrap();
+        let mut sftp = test_session(dir.path());
+
+        let attrs = FileAttributes {
+            size: Some(5),
+            uid: None,
+            user: None,
+            gid: None,
+            group: None,
+            permissions: Some(0o600),
+            atime: Some(1_000_000),
+            mtime: Some(2_000_000),
+        };
+        let status = sftp.setstat(1, "/file.txt".to_string(), attrs).await.unwrap();
+        assert_eq!(status.status_code, StatusCode::Ok);
+
+        let metadata = fs::metadata(dir.path().join("file.txt")).unwrap();
+        assert_eq!(metadata.len(), 5);
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        assert_eq!(
+            metadata.modified().unwrap(),
+            std::time::UNIX_EPOCH + Duration::from_secs(2_000_000)
+        );
+
+        // setuid、setgid 和 sticky 位会被去掉
+        let attrs = FileAttributes {
+            permissions: Some(0o4755),
+            ..no_attrs()
+        };
+        let status = sftp.setstat(2, "/file.txt".to_string(), attrs).await.unwrap();
+        assert_eq!(status.status_code, StatusCode::Ok);
+        let metadata = fs::metadata(dir.path().join("file.txt")).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o7777, 0o755);
+
+        // 不能把文件交给其他用户：root 运行时由服务器拒绝，否则由内核拒绝，
+        // 两种情况下其他属性都不会被修改
+        let attrs = FileAttributes {
+            uid: Some(metadata.uid() + 1),
+            permissions: Some(0o700),
+            ..no_attrs()
+        };
+        let status = sftp.setstat(3, "/file.txt".to_string(), attrs).await.unwrap();
+        assert_eq!(status.status_code, StatusCode::PermissionDenied);
+        if unsafe { libc::geteuid() } == 0 {
+            let attrs = FileAttributes {
+                gid: Some(metadata.gid() + 1),
+                ..no_attrs()
+            };
+            let status = sftp.setstat(4, "/file.txt".to_string(), attrs).await.unwrap();
+            assert_eq!(status.status_code, StatusCode::PermissionDenied);
+        } else if let Some(group) = users::group_access_list()
+            .unwrap_or_default()
+            .into_iter()
+            .find(|group| group.gid() != metadata.gid())
+        {
+            // 普通用户可以把文件改到自己所属的其他组
+            let attrs = FileAttributes {
+                gid: Some(group.gid()),
+                ..no_attrs()
+            };
+            let status = sftp.setstat(4, "/file.txt".to_string(), attrs).await.unwrap();
+            assert_eq!(status.status_code, StatusCode::Ok);
+            let changed = fs::metadata(dir.path().join("file.txt")).unwrap();
+            assert_eq!(changed.gid(), group.gid());
+            std::os::unix::fs::chown(dir.path().join("file.txt"), None, Some(metadata.gid())).unwrap();
+        }
+        let attrs = FileAttributes {
+            uid: Some(metadata.uid()),
+            gid: Some(metadata.gid()),
+            ..no_attrs()
+        };
+        let status = sftp.setstat(5, "/file.txt".to_string(), attrs).await.unwrap();
+        assert_eq!(status.status_code, StatusCode::Ok);
+        let metadata = fs::metadata(dir.path().join("file.txt")).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o7777, 0o755);
+    }
+
+    #[tokio::test]
+    async fn test_fsetstat() {
+        use russh_sftp::server::Handler;
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("file.txt"), b"Hello, world!").unwrap();
+        let mut sftp = test_session(dir.path());
+
+        let handle = sftp
+            .open(1, "/file.txt".to_string(), OpenFlags::READ | OpenFlags::WRITE, FileAttributes::default())
+            .await
+            .unwrap()
+            .handle;
+        let attrs = FileAttributes {
+            size: Some(0),
+            uid: None,
+            user: None,
+            gid: None,
+            group: None,
+            permissions: Some(0o640),
+            atime: None,
+            mtime: None,
+        };
+        let status = sftp.fsetstat(2, handle, attrs).await.unwrap();
+        assert_eq!(status.status_code, StatusCode::Ok);
+
+        let metadata = fs::metadata(dir.path().join("file.txt")).unwrap();
+        assert_eq!(metadata.len(), 0);
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o640);
+    }
+
+    #[tokio::test]
+    async fn test_symlink_and_readlink() {
+        use russh_sftp::server::Handler;
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("docs")).unwrap();
+        fs::write(dir.path().join("docs/file.txt"), b"Hello, world!").unwrap();
+        let mut sftp = test_session(dir.path());
+
+        let status = sftp
+            .symlink(1, "/docs/file.txt".to_string(), "/abs_link".to_string())
+            .await
+            .unwrap();
+        assert_eq!(status.status_code, StatusCode::Ok);
+        assert_eq!(
+            fs::read_link(dir.path().join("abs_link")).unwrap(),
+            dir.path().join("docs/file.txt")
+        );
+        let name = sftp.readlink(2, "/abs_link".to_string()).await.unwrap();
+        assert_eq!(name.files[0].filename, "/docs/file.txt");
+
+        sftp.symlink(3, "file.txt".to_string(), "/docs/rel_link".to_string())
+            .await
+            .unwrap();
+        let name = sftp.readlink(4, "/docs/rel_link".to_string()).await.unwrap();
+        assert_eq!(name.files[0].filename, "file.txt");
+
+        let status = sftp
+            .symlink(5, "../../etc/passwd".to_string(), "/docs/escape".to_string())
+            .await
+            .unwrap();
+        assert_eq!(status.status_code, StatusCode::PermissionDenied);
+        assert!(fs::symlink_metadata(dir.path().join("docs/escape")).is_err());
+
+        std::os::unix::fs::symlink("/etc/passwd", dir.path().join("outside")).unwrap();
+        let err = sftp.readlink(6, "/outside".to_string()).await.unwrap_err();
+        assert_eq!(err, StatusCode::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn test_hardlink() {
+        use russh_sftp::server::Handler;
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("file.txt"), b"Hello, world!").unwrap();
+        let mut sftp = test_session(dir.path());
+
+        let data: Vec<u8> = HardlinkExtension {
+            oldpath: "/file.txt".to_string(),
+            newpath: "/hard.txt".to_string(),
+        }
+        .try_into()
+        .unwrap();
+        let packet = sftp
+            .extended(1, extensions::HARDLINK.to_string(), data)
+            .await
+            .unwrap();
+        assert!(matches!(packet, Packet::Status(Status { status_code: StatusCode::Ok, .. })));
+        assert_eq!(fs::read(dir.path().join("hard.txt")).unwrap(), b"Hello, world!");
+    }
+
+    #[tokio::test]
+    async fn test_audit_records_details() {
+        use crate::audit::{query_audit_logs, AuditFilter, DatabaseLogger};
+        use russh_sftp::server::Handler;
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let pool = MockDatabasePool::get_pool();
+        User::<MockDatabasePool>::new_with_pool(pool.clone())
+            .register("test", "Correct-Horse-9")
+            .unwrap();
+        let logger = DatabaseLogger::new(pool.clone());
+        let subscriber = tracing_subscriber::Registry::default().with(logger.clone());
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut sftp = SftpSession {
+            session_id: "0123456789abcdef".to_string(),
+            client_addr: Some("192.0.2.1:50022".to_string()),
+            ..test_session(dir.path())
+        };
+        let handle = sftp
+            .open(1, "/a.txt".to_string(), OpenFlags::CREATE | OpenFlags::READ | OpenFlags::WRITE, FileAttributes::default())
+            .await
+            .unwrap()
+            .handle;
+        sftp.write(2, handle.clone(), 0, b"Hello".to_vec()).await.unwrap();
+        sftp.read(3, handle.clone(), 0, 1024).await.unwrap();
+        let err = sftp.read(3, handle.clone(), 5, 1024).await.unwrap_err();
+        assert_eq!(err, StatusCode::Eof);
+        sftp.close(4, handle).await.unwrap();
+        sftp.rename(5, "/a.txt".to_string(), "/b.txt".to_string()).await.unwrap();
+        let status = sftp.rename(6, "/a.txt".to_string(), "/c.txt".to_string()).await.unwrap();
+        assert_eq!(status.status_code, StatusCode::NoSuchFile);
+        logger.flush();
+
+        let conn = pool.get().unwrap();
+        let records = query_audit_logs(&conn, &AuditFilter::default()).unwrap();
+        let actions: Vec<_> = records.iter().map(|record| record.action.as_str()).collect();
+        assert_eq!(actions, ["Open", "Write", "Read", "Read", "Close", "Rename", "Rename"]);
+        assert!(records.iter().all(|record| {
+            record.session_id.as_deref() == Some("0123456789abcdef")
+                && record.client_addr.as_deref() == Some("192.0.2.1:50022")
+                && record.duration_us.is_some()
+        }));
+        assert_eq!(records[1].bytes, Some(5));
+        assert_eq!(records[2].bytes, Some(5));
+        assert_eq!(records[0].bytes, None);
+        // 读到文件末尾也会记录
+        assert_eq!(records[3].result, "Eof");
+        assert_eq!(records[3].bytes, Some(0));
+        let root = dir.path().canonicalize().unwrap();
+        assert_eq!(records[5].target, root.join("a.txt").to_string_lossy());
+        assert_eq!(
+            records[5].secondary_target.as_deref(),
+            Some(root.join("b.txt").to_string_lossy().as_ref())
+        );
+        // 失败的操作同样记录状态码和第二个路径
+        assert_eq!(records[6].result, "NoSuchFile");
+        assert_eq!(
+            records[6].secondary_target.as_deref(),
+            Some(root.join("c.txt").to_string_lossy().as_ref())
+        );
+    }
+
+    #[test]
+    fn test_io_error_to_status() {
+        let cases = [
+            (io::ErrorKind::NotFound, StatusCode::NoSuchFile),
+            (io::ErrorKind::PermissionDenied, StatusCode::PermissionDenied),
+            (io::ErrorKind::UnexpectedEof, StatusCode::Eof),
+            (io::ErrorKind::AlreadyExists, StatusCode::Failure),
+            (io::ErrorKind::Unsupported, StatusCode::OpUnsupported),
+        ];
+        for (kind, status_code) in cases {
+            assert_eq!(io_error_to_status(&io::Error::from(kind)), status_code);
+        }
+        assert_eq!(
+            io_error_to_status(&io::Error::from_raw_os_error(libc::EACCES)),
+            StatusCode::PermissionDenied
+        );
+    }
+
+    #[tokio::test]
+    async fn test_errors_do_not_panic() {
+        use russh_sftp::server::Handler;
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("file.txt"), b"Hello").unwrap();
+        let mut sftp = test_session(dir.path());
+
+        let err = sftp
+            .open(1, "/missing.txt".to_string(), OpenFlags::READ, FileAttributes::default())
+            .await
+            .unwrap_err();
+        assert_eq!(err, StatusCode::NoSuchFile);
+
+        let status = sftp.remove(2, "/missing.txt".to_string()).await.unwrap();
+        assert_eq!(status.status_code, StatusCode::NoSuchFile);
+        assert!(!status.error_message.is_empty());
+
+        let status = sftp.rmdir(3, "/file.txt".to_string()).await.unwrap();
+        assert_eq!(status.status_code, StatusCode::Failure);
+
+        let status = sftp
+            .rename(4, "/missing.txt".to_string(), "/other.txt".to_string())
+            .await
+            .unwrap();
+        assert_eq!(status.status_code, StatusCode::NoSuchFile);
+
+        let err = sftp.read(5, "no_such_handle".to_string(), 0, 10).await.unwrap_err();
+        assert_eq!(err, StatusCode::NoSuchFile);
+        let err = sftp.lstat(6, "/missing.txt".to_string()).await.unwrap_err();
+        assert_eq!(err, StatusCode::NoSuchFile);
+        let err = sftp.opendir(7, "/missing".to_string()).await.unwrap_err();
+        assert_eq!(err, StatusCode::NoSuchFile);
+
+        let handle = sftp
+            .open(8, "/file.txt".to_string(), OpenFlags::READ, FileAttributes::default())
+            .await
+            .unwrap()
+            .handle;
+        let data = sftp.read(9, handle.clone(), 0, 10).await.unwrap();
+        assert_eq!(data.data, b"Hello");
+        let err = sftp.read(10, handle, 5, 10).await.unwrap_err();
+        assert_eq!(err, StatusCode::Eof);
+    }
+
+    #[tokio::test]
+    async fn test_readdir_paginates_per_handle() {
+        use russh_sftp::server::Handler;
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("big")).unwrap();
+        fs::create_dir(dir.path().join("small")).unwrap();
+        for i in 0..250 {
+            fs::write(dir.path().join(format!("big/file_{}", i)), b"").unwrap();
+        }
+        fs::write(dir.path().join("small/only.txt"), b"").unwrap();
+        let mut sftp = test_session(dir.path());
+
+        let big = sftp.opendir(1, "/big".to_string()).await.unwrap().handle;
+        let small = sftp.opendir(2, "/small".to_string()).await.unwrap().handle;
+
+        let mut big_count = 0;
+        let mut small_count = 0;
+        let mut big_done = false;
+        let mut small_done = false;
+        while !big_done || !small_done {
+            if !big_done {
+                match sftp.readdir(3, big.clone()).await {
+                    Ok(name) => {
+                        assert!(name.files.len() <= READDIR_BATCH_SIZE);
+                        big_count += name.files.len();
+                    }
+                    Err(err) => {
+                        assert_eq!(err, StatusCode::Eof);
+                        big_done = true;
+                    }
+                }
+            }
+            if !small_done {
+                match sftp.readdir(4, small.clone()).await {
+                    Ok(name) => small_count += name.files.len(),
+                    Err(err) => {
+                        assert_eq!(err, StatusCode::Eof);
+                        small_done = true;
+                    }
+                }
+            }
+        }
+        assert_eq!(big_count, 250);
+        assert_eq!(small_count, 1);
+
+        sftp.close(5, big.clone()).await.unwrap();
+        assert_eq!(sftp.readdir(6, big).await.unwrap_err(), StatusCode::NoSuchFile);
+    }
+
+    #[tokio::test]
+    async fn test_openssh_extensions() {
+        use russh_sftp::extensions::Statvfs;
+        use russh_sftp::server::Handler;
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("old.txt"), b"old").unwrap();
+        fs::write(dir.path().join("new.txt"), b"new").unwrap();
+        let mut sftp = test_session(dir.path());
+
+        let version = sftp.init(3, HashMap::new()).await.unwrap();
+        assert_eq!(version.extensions.get(extensions::POSIX_RENAME).unwrap(), "1");
+        assert_eq!(version.extensions.get(extensions::STATVFS).unwrap(), "2");
+
+        // 普通的 rename 不覆盖已存在的目标，posix-rename 覆盖
+        let status = sftp
+            .rename(1, "/old.txt".to_string(), "/new.txt".to_string())
+            .await
+            .unwrap();
+        assert_eq!(status.status_code, StatusCode::Failure);
+        assert_eq!(fs::read(dir.path().join("new.txt")).unwrap(), b"new");
+        assert!(dir.path().join("old.txt").exists());
+        let data = russh_sftp::ser::to_bytes(&PosixRenameExtension {
+            oldpath: "/old.txt".to_string(),
+            newpath: "/new.txt".to_string(),
+        })
+        .unwrap()
+        .to_vec();
+        let packet = sftp
+            .extended(1, extensions::POSIX_RENAME.to_string(), data)
+            .await
+            .unwrap();
+        assert!(matches!(packet, Packet::Status(Status { status_code: StatusCode::Ok, .. })));
+        assert_eq!(fs::read(dir.path().join("new.txt")).unwrap(), b"old");
+        assert!(!dir.path().join("old.txt").exists());
+
+        let data: Vec<u8> = StatvfsExtension {
+            path: "/".to_string(),
+        }
+        .try_into()
+        .unwrap();
+        let packet = sftp
+            .extended(2, extensions::STATVFS.to_string(), data)
+            .await
+            .unwrap();
+        let Packet::ExtendedReply(reply) = packet else {
+            panic!("expected extended reply");
+        };
+        let stat: Statvfs = russh_sftp::de::from_bytes(&mut reply.data.into()).unwrap();
+        assert!(stat.block_size > 0);
+        assert!(stat.blocks > 0);
+
+        let handle = sftp
+            .open(3, "/new.txt".to_string(), OpenFlags::READ | OpenFlags::WRITE, FileAttributes::default())
+            .await
+            .unwrap()
+            .handle;
+        let data: Vec<u8> = FsyncExtension {
+            handle: handle.clone(),
+        }
+        .try_into()
+        .unwrap();
+        let packet = sftp.extended(4, extensions::FSYNC.to_string(), data).await.unwrap();
+        assert!(matches!(packet, Packet::Status(Status { status_code: StatusCode::Ok, .. })));
+
+        let data = russh_sftp::ser::to_bytes(&FstatvfsExtension { handle })
+            .unwrap()
+            .to_vec();
+        let packet = sftp
+            .extended(5, extensions::FSTATVFS.to_string(), data)
+            .await
+            .unwrap();
+        assert!(matches!(packet, Packet::ExtendedReply(_)));
+
+        let packet = sftp
+            .extended(6, extensions::LIMITS.to_string(), vec![])
+            .await
+            .unwrap();
+        let Packet::ExtendedReply(reply) = packet else {
+            panic!("expected extended reply");
+        };
+        let limits: LimitsExtension = russh_sftp::de::from_bytes(&mut reply.data.into()).unwrap();
+        assert_eq!(limits.max_packet_len, extensions::MAX_PACKET_LEN);
+        assert_eq!(limits.max_read_len, extensions::MAX_READ_LEN);
+
+        let err = sftp
+            .extended(7, "unknown@example.com".to_string(), vec![])
+            .await
+            .unwrap_err();
+        assert_eq!(err, StatusCode::OpUnsupported);
+    }
+
+    #[tokio::test]
+    async fn test_lsetstat_does_not_follow_symlinks() {
+        use russh_sftp::server::Handler;
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("file.txt"), b"Hello").unwrap();
+        std::os::unix::fs::symlink("file.txt", dir.path().join("link")).unwrap();
+        let target_mtime = fs::metadata(dir.path().join("file.txt")).unwrap().modified().unwrap();
+        let mut sftp = test_session(dir.path());
+
+        let lsetstat = |id: u32, attrs: FileAttributes| {
+            let data = russh_sftp::ser::to_bytes(&LsetstatExtension {
+                path: "/link".to_string(),
+                attrs,
+            })
+            .unwrap()
+            .to_vec();
+            (id, data)
+        };
+        let (id, data) = lsetstat(
+            1,
+            FileAttributes {
+                size: None,
+                uid: None,
+                user: None,
+                gid: None,
+                group: None,
+                permissions: None,
+                atime: Some(1_000_000),
+                mtime: Some(2_000_000),
+            },
+        );
+        let packet = sftp.extended(id, extensions::LSETSTAT.to_string(), data).await.unwrap();
+        assert!(matches!(packet, Packet::Status(Status { status_code: StatusCode::Ok, .. })));
+        assert_eq!(
+            fs::symlink_metadata(dir.path().join("link")).unwrap().modified().unwrap(),
+            std::time::UNIX_EPOCH + Duration::from_secs(2_000_000)
+        );
+        assert_eq!(
+            fs::metadata(dir.path().join("file.txt")).unwrap().modified().unwrap(),
+            target_mtime
+        );
+
+        let (id, data) = lsetstat(
+            2,
+            FileAttributes {
+                size: None,
+                uid: None,
+                user: None,
+                gid: None,
+                group: None,
+                permissions: Some(0o600),
+                atime: None,
+                mtime: None,
+            },
+        );
+        let packet = sftp.extended(id, extensions::LSETSTAT.to_string(), data).await.unwrap();
+        assert!(matches!(
+            packet,
+            Packet::Status(Status { status_code: StatusCode::OpUnsupported, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_copy_data_and_check_file() {
+        use russh_sftp::server::Handler;
+        use sha2::Digest;
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("src.txt"), b"Hello, world!").unwrap();
+        let mut sftp = test_session(dir.path());
+
+        let src = sftp
+            .open(1, "/src.txt".to_string(), OpenFlags::READ, FileAttributes::default())
+            .await
+            .unwrap()
+            .handle;
+        let dst = sftp
+            .open(
+                2,
+                "/dst.txt".to_string(),
+                OpenFlags::WRITE | OpenFlags::CREATE,
+                FileAttributes::default(),
+            )
+            .await
+            .unwrap()
+            .handle;
+        let copy = |id: u32, offset: u64, length: u64, to: &str| {
+            let data = russh_sftp::ser::to_bytes(&CopyDataExtension {
+                read_from_handle: src.clone(),
+                read_from_offset: offset,
+                read_data_length: length,
+                write_to_handle: to.to_string(),
+                write_to_offset: 0,
+            })
+            .unwrap()
+            .to_vec();
+            (id, data)
+        };
+
+        let (id, data) = copy(3, 0, 0, &dst);
+        let packet = sftp.extended(id, extensions::COPY_DATA.to_string(), data).await.unwrap();
+        assert!(matches!(packet, Packet::Status(Status { status_code: StatusCode::Ok, .. })));
+        assert_eq!(fs::read(dir.path().join("dst.txt")).unwrap(), b"Hello, world!");
+
+        // 同一句柄内重叠的范围会被拒绝
+        let (id, data) = copy(4, 0, 5, &src);
+        let packet = sftp.extended(id, extensions::COPY_DATA.to_string(), data).await.unwrap();
+        assert!(matches!(
+            packet,
+            Packet::Status(Status { status_code: StatusCode::BadMessage, .. })
+        ));
+
+        let data = russh_sftp::ser::to_bytes(&CheckFileNameExtension {
+            filename: "/dst.txt".to_string(),
+            hash_algorithms: "sha512,sha256".to_string(),
+            start_offset: 0,
+            length: 0,
+            block_size: 0,
+        })
+        .unwrap()
+        .to_vec();
+        let packet = sftp
+            .extended(5, extensions::CHECK_FILE_NAME.to_string(), data)
+            .await
+            .unwrap();
+        let Packet::ExtendedReply(reply) = packet else {
+            panic!("expected extended reply");
+        };
+        let reply: CheckFileReply = russh_sftp::de::from_bytes(&mut reply.data.into()).unwrap();
+        assert_eq!(reply.extension, extensions::CHECK_FILE);
+        assert_eq!(reply.hash_algorithm, "sha256");
+        assert_eq!(reply.hash, sha2::Sha256::digest(b"Hello, world!").to_vec());
+
+        let data = russh_sftp::ser::to_bytes(&CheckFileHandleExtension {
+            handle: src.clone(),
+            hash_algorithms: "md5".to_string(),
+            start_offset: 7,
+            length: 5,
+            block_size: 0,
+        })
+        .unwrap()
+        .to_vec();
+        let packet = sftp
+            .extended(6, extensions::CHECK_FILE_HANDLE.to_string(), data)
+            .await
+            .unwrap();
+        let Packet::ExtendedReply(reply) = packet else {
+            panic!("expected extended reply");
+        };
+        let reply: CheckFileReply = russh_sftp::de::from_bytes(&mut reply.data.into()).unwrap();
+        assert_eq!(reply.hash, md5::compute(b"world").to_vec());
+
+        let data = russh_sftp::ser::to_bytes(&CheckFileHandleExtension {
+            handle: src,
+            hash_algorithms: "crc32".to_string(),
+            start_offset: 0,
+            length: 0,
+            block_size: 0,
+        })
+        .unwrap()
+        .to_vec();
+        let err = sftp
+            .extended(7, extensions::CHECK_FILE_HANDLE.to_string(), data)
+            .await
+            .unwrap_err();
+        assert_eq!(err, StatusCode::OpUnsupported);
+    }
+
+    #[tokio::test]
+    async fn test_copy_data_clamps_length() {
+        use crate::quota::{get_usage, set_quota, QuotaLimits, Usage};
+        use russh_sftp::server::Handler;
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("src.txt"), b"Hello, world!").unwrap();
+        let pool = MockDatabasePool::get_pool();
+        let conn = pool.get().unwrap();
+        conn.execute(
+            "INSERT INTO Users (username, password, role) VALUES ('test', '', 'user')",
+            [],
+        )
+        .unwrap();
+        let limits = QuotaLimits {
+            max_bytes: Some(30),
+            max_files: None,
+        };
+        set_quota(&conn, Some("test"), None, limits).unwrap();
+        let mut sftp = test_session(dir.path());
+        sftp.quota = Quota::load(pool.clone(), "test", Some(dir.path())).unwrap();
+
+        let src = sftp
+            .open(1, "/src.txt".to_string(), OpenFlags::READ, FileAttributes::default())
+            .await
+            .unwrap()
+            .handle;
+        let dst = sftp
+            .open(
+                2,
+                "/dst.txt".to_string(),
+                OpenFlags::WRITE | OpenFlags::CREATE,
+                FileAttributes::default(),
+            )
+            .await
+            .unwrap()
+            .handle;
+        // 请求复制 1 TiB，只复制源文件中的 13 字节，也只占用 13 字节的配额
+        let data = russh_sftp::ser::to_bytes(&CopyDataExtension {
+            read_from_handle: src,
+            read_from_offset: 0,
+            read_data_length: 1 << 40,
+            write_to_handle: dst,
+            write_to_offset: 0,
+        })
+        .unwrap()
+        .to_vec();
+        let packet = sftp.extended(3, extensions::COPY_DATA.to_string(), data).await.unwrap();
+        assert!(matches!(packet, Packet::Status(Status { status_code: StatusCode::Ok, .. })));
+        assert_eq!(fs::read(dir.path().join("dst.txt")).unwrap(), b"Hello, world!");
+        assert_eq!(get_usage(&conn, "test").unwrap(), Some(Usage { bytes: 26, files: 2 }));
+    }
+
+    #[tokio::test]
+    async fn test_handles_are_unique_and_bounded() {
+        use russh_sftp::server::Handler;
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("file.txt"), b"Hello").unwrap();
+        let mut sftp = test_session(dir.path());
+        sftp.handles = HandleTable::new(2);
+
+        // 请求 id 相同也不会得到相同的句柄
+        let first = sftp
+            .open(1, "/file.txt".to_string(), OpenFlags::READ, FileAttributes::default())
+            .await
+            .unwrap()
+            .handle;
+        let second = sftp.opendir(1, "/".to_string()).await.unwrap().handle;
+        assert_ne!(first, second);
+
+        // 超出上限时不会创建文件
+        let err = sftp
+            .open(
+                2,
+                "/new.txt".to_string(),
+                OpenFlags::WRITE | OpenFlags::CREATE,
+                FileAttributes::default(),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(err, StatusCode::Failure);
+        assert!(!dir.path().join("new.txt").exists());
+
+        let status = sftp.close(3, first.clone()).await.unwrap();
+        assert_eq!(status.status_code, StatusCode::Ok);
+        let status = sftp.close(4, first.clone()).await.unwrap();
+        assert_eq!(status.status_code, StatusCode::NoSuchFile);
+        assert_eq!(sftp.read(5, first.clone(), 0, 5).await.unwrap_err(), StatusCode::NoSuchFile);
+
+        let third = sftp
+            .open(6, "/file.txt".to_string(), OpenFlags::READ, FileAttributes::default())
+            .await
+            .unwrap()
+            .handle;
+        assert_ne!(third, first);
+
+        let packet = sftp.extended(7, extensions::LIMITS.to_string(), vec![]).await.unwrap();
+        let Packet::ExtendedReply(reply) = packet else {
+            panic!("expected extended reply");
+        };
+        let limits: LimitsExtension = russh_sftp::de::from_bytes(&mut reply.data.into()).unwrap();
+        assert_eq!(limits.max_open_handles, 2);
+    }
+
+    #[tokio::test]
+    async fn test_fstat_uses_open_file() {
+        use russh_sftp::server::Handler;
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("docs")).unwrap();
+        fs::write(dir.path().join("docs/file.txt"), b"Hello").unwrap();
+        let mut sftp = test_session(dir.path());
+        sftp.cwd_offset = PathBuf::from("/docs");
+
+        let handle = sftp
+            .open(1, "file.txt".to_string(), OpenFlags::READ, FileAttributes::default())
+            .await
+            .unwrap()
+            .handle;
+        // 文件被删除后句柄仍然指向已打开的文件
+        fs::remove_file(dir.path().join("docs/file.txt")).unwrap();
+        let attrs = sftp.fstat(2, handle).await.unwrap().attrs;
+        assert_eq!(attrs.size, Some(5));
+
+        let handle = sftp.opendir(3, "/docs".to_string()).await.unwrap().handle;
+        let attrs = sftp.fstat(4, handle).await.unwrap().attrs;
+        assert!(attrs.is_dir());
+    }
+
+    #[test]
+    fn test_user_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let ssh = SshSession::<MockDatabasePool>::default();
+        ssh.auther.register("student", "Correct-Horse-9").unwrap();
+        ssh.auther
+            .set_root("student", &dir.path().join("student"), true)
+            .unwrap();
+
+        let (virtual_root, own_root) = ssh.user_root("student").unwrap();
+        assert!(own_root);
+        assert_eq!(
+            virtual_root.get_root(),
+            dir.path().join("student").canonicalize().unwrap()
+        );
+
+        // 根目录被删除后拒绝打开 SFTP 会话
+        fs::remove_dir(dir.path().join("student")).unwrap();
+        assert!(ssh.user_root("student").is_err());
+        assert!(ssh.user_root("nobody").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_totp_second_factor() {
+        use russh::server::Handler;
+
+        let mut ssh = SshSession::<MockDatabasePool>::default();
+        ssh.auther.register("student", "Correct-Horse-9").unwrap();
+        // 未登记两步验证时只需要密码
+        assert_eq!(ssh.auth_password("student", "Correct-Horse-9").await.unwrap(), Auth::Accept);
+
+        let enrollment = crate::totp::enroll(&ssh.pool.get().unwrap(), "student").unwrap();
+        assert_eq!(
+            ssh.auth_password("student", "Correct-Horse-9").await.unwrap(),
+            Auth::Reject {
+                proceed_with_methods: Some(MethodSet::KEYBOARD_INTERACTIVE)
+            }
+        );
+        // 没有通过密码的用户不会得到验证码提示
+        assert_eq!(
+            ssh.auth_keyboard_interactive("admin", "", None).await.unwrap(),
+            reject()
+        );
+        assert!(matches!(
+            ssh.auth_keyboard_interactive("student", "", None).await.unwrap(),
+            Auth::Partial { .. }
+        ));
+        assert!(ssh.auther.verify_second_factor("student", "000000").is_err());
+        assert!(ssh
+            .auther
+            .verify_second_factor("student", &enrollment.recovery_codes[0])
+            .is_ok());
+        // 密码错误时不会进入第二步
+        assert_eq!(ssh.auth_password("student", "wrong").await.unwrap(), reject());
+
+        // 公钥登录同样需要验证码
+        let key = KeyPair::generate_ed25519().unwrap().clone_public_key().unwrap();
+        crate::authorized_keys::add_key(&ssh.pool.get().unwrap(), "student", &key, None, None).unwrap();
+        assert_eq!(
+            ssh.auth_publickey("student", &key).await.unwrap(),
+            Auth::Reject {
+                proceed_with_methods: Some(MethodSet::KEYBOARD_INTERACTIVE)
+            }
+        );
+        assert!(matches!(
+            ssh.auth_keyboard_interactive("student", "", None).await.unwrap(),
+            Auth::Partial { .. }
+        ));
+        crate::totp::disable(&ssh.pool.get().unwrap(), "student").unwrap();
+        assert_eq!(ssh.auth_publickey("student", &key).await.unwrap(), Auth::Accept);
+    }
+
+    #[tokio::test]
+    async fn test_failed_logins_lock_account() {
+        use crate::lockout::{clear_ban, BanKind};
+        use russh::server::Handler;
+
+        let mut ssh = SshSession::<MockDatabasePool> {
+            client_addr: Some("192.0.2.1:50022".parse().unwrap()),
+            ..Default::default()
+        };
+        ssh.guard = LoginGuard::new(
+            ssh.pool.clone(),
+            LockoutPolicy {
+                max_user_failures: 2,
+                max_addr_failures: 10,
+                window: 600,
+                base_lockout: 60,
+                max_lockout: 3600,
+            },
+        );
+        ssh.auther.register("student", "Correct-Horse-9").unwrap();
+        for _ in 0..2 {
+            assert_eq!(ssh.auth_password("student", "wrong").await.unwrap(), reject());
+        }
+        // 封禁期间正确的密码也会被拒绝
+        assert_eq!(ssh.auth_password("student", "Correct-Horse-9").await.unwrap(), reject());
+        assert!(ssh.guard.banned("student", None).unwrap().is_some());
+
+        clear_ban(&ssh.pool.get().unwrap(), BanKind::User, "student").unwrap();
+        assert_eq!(ssh.auth_password("student", "Correct-Horse-9").await.unwrap(), Auth::Accept);
+
+        // 无法查询封禁记录时拒绝登录，而不是放行
+        let key = KeyPair::generate_ed25519().unwrap().clone_public_key().unwrap();
+        crate::authorized_keys::add_key(&ssh.pool.get().unwrap(), "student", &key, None, None).unwrap();
+        assert_eq!(ssh.auth_publickey_offered("student", &key).await.unwrap(), Auth::Accept);
+        ssh.pool.get().unwrap().execute("DROP TABLE Bans", []).unwrap();
+        assert_eq!(ssh.auth_password("student", "Correct-Horse-9").await.unwrap(), reject());
+        assert_eq!(ssh.auth_publickey_offered("student", &key).await.unwrap(), reject());
+        assert_eq!(ssh.auth_publickey("student", &key).await.unwrap(), reject());
+    }
+
+    #[tokio::test]
+    async fn test_access_rules_are_enforced() {
+        use crate::acl::Rights;
+        use russh_sftp::server::Handler;
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("shared")).unwrap();
+        fs::write(dir.path().join("shared/notes.txt"), b"notes").unwrap();
+        std::os::unix::fs::symlink("shared", dir.path().join("alias")).unwrap();
+
+        let pool = MockDatabasePool::get_pool();
+        let conn = pool.get().unwrap();
+        conn.execute(
+            "INSERT INTO Users (username, password, role) VALUES ('test', '', 'user')",
+            [],
+        )
+        .unwrap();
+        let read_only: Rights = "read,list".parse().unwrap();
+        crate::acl::add_rule(&conn, None, Some("user"), Path::new("/shared"), read_only).unwrap();
+        let mut sftp = test_session(dir.path());
+        sftp.acl = Acl::load(&conn, "test").unwrap();
+
+        let handle = sftp
+            .open(1, "/shared/notes.txt".to_string(), OpenFlags::READ, FileAttributes::default())
+            .await
+            .unwrap()
+            .handle;
+        assert!(!handle.is_empty());
+        let dir_handle = sftp.opendir(2, "/shared".to_string()).await.unwrap().handle;
+
+        // 通过只读的文件句柄或目录句柄也不能修改属性
+        let chmod = FileAttributes {
+            permissions: Some(0o777),
+            ..no_attrs()
+        };
+        for handle in [handle.clone(), dir_handle] {
+            let status = sftp.fsetstat(2, handle, chmod.clone()).await.unwrap();
+            assert_eq!(status.status_code, StatusCode::PermissionDenied);
+        }
+        let status = sftp.setstat(2, "/shared/notes.txt".to_string(), chmod).await.unwrap();
+        assert_eq!(status.status_code, StatusCode::PermissionDenied);
+
+        let status = sftp.remove(3, "/shared/notes.txt".to_string()).await.unwrap();
+        assert_eq!(status.status_code, StatusCode::PermissionDenied);
+        // 经过符号链接同样受到限制
+        let status = sftp.remove(4, "/alias/notes.txt".to_string()).await.unwrap();
+        assert_eq!(status.status_code, StatusCode::PermissionDenied);
+        assert!(dir.path().join("shared/notes.txt").exists());
+
+        let err = sftp
+            .open(
+                5,
+                "/shared/new.txt".to_string(),
+                OpenFlags::WRITE | OpenFlags::CREATE,
+                FileAttributes::default(),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(err, StatusCode::PermissionDenied);
+        assert!(!dir.path().join("shared/new.txt").exists());
+
+        let status = sftp
+            .mkdir(6, "/shared/sub".to_string(), FileAttributes::default())
+            .await
+            .unwrap();
+        assert_eq!(status.status_code, StatusCode::PermissionDenied);
+
+        // 没有规则的路径不受限制
+        let status = sftp
+            .mkdir(7, "/mine".to_string(), FileAttributes::default())
+            .await
+            .unwrap();
+        assert_eq!(status.status_code, StatusCode::Ok);
+        assert!(sftp.realpath(8, "/shared".to_string()).await.is_ok());
+
+        // 不能把只读的文件硬链接到可写的位置再通过链接修改
+        let status = sftp
+            .hardlink(9, "/shared/notes.txt".to_string(), "/mine/notes.txt".to_string())
+            .unwrap();
+        assert_eq!(status.status_code, StatusCode::PermissionDenied);
+        assert!(!dir.path().join("mine/notes.txt").exists());
+
+        // 不能通过重命名上级目录把受限的子目录移出规则的作用范围
+        fs::create_dir(dir.path().join("mine/secret")).unwrap();
+        fs::write(dir.path().join("mine/secret/key.txt"), b"key").unwrap();
+        crate::acl::add_rule(&conn, Some("test"), None, Path::new("/mine/secret"), Rights::default())
+            .unwrap();
+        sftp.acl = Acl::load(&conn, "test").unwrap();
+        let status = sftp
+            .rename(10, "/mine".to_string(), "/moved".to_string())
+            .await
+            .unwrap();
+        assert_eq!(status.status_code, StatusCode::PermissionDenied);
+        let status = sftp
+            .posix_rename(11, "/mine".to_string(), "/moved".to_string())
+            .unwrap();
+        assert_eq!(status.status_code, StatusCode::PermissionDenied);
+        assert!(dir.path().join("mine/secret/key.txt").exists());
+        assert!(!dir.path().join("moved").exists());
+        // realpath 仍然可以解析没有权限的路径，但不返回它的属性
+        let name = sftp.realpath(13, "/mine/secret/key.txt".to_string()).await.unwrap();
+        assert_eq!(name.files[0].filename, "/mine/secret/key.txt");
+        assert_eq!(name.files[0].longname, "/mine/secret/key.txt");
+        assert_eq!(name.files[0].attrs.size, None);
+        assert_eq!(name.files[0].attrs.permissions, None);
+        let name = sftp.realpath(14, "/shared/notes.txt".to_string()).await.unwrap();
+        assert_eq!(name.files[0].attrs.size, Some(5));
+        // 规则之外的条目仍然可以移动
+        fs::write(dir.path().join("mine/a.txt"), b"a").unwrap();
+        let status = sftp
+            .rename(15, "/mine/a.txt".to_string(), "/mine/b.txt".to_string())
+            .await
+            .unwrap();
+        assert_eq!(status.status_code, StatusCode::Ok);
+    }
+
+    #[tokio::test]
+    async fn test_quota_is_enforced() {
+        use crate::quota::{self, get_usage, set_quota, QuotaLimits, Usage};
+        use russh_sftp::server::Handler;
+
+        let dir = tempfile::tempdir().unwrap();
+        let pool = MockDatabasePool::get_pool();
+        let conn = pool.get().unwrap();
+        conn.execute(
+            "INSERT INTO Users (username, password, role) VALUES ('test', '', 'user')",
+            [],
+        )
+        .unwrap();
+        let limits = QuotaLimits {
+            max_bytes: Some(10),
+            max_files: Some(2),
+        };
+        set_quota(&conn, Some("test"), None, limits).unwrap();
+        let mut sftp = test_session(dir.path());
+        sftp.quota = Quota::load(pool.clone(), "test", Some(dir.path())).unwrap();
+
+        let handle = sftp
+            .open(
+                1,
+                "/file.txt".to_string(),
+                OpenFlags::WRITE | OpenFlags::CREATE,
+                FileAttributes::default(),
+            )
+            .await
+            .unwrap()
+            .handle;
+        let status = sftp.write(2, handle.clone(), 0, b"12345678".to_vec()).await.unwrap();
+        assert_eq!(status.status_code, StatusCode::Ok);
+        // 覆盖已有的数据不占用新的配额
+        let status = sftp.write(3, handle.clone(), 0, b"abcd".to_vec()).await.unwrap();
+        assert_eq!(status.status_code, StatusCode::Ok);
+        let status = sftp.write(4, handle.clone(), 8, b"xyz".to_vec()).await.unwrap();
+        assert_eq!(status.status_code, StatusCode::Failure);
+        assert!(status.error_message.contains("quota"));
+        assert_eq!(fs::metadata(dir.path().join("file.txt")).unwrap().len(), 8);
+        // 溢出的偏移量和超出 i64 的增长都会被拒绝，不会减少用量
+        for offset in [u64::MAX - 1, u64::MAX - 10] {
+            let status = sftp.write(10, handle.clone(), offset, b"xyz".to_vec()).await.unwrap();
+            assert_eq!(status.status_code, StatusCode::BadMessage);
+        }
+
+        let status = sftp
+            .mkdir(5, "/dir".to_string(), FileAttributes::default())
+            .await
+            .unwrap();
+        assert_eq!(status.status_code, StatusCode::Ok);
+        let err = sftp
+            .open(
+                6,
+                "/other.txt".to_string(),
+                OpenFlags::WRITE | OpenFlags::CREATE,
+                FileAttributes::default(),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(err, StatusCode::Failure);
+        assert!(!dir.path().join("other.txt").exists());
+        assert_eq!(get_usage(&conn, "test").unwrap(), Some(Usage { bytes: 8, files: 2 }));
+
+        sftp.close(7, handle).await.unwrap();
+        sftp.remove(8, "/file.txt".to_string()).await.unwrap();
+        sftp.rmdir(9, "/dir".to_string()).await.unwrap();
+        assert_eq!(get_usage(&conn, "test").unwrap(), Some(Usage { bytes: 0, files: 0 }));
+
+        // 硬链接和符号链接占用文件配额，删除硬链接不会归还原文件的字节
+        fs::write(dir.path().join("a.txt"), b"123456").unwrap();
+        sftp.quota.reserve(6, 1).unwrap();
+        let status = sftp.hardlink(11, "/a.txt".to_string(), "/b.txt".to_string()).unwrap();
+        assert_eq!(status.status_code, StatusCode::Ok);
+        assert_eq!(get_usage(&conn, "test").unwrap(), Some(Usage { bytes: 6, files: 2 }));
+        let status = sftp.symlink(12, "a.txt".to_string(), "/link".to_string()).await.unwrap();
+        assert_eq!(status.status_code, StatusCode::Failure);
+        assert!(fs::symlink_metadata(dir.path().join("link")).is_err());
+        sftp.remove(13, "/b.txt".to_string()).await.unwrap();
+        assert_eq!(get_usage(&conn, "test").unwrap(), Some(Usage { bytes: 6, files: 1 }));
+        let status = sftp.symlink(14, "a.txt".to_string(), "/link".to_string()).await.unwrap();
+        assert_eq!(status.status_code, StatusCode::Ok);
+        assert_eq!(get_usage(&conn, "test").unwrap(), Some(Usage { bytes: 6, files: 2 }));
+        sftp.remove(15, "/link".to_string()).await.unwrap();
+        assert_eq!(get_usage(&conn, "test").unwrap(), Some(Usage { bytes: 6, files: 1 }));
+
+        // setstat 修改大小同样受配额限制
+        let resize = |size: u64| FileAttributes {
+            size: Some(size),
+            ..no_attrs()
+        };
+        let status = sftp.setstat(16, "/a.txt".to_string(), resize(20)).await.unwrap();
+        assert_eq!(status.status_code, StatusCode::Failure);
+        assert_eq!(fs::metadata(dir.path().join("a.txt")).unwrap().len(), 6);
+        let status = sftp.setstat(17, "/a.txt".to_string(), resize(10)).await.unwrap();
+        assert_eq!(status.status_code, StatusCode::Ok);
+        assert_eq!(get_usage(&conn, "test").unwrap(), Some(Usage { bytes: 10, files: 1 }));
+        let status = sftp.setstat(18, "/a.txt".to_string(), resize(2)).await.unwrap();
+        assert_eq!(status.status_code, StatusCode::Ok);
+        assert_eq!(get_usage(&conn, "test").unwrap(), Some(Usage { bytes: 2, files: 1 }));
+
+        // posix-rename 覆盖的文件归还它占用的配额
+        fs::write(dir.path().join("c.txt"), b"123").unwrap();
+        sftp.quota.reserve(3, 1).unwrap();
+        let status = sftp.posix_rename(19, "/c.txt".to_string(), "/a.txt".to_string()).unwrap();
+        assert_eq!(status.status_code, StatusCode::Ok);
+        assert_eq!(get_usage(&conn, "test").unwrap(), Some(Usage { bytes: 3, files: 1 }));
+        assert_eq!(
+            quota::measure(dir.path()).unwrap(),
+            get_usage(&conn, "test").unwrap().unwrap()
+        );
+
+        // 硬链接共享同一份数据，通过任何一个链接截断都会释放全部字节
+        fs::hard_link(dir.path().join("a.txt"), dir.path().join("b.txt")).unwrap();
+        sftp.quota.reserve(0, 1).unwrap();
+        let flags = OpenFlags::WRITE | OpenFlags::TRUNCATE;
+        sftp.open(20, "/b.txt".to_string(), flags, no_attrs()).await.unwrap();
+        assert_eq!(fs::metadata(dir.path().join("a.txt")).unwrap().len(), 0);
+        assert_eq!(get_usage(&conn, "test").unwrap(), Some(Usage { bytes: 0, files: 2 }));
+        assert_eq!(
+            quota::measure(dir.path()).unwrap(),
+            get_usage(&conn, "test").unwrap().unwrap()
+        );
+    }
 }
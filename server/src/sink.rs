@@ -0,0 +1,307 @@
+//! 审计日志的输出目标：SQLite、按天轮转的 JSON Lines 文件和 RFC 5424 syslog，
+//! 由 AUDIT_SINKS 选择，可以同时使用多个
+use std::env;
+use std::fmt::Write as _;
+use std::io::Write;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use chrono::NaiveDateTime;
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+
+use crate::audit::{AuditEntry, DatabaseSink, TIMESTAMP_FORMAT};
+
+/// syslog 的 facility：authpriv（10），严重程度：informational（6）
+const SYSLOG_PRIORITY: u8 = 10 * 8 + 6;
+
+/// 结构化数据的 SD-ID，32473 是 RFC 5612 中保留给文档示例的企业编号
+const SYSLOG_SD_ID: &str = "audit@32473";
+
+/// 审计日志的一个输出目标，由后台线程成批调用
+pub trait AuditSink: Send {
+    /// 出错时显示的名称
+    fn name(&self) -> &'static str;
+
+    fn write(&mut self, batch: &[AuditEntry]) -> Result<()>;
+}
+
+/// syslog 服务器的地址
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyslogAddr {
+    Udp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl SyslogAddr {
+    /// 解析 `udp://HOST:PORT` 或 `unix:///PATH`
+    pub fn parse(addr: &str) -> Result<Self> {
+        if let Some(host) = addr.strip_prefix("udp://") {
+            let addr = host
+                .to_socket_addrs()
+                .with_context(|| format!("Invalid syslog address: {}", addr))?
+                .next()
+                .with_context(|| format!("Syslog address {} did not resolve", addr))?;
+            Ok(SyslogAddr::Udp(addr))
+        } else if let Some(path) = addr.strip_prefix("unix://") {
+            Ok(SyslogAddr::Unix(PathBuf::from(path)))
+        } else {
+            Err(anyhow::anyhow!("Invalid syslog address, expected udp://HOST:PORT or unix:///PATH: {}", addr))
+        }
+    }
+}
+
+/// 一个输出目标的配置
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SinkConfig {
+    Sqlite,
+    /// 写入这个目录下的 audit.YYYY-MM-DD.jsonl
+    Jsonl(PathBuf),
+    Syslog(SyslogAddr),
+}
+
+impl SinkConfig {
+    /// 从环境变量读取：AUDIT_SINKS（逗号分隔的 sqlite、jsonl、syslog，默认为 sqlite）、
+    /// AUDIT_JSONL_DIR 和 AUDIT_SYSLOG_ADDR
+    pub fn from_env() -> Result<Vec<Self>> {
+        let names = env::var("AUDIT_SINKS").unwrap_or("sqlite".to_string());
+        let mut sinks = vec![];
+        for name in names.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+            let sink = match name {
+                "sqlite" => SinkConfig::Sqlite,
+                "jsonl" => SinkConfig::Jsonl(PathBuf::from(
+                    env::var("AUDIT_JSONL_DIR").unwrap_or("audit_logs".to_string()),
+                )),
+                "syslog" => SinkConfig::Syslog(SyslogAddr::parse(
+                    &env::var("AUDIT_SYSLOG_ADDR").unwrap_or("unix:///dev/log".to_string()),
+                )?),
+                _ => return Err(anyhow::anyhow!("Unknown audit sink: {}", name)),
+            };
+            if !sinks.contains(&sink) {
+                sinks.push(sink);
+            }
+        }
+        if sinks.is_empty() {
+            return Err(anyhow::anyhow!("AUDIT_SINKS must name at least one audit sink"));
+        }
+        Ok(sinks)
+    }
+
+    pub fn open(&self, pool: &Arc<Pool<SqliteConnectionManager>>) -> Result<Box<dyn AuditSink>> {
+        Ok(match self {
+            SinkConfig::Sqlite => Box::new(DatabaseSink::new(pool.clone())),
+            SinkConfig::Jsonl(dir) => Box::new(JsonlSink::new(dir.clone())?),
+            SinkConfig::Syslog(addr) => Box::new(SyslogSink::new(addr.clone())?),
+        })
+    }
+}
+
+/// 每行一条 JSON 记录，每天一个文件
+pub struct JsonlSink {
+    writer: RollingFileAppender,
+}
+
+impl JsonlSink {
+    pub fn new(dir: PathBuf) -> Result<Self> {
+        let writer = RollingFileAppender::builder()
+            .rotation(Rotation::DAILY)
+            .filename_prefix("audit")
+            .filename_suffix("jsonl")
+            .build(&dir)
+            .with_context(|| format!("Failed to open audit log directory {}", dir.display()))?;
+        Ok(Self { writer })
+    }
+}
+
+impl AuditSink for JsonlSink {
+    fn name(&self) -> &'static str {
+        "jsonl"
+    }
+
+    fn write(&mut self, batch: &[AuditEntry]) -> Result<()> {
+        for entry in batch {
+            // 一行一次写入，轮转不会把一行拆到两个文件中
+            let mut line = serde_json::to_vec(entry)?;
+            line.push(b'\n');
+            self.writer.write_all(&line)?;
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+enum SyslogSocket {
+    Udp(UdpSocket, SocketAddr),
+    Unix(UnixDatagram, PathBuf),
+}
+
+/// 每条记录发送一个 RFC 5424 格式的数据报，字段放在结构化数据中
+pub struct SyslogSink {
+    socket: SyslogSocket,
+    hostname: String,
+}
+
+impl SyslogSink {
+    pub fn new(addr: SyslogAddr) -> Result<Self> {
+        let socket = match addr {
+            SyslogAddr::Udp(addr) => {
+                let local = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+                SyslogSocket::Udp(UdpSocket::bind(local)?, addr)
+            }
+            SyslogAddr::Unix(path) => SyslogSocket::Unix(UnixDatagram::unbound()?, path),
+        };
+        Ok(Self {
+            socket,
+            hostname: hostname().unwrap_or("-".to_string()),
+        })
+    }
+
+    fn format(&self, entry: &AuditEntry) -> String {
+        let timestamp = entry
+            .created_at
+            .as_deref()
+            .and_then(|time| NaiveDateTime::parse_from_str(time, TIMESTAMP_FORMAT).ok())
+            .map_or("-".to_string(), |time| time.format("%Y-%m-%dT%H:%M:%SZ").to_string());
+        let mut message = format!(
+            "<{}>1 {} {} sshfs-rs {} audit [{}",
+            SYSLOG_PRIORITY,
+            timestamp,
+            self.hostname,
+            std::process::id(),
+            SYSLOG_SD_ID
+        );
+        let bytes = entry.bytes.map(|bytes| bytes.to_string());
+        let duration_us = entry.duration_us.map(|duration| duration.to_string());
+        let params = [
+            ("username", Some(&entry.username)),
+            ("action", Some(&entry.action)),
+            ("target", Some(&entry.target)),
+            ("result", Some(&entry.result)),
+            ("session_id", entry.session_id.as_ref()),
+            ("client_addr", entry.client_addr.as_ref()),
+            ("secondary_target", entry.secondary_target.as_ref()),
+            ("bytes", bytes.as_ref()),
+            ("duration_us", duration_us.as_ref()),
+        ];
+        for (name, value) in params {
+            if let Some(value) = value {
+                // PARAM-VALUE 中的 "、\ 和 ] 需要转义
+                let value = value.replace('\\', "\\\\").replace('"', "\\\"").replace(']', "\\]");
+                let _ = write!(message, " {}=\"{}\"", name, value);
+            }
+        }
+        let _ = write!(
+            message,
+            "] {} {} {}: {}",
+            entry.username, entry.action, entry.target, entry.result
+        );
+        message
+    }
+}
+
+impl AuditSink for SyslogSink {
+    fn name(&self) -> &'static str {
+        "syslog"
+    }
+
+    fn write(&mut self, batch: &[AuditEntry]) -> Result<()> {
+        for entry in batch {
+            let message = self.format(entry);
+            match &self.socket {
+                SyslogSocket::Udp(socket, addr) => socket.send_to(message.as_bytes(), addr)?,
+                SyslogSocket::Unix(socket, path) => socket.send_to(message.as_bytes(), path)?,
+            };
+        }
+        Ok(())
+    }
+}
+
+fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    // SAFETY: buf 可写，长度正确；gethostname 截断时不保证以 0 结尾，所以最后一个字节始终保留为 0
+    let ret = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len() - 1) };
+    if ret != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|byte| *byte == 0)?;
+    String::from_utf8(buf[..len].to_vec())
+        .ok()
+        .filter(|name| !name.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn entry(target: &str) -> AuditEntry {
+        AuditEntry {
+            username: "admin".to_string(),
+            action: "Rename".to_string(),
+            target: target.to_string(),
+            result: "Ok".to_string(),
+            secondary_target: Some("b \"]\\.txt".to_string()),
+            bytes: Some(5),
+            created_at: Some("2024-05-01 12:00:00".to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_jsonl_sink() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut sink = JsonlSink::new(dir.path().join("audit")).unwrap();
+        sink.write(&[entry("a.txt"), entry("c.txt")]).unwrap();
+
+        let files: Vec<_> = fs::read_dir(dir.path().join("audit"))
+            .unwrap()
+            .map(|file| file.unwrap().path())
+            .collect();
+        assert_eq!(files.len(), 1);
+        let name = files[0].file_name().unwrap().to_str().unwrap().to_string();
+        assert!(name.starts_with("audit.") && name.ends_with(".jsonl"), "{}", name);
+        let entries: Vec<AuditEntry> = fs::read_to_string(&files[0])
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].target, "c.txt");
+        assert_eq!(entries[1].bytes, Some(5));
+    }
+
+    #[test]
+    fn test_syslog_sink() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = SyslogAddr::parse(&format!("udp://{}", server.local_addr().unwrap())).unwrap();
+        let mut sink = SyslogSink::new(addr).unwrap();
+        sink.write(&[entry("a.txt")]).unwrap();
+
+        let mut buf = [0u8; 2048];
+        let len = server.recv(&mut buf).unwrap();
+        let message = String::from_utf8(buf[..len].to_vec()).unwrap();
+        assert!(message.starts_with("<86>1 2024-05-01T12:00:00Z "), "{}", message);
+        assert!(
+            message.contains(" sshfs-rs ") && message.contains(" audit [audit@32473 username=\"admin\""),
+            "{}",
+            message
+        );
+        assert!(message.contains(r#"secondary_target="b \"\]\\.txt" bytes="5"]"#), "{}", message);
+        assert!(message.ends_with("] admin Rename a.txt: Ok"), "{}", message);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log");
+        let server = UnixDatagram::bind(&path).unwrap();
+        let mut sink = SyslogSink::new(SyslogAddr::parse(&format!("unix://{}", path.display())).unwrap()).unwrap();
+        sink.write(&[entry("a.txt")]).unwrap();
+        let len = server.recv(&mut buf).unwrap();
+        assert!(buf[..len].starts_with(b"<86>1 "));
+
+        assert!(SyslogAddr::parse("tcp://127.0.0.1:514").is_err());
+    }
+}
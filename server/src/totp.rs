@@ -0,0 +1,268 @@
+//! 基于 TOTP（RFC 6238）的两步验证和一次性恢复码
+use std::env;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use qrcode::render::unicode;
+use qrcode::QrCode;
+use rand::distributions::{Alphanumeric, DistString};
+use rusqlite::{params, Connection, OptionalExtension};
+use sha2::{Digest, Sha256};
+use totp_rs::{Algorithm, Secret, TOTP};
+
+/// 验证码的时间步长（秒），与常见的验证器应用一致
+const STEP: u64 = 30;
+/// 允许前后各一个时间步的时钟偏差
+const SKEW: u64 = 1;
+/// 每次生成的恢复码数量
+const RECOVERY_CODE_COUNT: usize = 10;
+
+/// 登记两步验证后需要交给用户的信息
+pub struct Enrollment {
+    /// base32 编码的密钥，可以手动输入到验证器应用中
+    pub secret: String,
+    /// otpauth:// URI，可以生成二维码扫描
+    pub uri: String,
+    pub recovery_codes: Vec<String>,
+}
+
+fn issuer() -> String {
+    env::var("TOTP_ISSUER").unwrap_or("sshfs-rs".to_string())
+}
+
+fn build_totp(secret: &str, username: &str) -> Result<TOTP> {
+    let secret = Secret::Encoded(secret.to_string())
+        .to_bytes()
+        .map_err(|err| anyhow::anyhow!("Invalid TOTP secret: {:?}", err))?;
+    TOTP::new(
+        Algorithm::SHA1,
+        6,
+        SKEW as u8,
+        STEP,
+        secret,
+        Some(issuer()),
+        username.to_string(),
+    )
+    .map_err(|err| anyhow::anyhow!("Invalid TOTP parameters: {:?}", err))
+}
+
+fn hash_recovery_code(code: &str) -> String {
+    let code = code.trim().to_lowercase().replace('-', "");
+    format!("{:x}", Sha256::digest(code.as_bytes()))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or_default()
+}
+
+/// 为用户生成新的密钥和恢复码，已经登记过时会替换旧的密钥
+pub fn enroll(conn: &Connection, username: &str) -> Result<Enrollment> {
+    let secret = match Secret::generate_secret().to_encoded() {
+        Secret::Encoded(secret) => secret,
+        Secret::Raw(_) => unreachable!("to_encoded always returns an encoded secret"),
+    };
+    let totp = build_totp(&secret, username)?;
+    let updated = conn.execute(
+        "UPDATE Users SET totp_secret = ?, totp_last_step = NULL WHERE username = ?",
+        params![secret, username],
+    )?;
+    if updated == 0 {
+        return Err(anyhow::anyhow!("No such user: {}", username));
+    }
+    let recovery_codes = regenerate_recovery_codes(conn, username)?;
+    Ok(Enrollment {
+        secret,
+        uri: totp.get_url(),
+        recovery_codes,
+    })
+}
+
+/// 关闭两步验证并删除恢复码
+pub fn disable(conn: &Connection, username: &str) -> Result<()> {
+    let updated = conn.execute(
+        "UPDATE Users SET totp_secret = NULL, totp_last_step = NULL WHERE username = ?",
+        params![username],
+    )?;
+    if updated == 0 {
+        return Err(anyhow::anyhow!("No such user: {}", username));
+    }
+    conn.execute("DELETE FROM RecoveryCodes WHERE username = ?", params![username])?;
+    Ok(())
+}
+
+pub fn is_enrolled(conn: &Connection, username: &str) -> Result<bool> {
+    let secret: Option<Option<String>> = conn
+        .query_row(
+            "SELECT totp_secret FROM Users WHERE username = ?",
+            params![username],
+            |row| row.get(0),
+        )
+        .optional()?;
+    Ok(matches!(secret, Some(Some(_))))
+}
+
+/// 作废旧的恢复码并生成一组新的，明文只在这里返回一次
+pub fn regenerate_recovery_codes(conn: &Connection, username: &str) -> Result<Vec<String>> {
+    if !is_enrolled(conn, username)? {
+        return Err(anyhow::anyhow!(
+            "{} has not enrolled in two-factor authentication",
+            username
+        ));
+    }
+    conn.execute("DELETE FROM RecoveryCodes WHERE username = ?", params![username])?;
+    let mut rng = rand::thread_rng();
+    let mut codes = vec![];
+    for _ in 0..RECOVERY_CODE_COUNT {
+        let code = Alphanumeric.sample_string(&mut rng, 10).to_lowercase();
+        let code = format!("{}-{}", &code[..5], &code[5..]);
+        conn.execute(
+            "INSERT INTO RecoveryCodes (username, code_hash) VALUES (?, ?)",
+            params![username, hash_recovery_code(&code)],
+        )?;
+        codes.push(code);
+    }
+    Ok(codes)
+}
+
+/// 检查验证码或恢复码。每个时间步的验证码和每个恢复码都只能使用一次
+pub fn verify(conn: &Connection, username: &str, code: &str) -> Result<bool> {
+    verify_at(conn, username, code, now())
+}
+
+fn verify_at(conn: &Connection, username: &str, code: &str, time: u64) -> Result<bool> {
+    let (secret, last_step): (Option<String>, Option<i64>) = conn
+        .query_row(
+            "SELECT totp_secret, totp_last_step FROM Users WHERE username = ?",
+            params![username],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?
+        .with_context(|| format!("No such user: {}", username))?;
+    let Some(secret) = secret else {
+        return Ok(false);
+    };
+    let code = code.trim();
+
+    let totp = build_totp(&secret, username)?;
+    let current = time / STEP;
+    for step in current.saturating_sub(SKEW)..=current + SKEW {
+        if last_step.is_some_and(|last| step as i64 <= last) {
+            continue;
+        }
+        if totp.generate(step * STEP) == code {
+            return consume_step(conn, username, step as i64);
+        }
+    }
+
+    let used = conn.execute(
+        "UPDATE RecoveryCodes SET used_at = CURRENT_TIMESTAMP
+            WHERE username = ? AND code_hash = ? AND used_at IS NULL",
+        params![username, hash_recovery_code(code)],
+    )?;
+    Ok(used > 0)
+}
+
+/// 记录已经使用的时间步。在同一条语句中检查和更新，两个并发的登录用同一个验证码时只有一个成功
+fn consume_step(conn: &Connection, username: &str, step: i64) -> Result<bool> {
+    let updated = conn.execute(
+        "UPDATE Users SET totp_last_step = ?1
+            WHERE username = ?2 AND (totp_last_step IS NULL OR totp_last_step < ?1)",
+        params![step, username],
+    )?;
+    Ok(updated == 1)
+}
+
+/// 把 otpauth URI 渲染为可以在终端中扫描的二维码
+pub fn render_qr(uri: &str) -> Result<String> {
+    let code = QrCode::new(uri.as_bytes()).context("Failed to encode QR code")?;
+    Ok(code
+        .render::<unicode::Dense1x2>()
+        .dark_color(unicode::Dense1x2::Light)
+        .light_color(unicode::Dense1x2::Dark)
+        .build())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{DatabasePool, MockDatabasePool};
+
+    fn current_code(conn: &Connection, username: &str, time: u64) -> String {
+        let secret: String = conn
+            .query_row(
+                "SELECT totp_secret FROM Users WHERE username = ?",
+                params![username],
+                |row| row.get(0),
+            )
+            .unwrap();
+        build_totp(&secret, username).unwrap().generate(time)
+    }
+
+    #[test]
+    fn test_enroll_and_verify() {
+        let pool = MockDatabasePool::get_pool();
+        let conn = pool.get().unwrap();
+        assert!(!is_enrolled(&conn, "admin").unwrap());
+        assert!(!verify(&conn, "admin", "123456").unwrap());
+        assert!(enroll(&conn, "nobody").is_err());
+        assert!(regenerate_recovery_codes(&conn, "admin").is_err());
+
+        let enrollment = enroll(&conn, "admin").unwrap();
+        assert!(is_enrolled(&conn, "admin").unwrap());
+        assert!(enrollment.uri.starts_with("otpauth://totp/"));
+        assert!(enrollment.uri.contains(&enrollment.secret));
+        assert_eq!(enrollment.recovery_codes.len(), RECOVERY_CODE_COUNT);
+
+        let time = 1_700_000_000;
+        let code = current_code(&conn, "admin", time);
+        assert!(!verify_at(&conn, "admin", "000000x", time).unwrap());
+        assert!(verify_at(&conn, "admin", &code, time).unwrap());
+        // 同一个验证码不能重放
+        assert!(!verify_at(&conn, "admin", &code, time).unwrap());
+        // 允许一个时间步的偏差
+        let next = current_code(&conn, "admin", time + STEP);
+        assert!(verify_at(&conn, "admin", &next, time).unwrap());
+        let late = current_code(&conn, "admin", time + 5 * STEP);
+        assert!(!verify_at(&conn, "admin", &late, time + STEP).unwrap());
+    }
+
+    #[test]
+    fn test_consume_step() {
+        let pool = MockDatabasePool::get_pool();
+        let conn = pool.get().unwrap();
+        enroll(&conn, "admin").unwrap();
+        assert!(consume_step(&conn, "admin", 10).unwrap());
+        // 另一个登录已经用掉了这个时间步或之后的时间步
+        assert!(!consume_step(&conn, "admin", 10).unwrap());
+        assert!(!consume_step(&conn, "admin", 9).unwrap());
+        assert!(consume_step(&conn, "admin", 11).unwrap());
+        assert!(!consume_step(&conn, "nobody", 12).unwrap());
+    }
+
+    #[test]
+    fn test_recovery_codes() {
+        let pool = MockDatabasePool::get_pool();
+        let conn = pool.get().unwrap();
+        let enrollment = enroll(&conn, "admin").unwrap();
+        let code = &enrollment.recovery_codes[0];
+        assert!(verify(&conn, "admin", &code.to_uppercase()).unwrap());
+        assert!(!verify(&conn, "admin", code).unwrap());
+
+        let codes = regenerate_recovery_codes(&conn, "admin").unwrap();
+        assert!(!verify(&conn, "admin", &enrollment.recovery_codes[1]).unwrap());
+        assert!(verify(&conn, "admin", &codes[1]).unwrap());
+
+        disable(&conn, "admin").unwrap();
+        assert!(!is_enrolled(&conn, "admin").unwrap());
+        assert!(!verify(&conn, "admin", &codes[2]).unwrap());
+    }
+
+    #[test]
+    fn test_render_qr() {
+        let qr = render_qr("otpauth://totp/sshfs-rs:admin?secret=ABC").unwrap();
+        assert!(qr.lines().count() > 10);
+    }
+}